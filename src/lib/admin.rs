@@ -0,0 +1,98 @@
+use axum::{
+    Json,
+    extract::{FromRequestParts, Path, State},
+    response::{IntoResponse, Response},
+};
+use rquest::StatusCode;
+use serde_json::{Value, json};
+use tracing::{info, warn};
+
+use crate::{error::ClewdrError, messages::api_key, state::AppState};
+
+/// Extractor requiring the admin password
+pub struct AdminAuth;
+
+impl FromRequestParts<AppState> for AdminAuth {
+    type Rejection = StatusCode;
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let key = api_key(&parts.headers);
+        if !state.live_config.load().admin_auth(key) {
+            warn!("Invalid admin password");
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        Ok(AdminAuth)
+    }
+}
+
+impl AppState {
+    /// Borrow a cookie, bootstrap it and run the admin action
+    async fn admin_action(&mut self, uuid: Option<&str>) -> Result<Value, ClewdrError> {
+        self.request_cookie().await?;
+        let res = async {
+            self.bootstrap().await?;
+            match uuid {
+                Some(uuid) => {
+                    let org_uuid = self.org_uuid.clone().ok_or(ClewdrError::UnexpectedNone)?;
+                    self.delete_conversation(&org_uuid, uuid, self.header_cookie())
+                        .await?;
+                    Ok(json!({ "deleted": uuid }))
+                }
+                None => {
+                    let convs = self
+                        .list_conversations()
+                        .await?
+                        .into_iter()
+                        .map(|c| {
+                            json!({
+                                "uuid": c["uuid"],
+                                "name": c["name"],
+                                "created_at": c["created_at"],
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    Ok(json!({ "conversations": convs }))
+                }
+            }
+        }
+        .await;
+        let reason = match res {
+            Err(ClewdrError::InvalidCookie(ref r)) => Some(r.clone()),
+            _ => None,
+        };
+        self.return_cookie(reason).await;
+        res
+    }
+}
+
+/// Admin handler listing conversations on the account of the next cookie
+pub async fn api_list_conversations(
+    AdminAuth: AdminAuth,
+    State(mut s): State<AppState>,
+) -> Response {
+    match s.admin_action(None).await {
+        Ok(v) => Json(v).into_response(),
+        Err(e) => {
+            warn!("Failed to list conversations: {}", e);
+            e.into_response()
+        }
+    }
+}
+
+/// Admin handler deleting a conversation on the account of the next cookie
+pub async fn api_delete_conversation(
+    AdminAuth: AdminAuth,
+    State(mut s): State<AppState>,
+    Path(uuid): Path<String>,
+) -> Response {
+    info!("Deleting conversation: {}", uuid);
+    match s.admin_action(Some(&uuid)).await {
+        Ok(v) => Json(v).into_response(),
+        Err(e) => {
+            warn!("Failed to delete conversation: {}", e);
+            e.into_response()
+        }
+    }
+}
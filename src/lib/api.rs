@@ -0,0 +1,111 @@
+use std::sync::LazyLock;
+
+use axum::{
+    body::Body,
+    extract::FromRequestParts,
+    response::{IntoResponse, Response},
+};
+use colored::Colorize;
+use rquest::{
+    Client, StatusCode,
+    header::{CONTENT_TYPE, HeaderValue},
+};
+use serde_json::Value;
+use tracing::info;
+
+use crate::{
+    config::ExhaustedPolicy,
+    error::ClewdrError,
+    messages::{ClientRequestBody, DEFAULT_MAX_TOKENS},
+    state::AppState,
+};
+
+/// Endpoint of the official Anthropic API
+pub const API_ENDPOINT: &str = "https://api.anthropic.com";
+/// Version sent to the official Anthropic API
+const API_VERSION: &str = "2023-06-01";
+
+/// The client to be used for requests to the official Anthropic API
+static API_CLIENT: LazyLock<Client> =
+    LazyLock::new(|| Client::builder().build().expect("Failed to create client"));
+
+/// Whether the request asked for API key mode via `x-clewdr-api-mode`
+pub struct ApiMode(pub bool);
+
+impl FromRequestParts<AppState> for ApiMode {
+    type Rejection = StatusCode;
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        _: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let enabled = parts
+            .headers
+            .get("x-clewdr-api-mode")
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| matches!(v.trim(), "1" | "true" | "api"));
+        Ok(ApiMode(enabled))
+    }
+}
+
+/// Serialize the request for the official API, dropping unset fields
+fn api_body(p: &ClientRequestBody) -> Value {
+    let mut json = serde_json::to_value(p).unwrap_or_default();
+    if json["max_tokens"].is_null() {
+        json["max_tokens"] = DEFAULT_MAX_TOKENS.into();
+    }
+    if let Some(obj) = json.as_object_mut() {
+        obj.retain(|_, v| match v {
+            Value::Null => false,
+            Value::Array(a) => !a.is_empty(),
+            _ => true,
+        });
+    }
+    json
+}
+
+impl AppState {
+    /// Check if the request should be sent to the official API
+    pub fn use_api(&self, requested: bool) -> bool {
+        !self.config.api_key.is_empty() && (requested || self.config.api_mode)
+    }
+
+    /// Send the request to the official Anthropic API with a real API key
+    pub async fn send_api(&self, p: &ClientRequestBody) -> Result<rquest::Response, ClewdrError> {
+        info!("Forwarding request to {}", API_ENDPOINT.green());
+        let mut req = API_CLIENT
+            .post(format!("{}/v1/messages", API_ENDPOINT))
+            .header("x-api-key", self.config.api_key.as_str())
+            .header("anthropic-version", API_VERSION)
+            .json(&api_body(p));
+        if !p.betas.is_empty() {
+            req = req.header("anthropic-beta", p.betas.join(","));
+        }
+        if let Some(proxy) = self.proxy() {
+            req = req.proxy(proxy);
+        }
+        Ok(req.send().await?)
+    }
+
+    /// Forward the request to the official Anthropic API with a real API key
+    /// The response is passed through unchanged
+    pub async fn forward_api(&self, p: &ClientRequestBody) -> Result<Response, ClewdrError> {
+        let res = self.send_api(p).await?;
+        let status = res.status();
+        let content_type = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .cloned()
+            .unwrap_or(HeaderValue::from_static("application/json"));
+        let mut response = Body::from_stream(res.bytes_stream()).into_response();
+        *response.status_mut() = status;
+        response.headers_mut().insert(CONTENT_TYPE, content_type);
+        Ok(response)
+    }
+
+    /// Check if the error should be answered by the official API under the exhausted pool policy
+    pub fn falls_back_to_api(&self, e: &ClewdrError) -> bool {
+        e.is_no_cookie()
+            && self.config.exhausted_policy == ExhaustedPolicy::FallbackApiKey
+            && self.use_api(true)
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    io::Write,
+    mem,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::runtime::Handle;
+use tracing::error;
+
+use crate::{error::ClewdrError, messages::ClientRequestBody, state::AppState};
+
+/// One JSON-lines record of a request and its response
+/// Written to the audit directory when dropped
+#[derive(Serialize, Debug)]
+pub struct AuditRecord {
+    timestamp: String,
+    model: String,
+    cookie_hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_id: Option<String>,
+    request: Value,
+    response: String,
+    latency_ms: i64,
+    error: Option<String>,
+    #[serde(skip)]
+    start: DateTime<Utc>,
+    #[serde(skip)]
+    dir: PathBuf,
+}
+
+impl AuditRecord {
+    /// Start a new record, returns None if auditing is disabled
+    pub fn new(state: &AppState, p: &ClientRequestBody) -> Option<Self> {
+        let dir = state.config.audit_dir.trim();
+        if dir.is_empty() {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        state
+            .cookie
+            .as_ref()
+            .map(|c| c.cookie.to_string())
+            .unwrap_or_default()
+            .hash(&mut hasher);
+        let start = Utc::now();
+        Some(Self {
+            timestamp: start.to_rfc3339(),
+            model: p.model.clone(),
+            cookie_hash: format!("{:016x}", hasher.finish()),
+            user_id: p.user_id().map(|u| u.to_string()),
+            request: sanitize(p),
+            response: String::new(),
+            latency_ms: 0,
+            error: None,
+            start,
+            dir: PathBuf::from(dir),
+        })
+    }
+
+    /// Append text to the response
+    pub fn push(&mut self, text: &str) {
+        self.response.push_str(text);
+    }
+
+    /// Append the text of a Claude.ai event to the response
+    pub fn push_event(&mut self, data: &str) {
+        let Ok(json) = serde_json::from_str::<Value>(data) else {
+            return;
+        };
+        if let Some(text) = json
+            .get("completion")
+            .or(json.pointer("/delta/text"))
+            .and_then(|t| t.as_str())
+        {
+            self.push(text);
+        }
+    }
+
+    /// Record the error of the request
+    pub fn fail(&mut self, e: &ClewdrError) {
+        self.error = Some(e.to_string());
+    }
+
+    /// Append the record to the audit file of the day
+    /// The file is written on the blocking pool so the async workers never wait on disk
+    fn write(&mut self) {
+        self.latency_ms = (Utc::now() - self.start).num_milliseconds();
+        let Ok(mut line) = serde_json::to_string(self) else {
+            return;
+        };
+        line.push('\n');
+        let dir = mem::take(&mut self.dir);
+        let file_name = dir.join(format!("audit-{}.jsonl", self.start.format("%Y-%m-%d")));
+        let write = move || append_line(&dir, &file_name, &line);
+        match Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(write);
+            }
+            Err(_) => write(),
+        }
+    }
+}
+
+/// Append a line to the audit file, creating the audit dir if needed
+fn append_line(dir: &Path, file_name: &Path, line: &str) {
+    if let Err(e) = std::fs::create_dir_all(dir) {
+        error!("Failed to create audit dir: {}", e);
+        return;
+    }
+    let res = std::fs::File::options()
+        .create(true)
+        .append(true)
+        .open(file_name)
+        .and_then(|mut f| f.write_all(line.as_bytes()));
+    if let Err(e) = res {
+        error!("Failed to write audit record: {}", e);
+    }
+}
+
+impl Drop for AuditRecord {
+    fn drop(&mut self) {
+        self.write();
+    }
+}
+
+/// Serialize the request, omitting image data
+fn sanitize(p: &ClientRequestBody) -> Value {
+    let mut json = serde_json::to_value(p).unwrap_or_default();
+    if let Some(msgs) = json["messages"].as_array_mut() {
+        for block in msgs
+            .iter_mut()
+            .filter_map(|m| m["content"].as_array_mut())
+            .flatten()
+        {
+            if block["type"] == "image" {
+                block["source"]["data"] = "[omitted]".into();
+            }
+        }
+    }
+    json
+}
@@ -0,0 +1,87 @@
+use axum::{
+    Json,
+    extract::State,
+    http::Extensions,
+    response::{IntoResponse, Response},
+};
+use futures::{StreamExt, stream};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::info;
+
+use crate::{
+    error::ClewdrError,
+    messages::{Auth, ClientRequestBody, ValidJson},
+    state::AppState,
+};
+
+/// Independent requests sent in one batch
+#[derive(Deserialize, Debug)]
+pub struct BatchRequest {
+    pub requests: Vec<ClientRequestBody>,
+}
+
+impl AppState {
+    /// Run one request of a batch, non-stream, returning its result entry
+    /// Each request is charged to the rate limit and queued like a single request
+    async fn batch_item(
+        &self,
+        mut p: ClientRequestBody,
+        key: &str,
+        extensions: &Extensions,
+    ) -> Value {
+        p.stream = false;
+        p.hash_user_id();
+        let ready = self
+            .charge_rate_limit(key, extensions)
+            .and_then(|_| self.config.model_chain(&p.model))
+            .and_then(|chain| {
+                p.set_model_chain(chain);
+                p.render_variables(&self.config)
+            });
+        let res = match ready {
+            Ok(()) => {
+                let _permit = self.queue.acquire(&self.config).await;
+                self.message_with_retries(p).await
+            }
+            Err(e) => e.into_response(),
+        };
+        let status = res.status();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .ok()
+            .and_then(|b| serde_json::from_slice::<Value>(&b).ok())
+            .unwrap_or_default();
+        if status.is_success() {
+            json!({ "type": "succeeded", "message": body })
+        } else {
+            json!({ "type": "errored", "status": status.as_u16(), "error": body })
+        }
+    }
+}
+
+/// Axum handler running a batch of requests with bounded concurrency
+/// Results are returned in the order of the requests
+pub async fn api_batch(
+    Auth(key): Auth,
+    extensions: Extensions,
+    State(mut state): State<AppState>,
+    ValidJson(batch): ValidJson<BatchRequest>,
+) -> Response {
+    state.set_key(&key);
+    let len = batch.requests.len();
+    if len > state.config.max_batch_size {
+        return ClewdrError::InvalidRequest(format!(
+            "batch of {} requests exceeds the limit of {}",
+            len, state.config.max_batch_size
+        ))
+        .into_response();
+    }
+    info!("Batch received, requests: {}", len);
+    let results = stream::iter(batch.requests)
+        .map(|p| state.batch_item(p, &key, &extensions))
+        .buffered(state.config.batch_concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    Json(json!({ "results": results })).into_response()
+}
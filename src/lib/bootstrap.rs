@@ -1,10 +1,14 @@
+use std::{mem, sync::atomic::Ordering, time::Duration};
+
 use colored::Colorize;
+use futures::{StreamExt, stream};
 use serde_json::Value;
-use tracing::warn;
+use tokio::time::sleep;
+use tracing::{info, warn};
 
 use crate::{
-    client::{SUPER_CLIENT, SetupRequest},
-    config::Reason,
+    client::SetupRequest,
+    config::{Config, Reason, UselessCookie},
     error::{ClewdrError, check_res_err},
     state::AppState,
     utils::print_out_json,
@@ -15,12 +19,37 @@ impl AppState {
     /// This function will send a request to the server to get the bootstrap data
     /// It will also check if the cookie is valid
     pub async fn bootstrap(&mut self) -> Result<(), ClewdrError> {
-        let proxy = self.config.rquest_proxy.clone();
-        let end_point = format!("{}/api/bootstrap", self.config.endpoint());
-        let res = SUPER_CLIENT
-            .get(end_point)
-            .setup_request("", self.header_cookie(), proxy.clone())
-            .send()
+        // reuse the result of a recent bootstrap, e.g. from warm-up
+        if let Some(c) = self.cookie.as_ref().filter(|c| c.bootstrap_fresh()) {
+            self.org_uuid = c.org_uuid.clone();
+            self.capabilities = c.capabilities.clone();
+            self.ready.store(true, Ordering::Relaxed);
+            return Ok(());
+        }
+        let mut backoff = Duration::from_millis(self.config.bootstrap_backoff_ms);
+        for _ in 0..self.config.bootstrap_retries {
+            match self.fetch_bootstrap().await {
+                Err(e) if e.is_transient() => {
+                    warn!("Bootstrap failed, retrying in {:?}: {}", backoff, e);
+                    sleep(backoff).await;
+                    backoff *= 2;
+                }
+                res => return res,
+            }
+        }
+        self.fetch_bootstrap().await
+    }
+
+    /// Fetch the account and organization of the cookie
+    async fn fetch_bootstrap(&mut self) -> Result<(), ClewdrError> {
+        let proxy = self.proxy();
+        let cookie = self.header_cookie();
+        let res = self
+            .send_upstream(|client, endpoint| {
+                client
+                    .get(format!("{}/api/bootstrap", endpoint))
+                    .setup_request("", &cookie, proxy.clone())
+            })
             .await?;
         self.update_cookie_from_res(&res);
         let res = check_res_err(res).await?;
@@ -69,12 +98,13 @@ impl AppState {
         );
 
         // Bootstrap complete
-        let end_point = self.config.endpoint();
-        let end_point = format!("{}/api/organizations", end_point);
-        let res = SUPER_CLIENT
-            .get(end_point)
-            .setup_request("", self.header_cookie(), proxy)
-            .send()
+        let cookie = self.header_cookie();
+        let res = self
+            .send_upstream(|client, endpoint| {
+                client
+                    .get(format!("{}/api/organizations", endpoint))
+                    .setup_request("", &cookie, proxy.clone())
+            })
             .await?;
         self.update_cookie_from_res(&res);
         let res = check_res_err(res).await?;
@@ -105,9 +135,69 @@ impl AppState {
             .and_then(|u| u.as_str())
             .ok_or(ClewdrError::UnexpectedNone)?;
         self.org_uuid = Some(u.to_string());
+        // cache the result in the cookie
+        if let Some(c) = self.cookie.as_mut() {
+            c.org_uuid = self.org_uuid.clone();
+            c.capabilities = self.capabilities.clone();
+            c.bootstrapped_at = Some(chrono::Utc::now().timestamp());
+        }
+        self.ready.store(true, Ordering::Relaxed);
         Ok(())
     }
 
+    /// Bootstrap every cookie once on startup
+    /// Invalid cookies are moved to the wasted list, exhausted cookies get their reset time
+    pub async fn warmup(&self, config: &mut Config) {
+        let cookies = mem::take(&mut config.cookie_array);
+        let total = cookies.len();
+        let results = stream::iter(cookies)
+            .map(|c| {
+                let mut state = self.clone();
+                async move {
+                    state.set_cookie(c.clone());
+                    match state.bootstrap().await {
+                        Ok(()) => (state.cookie.take().unwrap_or(c), None),
+                        Err(ClewdrError::InvalidCookie(r)) => (c, Some(r)),
+                        Err(ClewdrError::AccountSuspended(_)) => (c, Some(Reason::Suspended)),
+                        Err(e) => {
+                            // keep cookies with transient errors
+                            warn!("Warm-up failed for {}: {}", c.cookie, e);
+                            (c, None)
+                        }
+                    }
+                }
+            })
+            .buffer_unordered(self.config.warmup_concurrency.max(1))
+            .collect::<Vec<_>>()
+            .await;
+        let mut passed = 0;
+        for (mut cookie, reason) in results {
+            match reason {
+                None => {
+                    if cookie.org_uuid.is_some() {
+                        passed += 1;
+                    }
+                    config.cookie_array.push(cookie);
+                }
+                Some(Reason::TooManyRequest(i)) | Some(Reason::Restricted(i)) => {
+                    cookie.reset_time = Some(i);
+                    config.cookie_array.push(cookie);
+                }
+                Some(r) => {
+                    warn!("Cookie {} is invalid: {}", cookie.cookie, r);
+                    config
+                        .wasted_cookie
+                        .push(UselessCookie::new(cookie.cookie, r));
+                }
+            }
+        }
+        info!(
+            "Warm-up complete, {} of {} cookies passed",
+            passed.to_string().green(),
+            total.to_string().blue()
+        );
+    }
+
     /// Check if the account is restricted or banned.
     /// If the account is restricted, check if the restriction is expired.
     /// If the account is banned, return an error.
@@ -1,16 +1,32 @@
+use axum::{http::HeaderValue, response::Response};
 use base64::{Engine, prelude::BASE64_STANDARD};
-use futures::future::join_all;
+use colored::Colorize;
+use image::{DynamicImage, ImageFormat, imageops::FilterType};
+use itertools::Itertools;
 use rquest::{
     Client, ClientBuilder, Proxy, RequestBuilder,
     header::{COOKIE, ORIGIN, REFERER},
     multipart::{Form, Part},
+    tls::CertStore,
 };
 use rquest_util::Emulation;
 use serde_json::Value;
-use std::sync::LazyLock;
-use tracing::warn;
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    path::Path,
+    sync::{LazyLock, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use tokio::task::{JoinError, JoinSet};
+use tracing::{info, warn};
 
-use crate::{config::ENDPOINT, state::AppState, types::message::ImageSource};
+use crate::{
+    config::{Config, ENDPOINT},
+    error::ClewdrError,
+    state::AppState,
+    types::message::ImageSource,
+};
 
 /// The client to be used for requests to the Claude.ai
 /// This client is used for requests that require a specific emulation
@@ -21,6 +37,107 @@ pub static SUPER_CLIENT: LazyLock<Client> = LazyLock::new(|| {
         .expect("Failed to create client")
 });
 
+/// The client to be used for requests to the reverse proxy
+/// Only this client honors the custom CA and invalid certs settings
+static RPROXY_CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Endpoints cooling down after a connection failure, with the time they recover
+static COOLDOWNS: LazyLock<Mutex<HashMap<String, Instant>>> = LazyLock::new(Default::default);
+
+/// Whether the endpoint is not cooling down after a connection failure
+pub fn endpoint_healthy(endpoint: &str) -> bool {
+    COOLDOWNS
+        .lock()
+        .map(|c| c.get(endpoint).is_none_or(|t| *t <= Instant::now()))
+        .unwrap_or(true)
+}
+
+/// Record the outcome of a request to an endpoint
+fn mark_endpoint(endpoint: &str, cooldown: Option<Duration>) {
+    let Ok(mut c) = COOLDOWNS.lock() else {
+        return;
+    };
+    match cooldown {
+        Some(d) => {
+            c.insert(endpoint.to_string(), Instant::now() + d);
+        }
+        None => {
+            c.remove(endpoint);
+        }
+    }
+}
+
+/// Whether the request failed before the endpoint answered
+fn is_connection_error(e: &rquest::Error) -> bool {
+    e.is_connect() || e.is_timeout() || e.is_request()
+}
+
+/// Client for requests to the given endpoint
+fn client_for(endpoint: &str) -> &'static Client {
+    if endpoint == ENDPOINT {
+        return &SUPER_CLIENT;
+    }
+    RPROXY_CLIENT.get().unwrap_or(&SUPER_CLIENT)
+}
+
+/// Build the reverse proxy client from config
+pub fn init_rproxy_client(config: &Config) -> Result<(), ClewdrError> {
+    if config.endpoints().iter().all(|e| e == ENDPOINT) {
+        return Ok(());
+    }
+    let mut builder = ClientBuilder::new().emulation(Emulation::Chrome134);
+    let ca_file = config.rproxy_ca_file.trim();
+    if !ca_file.is_empty() {
+        let pem = std::fs::read(ca_file)?;
+        builder = builder.cert_store(CertStore::from_pem_stack(pem)?);
+        info!("Using custom CA for reverse proxy: {}", ca_file);
+    }
+    if config.rproxy_accept_invalid_certs {
+        warn!(
+            "{}",
+            "UNSAFE: accepting invalid TLS certificates from reverse proxy".red()
+        );
+        builder = builder.cert_verification(false);
+    }
+    let _ = RPROXY_CLIENT.set(builder.build()?);
+    Ok(())
+}
+
+impl AppState {
+    /// Send a request to the endpoints in order of priority
+    /// Moves on to the next endpoint only when the connection fails, `build` gets the client and endpoint
+    pub async fn send_upstream(
+        &self,
+        build: impl Fn(&'static Client, &str) -> RequestBuilder,
+    ) -> Result<rquest::Response, ClewdrError> {
+        let cooldown = Duration::from_secs(self.config.endpoint_cooldown_secs);
+        // endpoints cooling down are only tried when all others failed
+        let (healthy, cooling): (Vec<_>, Vec<_>) = self
+            .config
+            .endpoints()
+            .into_iter()
+            .partition(|e| endpoint_healthy(e));
+        let mut last_err = None;
+        for endpoint in healthy.into_iter().chain(cooling) {
+            match build(client_for(&endpoint), &endpoint).send().await {
+                Ok(res) => {
+                    mark_endpoint(&endpoint, None);
+                    return Ok(res);
+                }
+                Err(e) if is_connection_error(&e) => {
+                    warn!("Endpoint {} failed, trying the next one: {}", endpoint, e);
+                    mark_endpoint(&endpoint, Some(cooldown));
+                    last_err = Some(e);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(last_err
+            .expect("at least one endpoint is configured")
+            .into())
+    }
+}
+
 /// Helper function to add headers to a request
 pub trait SetupRequest {
     fn setup_request(
@@ -58,84 +175,382 @@ fn header_ref<S: AsRef<str>>(ref_path: S) -> String {
         format!("{}/chat/{}", ENDPOINT, ref_path.as_ref())
     }
 }
+
+/// Images uploaded to Claude.ai
+#[derive(Debug, Default)]
+pub struct UploadedImages {
+    /// File UUIDs returned by Claude.ai, in the original order
+    pub files: Vec<String>,
+    /// Indices of the images that were downscaled before uploading
+    pub downscaled: Vec<usize>,
+    /// Indices of the images that failed to upload
+    pub failed: Vec<usize>,
+}
+
+impl UploadedImages {
+    /// Report downscaled and failed images in debug headers
+    pub fn apply_header(&self, res: &mut Response) {
+        for (name, indices) in [
+            ("x-clewdr-downscaled", &self.downscaled),
+            ("x-clewdr-upload-failed", &self.failed),
+        ] {
+            if indices.is_empty() {
+                continue;
+            }
+            let value = indices.iter().join(",");
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                res.headers_mut().insert(name, value);
+            }
+        }
+    }
+}
+
+/// Run uploads with bounded concurrency
+/// Survivors keep their original order, failures are reported by index instead of shifting them
+async fn upload_bounded<T, F, Fut>(
+    items: Vec<T>,
+    limit: usize,
+    upload: F,
+) -> (Vec<String>, Vec<usize>)
+where
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = Option<String>> + Send + 'static,
+{
+    let mut results = vec![None; items.len()];
+    let mut set = JoinSet::new();
+    for (i, item) in items.into_iter().enumerate() {
+        if set.len() >= limit {
+            if let Some(res) = set.join_next().await {
+                store_upload(&mut results, res);
+            }
+        }
+        let file = upload(item);
+        set.spawn(async move { (i, file.await) });
+    }
+    while let Some(res) = set.join_next().await {
+        store_upload(&mut results, res);
+    }
+    let failed = results
+        .iter()
+        .positions(Option::is_none)
+        .inspect(|i| warn!("Failed to upload image {}", i))
+        .collect();
+    (results.into_iter().flatten().collect(), failed)
+}
+
+/// Store the result of an upload task, a panicked task counts as a failed upload
+fn store_upload(results: &mut [Option<String>], res: Result<(usize, Option<String>), JoinError>) {
+    match res {
+        Ok((i, file)) => results[i] = file,
+        Err(e) => warn!("Image upload task failed: {}", e),
+    }
+}
+
+/// Choose the file name based on the media type
+fn file_name(media_type: &str) -> &'static str {
+    match media_type {
+        "image/png" => "image.png",
+        "image/jpeg" => "image.jpg",
+        "image/gif" => "image.gif",
+        "image/webp" => "image.webp",
+        "application/pdf" => "document.pdf",
+        _ => "file",
+    }
+}
+
+/// Detect the media type of an image, or a PDF document, from its magic bytes
+fn sniff_media_type(bytes: &[u8]) -> Option<&'static str> {
+    match bytes {
+        [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => Some("image/png"),
+        [0xff, 0xd8, 0xff, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some("image/gif"),
+        [
+            b'R',
+            b'I',
+            b'F',
+            b'F',
+            _,
+            _,
+            _,
+            _,
+            b'W',
+            b'E',
+            b'B',
+            b'P',
+            ..,
+        ] => Some("image/webp"),
+        [b'%', b'P', b'D', b'F', b'-', ..] => Some("application/pdf"),
+        _ => None,
+    }
+}
+
+/// Read a local image confined to the configured root, returning it with its media type
+fn read_image_file(
+    i: usize,
+    img: &ImageSource,
+    root: &str,
+    max_size: usize,
+) -> Result<(Vec<u8>, String), ClewdrError> {
+    let root = root.trim();
+    if root.is_empty() {
+        return Err(ClewdrError::UnsupportedImage(format!(
+            "image {} is a local file, but file images are disabled",
+            i
+        )));
+    }
+    let path = img.path.as_deref().unwrap_or_default();
+    let root = Path::new(root).canonicalize()?;
+    // canonicalize resolves `..` and symlinks, so the check covers traversal
+    let file = root
+        .join(path)
+        .canonicalize()
+        .ok()
+        .filter(|f| f.starts_with(&root) && f.is_file())
+        .ok_or_else(|| {
+            ClewdrError::UnsupportedImage(format!("image {} has an invalid path `{}`", i, path))
+        })?;
+    let size = std::fs::metadata(&file)?.len() as usize;
+    if size > max_size {
+        return Err(ClewdrError::ImageTooLarge(format!(
+            "image {} is {} bytes, limit is {} bytes",
+            i, size, max_size
+        )));
+    }
+    let bytes = std::fs::read(&file)?;
+    let Some(detected) = sniff_media_type(&bytes) else {
+        return Err(ClewdrError::UnsupportedImage(format!(
+            "image {} is not a PNG, JPEG, GIF or WebP image, nor a PDF",
+            i
+        )));
+    };
+    if !img.media_type.is_empty() && detected != img.media_type {
+        return Err(ClewdrError::UnsupportedImage(format!(
+            "image {} is declared as `{}` but contains {}",
+            i, img.media_type, detected
+        )));
+    }
+    Ok((bytes, detected.to_string()))
+}
+
+/// Decode a base64 image and check it against its declared media type
+fn decode_image(i: usize, img: &ImageSource, max_size: usize) -> Result<Vec<u8>, ClewdrError> {
+    if img.type_ != "base64" {
+        return Err(ClewdrError::UnsupportedImage(format!(
+            "image {} has source type `{}`, only base64 and file are supported",
+            i, img.type_
+        )));
+    }
+    // strip an optional data URL prefix
+    let data = img
+        .data
+        .split_once(";base64,")
+        .map_or(img.data.as_str(), |(_, d)| d)
+        .trim();
+    // reject oversized payloads before allocating the decoded buffer
+    let estimated = data.len() / 4 * 3;
+    if estimated > max_size {
+        return Err(ClewdrError::ImageTooLarge(format!(
+            "image {} decodes to about {} bytes, limit is {} bytes",
+            i, estimated, max_size
+        )));
+    }
+    let bytes = BASE64_STANDARD.decode(data).map_err(|e| {
+        ClewdrError::UnsupportedImage(format!("image {} is not valid base64: {}", i, e))
+    })?;
+    let Some(detected) = sniff_media_type(&bytes) else {
+        return Err(ClewdrError::UnsupportedImage(format!(
+            "image {} is not a PNG, JPEG, GIF or WebP image, nor a PDF",
+            i
+        )));
+    };
+    if detected != img.media_type {
+        return Err(ClewdrError::UnsupportedImage(format!(
+            "image {} is declared as `{}` but contains {}",
+            i, img.media_type, detected
+        )));
+    }
+    Ok(bytes)
+}
+
 impl AppState {
     /// Upload images to the Claude.ai
-    pub async fn upload_images(&self, imgs: Vec<ImageSource>) -> Vec<String> {
-        // upload images
-        let fut = imgs
-            .into_iter()
-            .map_while(|img| {
-                // check if the image is base64
-                if img.type_ != "base64" {
-                    warn!("Image type is not base64");
-                    return None;
-                }
-                // decode the image
-                let bytes = BASE64_STANDARD
-                    .decode(img.data.as_bytes())
-                    .inspect_err(|e| {
-                        warn!("Failed to decode image: {}", e);
-                    })
-                    .ok()?;
-                // choose the file name based on the media type
-                let file_name = match img.media_type.as_str() {
-                    "image/png" => "image.png",
-                    "image/jpeg" => "image.jpg",
-                    "image/gif" => "image.gif",
-                    "image/webp" => "image.webp",
-                    "application/pdf" => "document.pdf",
-                    _ => "file",
-                };
+    pub async fn upload_images(
+        &self,
+        imgs: Vec<ImageSource>,
+    ) -> Result<UploadedImages, ClewdrError> {
+        let Some(org_uuid) = self.org_uuid.clone() else {
+            return Ok(UploadedImages::default());
+        };
+        let max_size = self.config.max_image_size;
+        let max_total = self.config.max_image_total_size;
+        // decode and shrink images before uploading
+        let mut prepared = Vec::with_capacity(imgs.len());
+        let mut downscaled = Vec::new();
+        let mut total = 0;
+        for (i, img) in imgs.into_iter().enumerate() {
+            // validate and decode the image
+            let (bytes, media_type) = if img.type_ == "file" {
+                read_image_file(i, &img, &self.config.image_file_root, max_size)?
+            } else {
+                (decode_image(i, &img, max_size)?, img.media_type.clone())
+            };
+            let (bytes, shrunk) = self.shrink_image(bytes, &media_type)?;
+            if shrunk {
+                downscaled.push(i);
+            }
+            if bytes.len() > max_size {
+                return Err(ClewdrError::ImageTooLarge(format!(
+                    "image {} is {} bytes, limit is {} bytes",
+                    i,
+                    bytes.len(),
+                    max_size
+                )));
+            }
+            total += bytes.len();
+            if total > max_total {
+                return Err(ClewdrError::ImageTooLarge(format!(
+                    "total image payload exceeds {} bytes",
+                    max_total
+                )));
+            }
+            prepared.push((bytes, file_name(&media_type)));
+        }
+
+        // upload with bounded concurrency, preserving the original order
+        let endpoint = format!("https://claude.ai/api/{}/upload", org_uuid);
+        let cookie = self.header_cookie();
+        let limit = self.config.image_upload_concurrency.max(1);
+        let (files, failed) = upload_bounded(prepared, limit, |(bytes, file_name)| {
+            upload_one(
+                endpoint.clone(),
+                cookie.clone(),
+                self.proxy(),
+                bytes,
+                file_name,
+            )
+        })
+        .await;
+        Ok(UploadedImages {
+            files,
+            downscaled,
+            failed,
+        })
+    }
+
+    /// Upload a single file, returns the file UUID
+    async fn upload_one(
+        self,
+        org_uuid: String,
+        bytes: Vec<u8>,
+        file_name: &'static str,
+    ) -> Option<String> {
+        let cookie = self.header_cookie();
+        let proxy = self.proxy();
+        let res = self
+            .send_upstream(|client, endpoint| {
                 // create the part and form
-                let part = Part::bytes(bytes).file_name(file_name);
+                let part = Part::bytes(bytes.clone()).file_name(file_name);
                 let form = Form::new().part("file", part);
-                let endpoint = format!("https://claude.ai/api/{}/upload", self.org_uuid.as_ref()?);
-                Some(
-                    // send the request into future
-                    SUPER_CLIENT
-                        .post(endpoint)
-                        .setup_request(
-                            "new",
-                            self.header_cookie(),
-                            self.config.rquest_proxy.clone(),
-                        )
-                        .header_append("anthropic-client-platform", "web_claude_ai")
-                        .multipart(form)
-                        .send(),
-                )
+                client
+                    .post(format!("{}/api/{}/upload", endpoint, org_uuid))
+                    .setup_request("new", &cookie, proxy.clone())
+                    .header_append("anthropic-client-platform", "web_claude_ai")
+                    .multipart(form)
             })
-            .collect::<Vec<_>>();
-
-        // get upload responses
-        let fut = join_all(fut)
             .await
-            .into_iter()
-            .map_while(|r| {
-                // check if the response is ok
-                r.inspect_err(|e| {
-                    warn!("Failed to upload image: {}", e);
-                })
-                .ok()
+            .inspect_err(|e| {
+                warn!("Failed to upload image: {}", e);
             })
-            .map(|r| async {
-                // get the response json
-                // extract the file_uuid
-                let json = r
-                    .json::<Value>()
-                    .await
-                    .inspect_err(|e| {
-                        warn!("Failed to parse image response: {}", e);
-                    })
-                    .ok()?;
-                Some(json["file_uuid"].as_str()?.to_string())
+            .ok()?;
+        // get the response json
+        // extract the file_uuid
+        let json = res
+            .json::<Value>()
+            .await
+            .inspect_err(|e| {
+                warn!("Failed to parse image response: {}", e);
             })
-            .collect::<Vec<_>>();
+            .ok()?;
+        Some(json["file_uuid"].as_str()?.to_string())
+    }
 
-        // collect the results
-        join_all(fut)
-            .await
-            .into_iter()
-            .flatten()
-            .collect::<Vec<_>>()
+    /// Downscale an image if it exceeds the configured dimension
+    /// Returns the (possibly re-encoded) bytes and whether it was downscaled
+    fn shrink_image(
+        &self,
+        bytes: Vec<u8>,
+        media_type: &str,
+    ) -> Result<(Vec<u8>, bool), ClewdrError> {
+        let max_dim = self.config.max_image_dimension;
+        // documents are uploaded as is
+        let Some(format) = ImageFormat::from_mime_type(media_type) else {
+            return Ok((bytes, false));
+        };
+        if max_dim == 0 {
+            return Ok((bytes, false));
+        }
+        let img = image::load_from_memory_with_format(&bytes, format)?;
+        if img.width() <= max_dim && img.height() <= max_dim {
+            return Ok((bytes, false));
+        }
+        info!(
+            "Downscaling image from {}x{} to fit {}",
+            img.width(),
+            img.height(),
+            max_dim
+        );
+        let img = img.resize(max_dim, max_dim, FilterType::Lanczos3);
+        // jpeg does not support alpha channel
+        let img = if format == ImageFormat::Jpeg {
+            DynamicImage::ImageRgb8(img.to_rgb8())
+        } else {
+            img
+        };
+        let mut buf = Cursor::new(Vec::new());
+        img.write_to(&mut buf, format)?;
+        Ok((buf.into_inner(), true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn failed_upload_keeps_survivors_in_order() {
+        // the first upload finishes last, the middle one fails
+        let (files, failed) = upload_bounded(vec![30, 0, 10], 3, |delay| async move {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            (delay > 0).then(|| format!("file-{}", delay))
+        })
+        .await;
+        assert_eq!(files, ["file-30", "file-10"]);
+        assert_eq!(failed, [1]);
+    }
+
+    #[tokio::test]
+    async fn panicked_upload_counts_as_failed() {
+        let (files, failed) = upload_bounded(vec![true, false], 1, |ok| async move {
+            assert!(ok, "upload task panicked");
+            Some("file".to_string())
+        })
+        .await;
+        assert_eq!(files, ["file"]);
+        assert_eq!(failed, [1]);
+    }
+
+    #[test]
+    fn downscaled_images_are_reported() {
+        let images = UploadedImages {
+            files: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+            downscaled: vec![0, 2],
+            failed: vec![1],
+        };
+        let mut res = Response::new(Body::empty());
+        images.apply_header(&mut res);
+        assert_eq!(res.headers()["x-clewdr-downscaled"], "0,2");
+        assert_eq!(res.headers()["x-clewdr-upload-failed"], "1");
     }
 }
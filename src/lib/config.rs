@@ -1,18 +1,39 @@
+use axum::response::sse::KeepAlive;
 use colored::Colorize;
+use itertools::Itertools;
 use passwords::PasswordGenerator;
+use regex::Regex;
 use rquest::Proxy;
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::{HashMap, HashSet},
     fmt::{Debug, Display},
-    hash::Hash,
+    hash::{DefaultHasher, Hash, Hasher},
+    time::Duration,
 };
 use tiktoken_rs::o200k_base;
 use tracing::{error, info, warn};
 
-use crate::{Args, error::ClewdrError, utils::config_dir};
+use crate::{
+    Args,
+    client::endpoint_healthy,
+    error::ClewdrError,
+    markdown::{MarkdownStrip, MarkdownStripper},
+    openai::{LeadingTrimmer, PrefillTrimmer, TagStripper},
+    tokenizer::TokenizerKind,
+    utils::{FALLBACK_TIME_ZONE, TIME_ZONE, config_dir},
+};
 
 pub const CONFIG_NAME: &str = "config.toml";
 pub const ENDPOINT: &str = "https://api.claude.ai";
+/// Origin of the Claude.ai web app, where conversations are opened
+pub const WEB_ORIGIN: &str = "https://claude.ai";
+/// Seconds a cached bootstrap result stays valid
+pub const BOOTSTRAP_CACHE_SECS: i64 = 5 * 60;
+/// Context window enabled by the `context-1m` beta
+pub const EXTENDED_CONTEXT_LIMIT: usize = 1_000_000;
+/// Handshake message clients send to check the proxy, also used to test cookies
+pub const PROBE_MESSAGE: &str = "Hi";
 const fn default_max_connections() -> usize {
     16
 }
@@ -21,6 +42,207 @@ const fn default_max_retries() -> usize {
     5
 }
 
+fn default_pro_models() -> Vec<String> {
+    vec!["opus".to_string()]
+}
+
+fn default_strip_open_tag() -> String {
+    "<thinking>".to_string()
+}
+
+fn default_strip_close_tag() -> String {
+    "</thinking>".to_string()
+}
+
+fn default_ignored_system_prompts() -> Vec<String> {
+    vec!["[Start a new chat]".to_string()]
+}
+
+fn default_new_chat_sentinel() -> String {
+    "[Start a new chat]".to_string()
+}
+
+fn default_redact_patterns() -> Vec<String> {
+    vec![r"sk-ant-[A-Za-z0-9_\-]+".to_string()]
+}
+
+fn default_conversation_name_template() -> String {
+    "clewdr-{timestamp}-{model}".to_string()
+}
+
+const fn default_coalesce_chars() -> usize {
+    256
+}
+
+const fn default_conversation_retries() -> usize {
+    3
+}
+
+const fn default_shutdown_grace_secs() -> u64 {
+    30
+}
+
+const fn default_tls_reload_secs() -> u64 {
+    60
+}
+
+const fn default_endpoint_cooldown_secs() -> u64 {
+    30
+}
+
+const fn default_model_cache_ttl_secs() -> u64 {
+    86400
+}
+
+const fn default_warmup_concurrency() -> usize {
+    4
+}
+
+const fn default_bootstrap_retries() -> usize {
+    2
+}
+
+const fn default_bootstrap_backoff_ms() -> u64 {
+    500
+}
+
+const fn default_max_queue_len() -> usize {
+    64
+}
+
+const fn default_batch_concurrency() -> usize {
+    4
+}
+
+const fn default_max_batch_size() -> usize {
+    100
+}
+
+const fn default_queue_timeout_secs() -> u64 {
+    60
+}
+
+const fn default_cookie_wait_secs() -> u64 {
+    60
+}
+
+const fn default_keep_alive_secs() -> u64 {
+    15
+}
+
+const fn default_json_mode_retry() -> bool {
+    true
+}
+
+const fn default_legacy_functions() -> bool {
+    true
+}
+
+fn default_probe_messages() -> Vec<String> {
+    vec![PROBE_MESSAGE.to_string()]
+}
+
+fn default_probe_response() -> String {
+    "Claude Reverse Proxy is working, please send a real message.".to_string()
+}
+
+fn default_empty_response_placeholder() -> String {
+    "[Claude.ai returned an empty response]".to_string()
+}
+
+const fn default_sse_event_ids() -> bool {
+    true
+}
+
+const fn default_sse_retry_ms() -> u64 {
+    3000
+}
+
+const fn default_normalize_models() -> bool {
+    true
+}
+
+const fn default_trim_prefill_overlap() -> bool {
+    true
+}
+
+const fn default_prefill_min_overlap() -> usize {
+    3
+}
+
+fn default_leading_pattern() -> String {
+    r"^\s*(?:Assistant:\s*)?".to_string()
+}
+
+fn default_turn_separator() -> String {
+    "\n\n".to_string()
+}
+
+fn default_timezone() -> String {
+    TIME_ZONE.to_string()
+}
+
+fn default_system_separator() -> String {
+    "\n\n".to_string()
+}
+
+const fn default_turn_stops() -> bool {
+    true
+}
+
+const fn default_trim_stop_sequences() -> bool {
+    true
+}
+
+const fn default_first_token_cooldown_secs() -> u64 {
+    60
+}
+
+const fn default_templating() -> bool {
+    true
+}
+
+const fn default_max_n() -> usize {
+    4
+}
+
+const fn default_loop_window_secs() -> u64 {
+    60
+}
+
+const fn default_rate_limit_burst() -> u32 {
+    10
+}
+
+const fn default_session_ttl_secs() -> u64 {
+    30 * 60
+}
+
+const fn default_sweep_age_secs() -> u64 {
+    60 * 60
+}
+
+const fn default_max_image_size() -> usize {
+    10 * 1024 * 1024
+}
+
+const fn default_max_image_total_size() -> usize {
+    30 * 1024 * 1024
+}
+
+/// Base64 images of `max_image_total_size` with room for the text
+const fn default_max_body_size() -> usize {
+    48 * 1024 * 1024
+}
+
+const fn default_max_image_dimension() -> u32 {
+    2048
+}
+
+const fn default_image_upload_concurrency() -> usize {
+    2
+}
+
 /// A struct representing the configuration of the application
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
@@ -31,6 +253,18 @@ pub struct Config {
     pub auto_update: bool,
     #[serde(default = "default_max_retries")]
     pub max_retries: usize,
+    #[serde(default = "default_shutdown_grace_secs")]
+    pub shutdown_grace_secs: u64,
+    #[serde(default)]
+    pub warmup_cookies: bool,
+    #[serde(default = "default_warmup_concurrency")]
+    pub warmup_concurrency: usize,
+    /// Extra bootstrap attempts after a transient error
+    #[serde(default = "default_bootstrap_retries")]
+    pub bootstrap_retries: usize,
+    /// Delay before the first bootstrap retry, doubled on each further retry
+    #[serde(default = "default_bootstrap_backoff_ms")]
+    pub bootstrap_backoff_ms: u64,
 
     // Cookie configurations
     #[serde(default)]
@@ -41,33 +275,306 @@ pub struct Config {
     // Network settings
     #[serde(default = "default_max_connections")]
     pub max_connections: usize,
+    #[serde(default)]
+    pub max_concurrent_requests: usize,
+    #[serde(default = "default_max_queue_len")]
+    pub max_queue_len: usize,
+    #[serde(default = "default_queue_timeout_secs")]
+    pub queue_timeout_secs: u64,
+    /// Requests of a batch processed at the same time
+    #[serde(default = "default_batch_concurrency")]
+    pub batch_concurrency: usize,
+    #[serde(default = "default_max_batch_size")]
+    pub max_batch_size: usize,
+    #[serde(default)]
+    pub rate_limit_rpm: u32,
+    #[serde(default = "default_rate_limit_burst")]
+    pub rate_limit_burst: u32,
+    #[serde(default)]
+    pub rate_limit_by_ip: bool,
+    /// Concurrent streams of one API key, unlimited when 0
+    #[serde(default)]
+    pub max_streams_per_key: usize,
+    /// Stream caps of single API keys, overriding `max_streams_per_key`
+    #[serde(default)]
+    pub key_max_streams: HashMap<String, usize>,
+    #[serde(default)]
+    pub dedup_requests: bool,
+    /// Identical requests a key may send in a row before the next one is rejected, disabled when 0
+    #[serde(default)]
+    pub loop_max_repeats: usize,
+    /// Time span the identical requests must fall in to count as a loop
+    #[serde(default = "default_loop_window_secs")]
+    pub loop_window_secs: u64,
+    #[serde(default = "default_max_n")]
+    pub max_n: usize,
+    #[serde(default = "default_json_mode_retry")]
+    pub json_mode_retry: bool,
+    /// Translate deprecated OpenAI `functions` and `function_call` to tools, `tools` is preferred
+    #[serde(default = "default_legacy_functions")]
+    pub legacy_functions: bool,
+    #[serde(default = "default_keep_alive_secs")]
+    pub keep_alive_secs: u64,
+    /// Number stream events with incrementing `id:` fields
+    #[serde(default = "default_sse_event_ids")]
+    pub sse_event_ids: bool,
+    /// Reconnection delay hinted with `retry:` in the first stream event, disabled when 0
+    #[serde(default = "default_sse_retry_ms")]
+    pub sse_retry_ms: u64,
+    /// Report upstream and cookie rate limit state in response headers
+    #[serde(default)]
+    pub forward_rate_limit_headers: bool,
+    /// Report the digest of the cookie and the organization serving the request in response headers
+    #[serde(default)]
+    pub cookie_headers: bool,
+    /// Interval of debug progress logs during a stream, disabled when 0
+    #[serde(default)]
+    pub stream_progress_secs: u64,
+    /// Window to merge small text deltas into one frame, disabled when 0
+    #[serde(default)]
+    pub coalesce_ms: u64,
+    /// Characters after which merged deltas are flushed early
+    #[serde(default = "default_coalesce_chars")]
+    pub coalesce_chars: usize,
     password: String,
+    #[serde(default)]
+    admin_password: String,
+    #[serde(default)]
+    pub key_tags: HashMap<String, Vec<String>>,
     pub proxy: String,
     ip: String,
     port: u16,
+    #[serde(default)]
+    pub unix_socket: String,
+    /// PEM certificate chain, serve HTTPS when set together with the key
+    #[serde(default)]
+    pub tls_cert: String,
+    /// PEM private key of the certificate
+    #[serde(default)]
+    pub tls_key: String,
+    /// Seconds between checks for a renewed certificate, 0 disables reloading
+    #[serde(default = "default_tls_reload_secs")]
+    pub tls_reload_secs: u64,
 
     // Api settings
     #[serde(default)]
     pub pass_params: bool,
     #[serde(default)]
+    pub api_key: String,
+    #[serde(default)]
+    pub api_mode: bool,
+    #[serde(default)]
+    pub exhausted_policy: ExhaustedPolicy,
+    /// Order in which valid cookies are dispatched
+    #[serde(default)]
+    pub cookie_strategy: CookieStrategy,
+    #[serde(default = "default_cookie_wait_secs")]
+    pub cookie_wait_secs: u64,
+    /// Keep every conversation on Claude.ai, per request with the `x-clewdr-keep` header
+    #[serde(default)]
     pub preserve_chats: bool,
     #[serde(default)]
+    pub audit_dir: String,
+    #[serde(default)]
+    pub debug_endpoints: bool,
+    /// Log every request lifecycle event at debug level
+    #[serde(default)]
+    pub log_events: bool,
+    /// Compress responses with gzip or deflate when the client accepts it
+    #[serde(default)]
+    pub compression: bool,
+    /// Largest accepted request body in bytes, leave room for base64 images
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+    /// Indent JSON responses, also enabled per request with `?pretty=true`
+    #[serde(default)]
+    pub pretty_json: bool,
+    /// Regexes masked in dumped request and response logs
+    #[serde(default = "default_redact_patterns")]
+    pub redact_patterns: Vec<String>,
+    /// Mask message content in dumped logs, keeping the structure
+    #[serde(default)]
+    pub redact_content: bool,
+    #[serde(default)]
+    pub sweep_interval_secs: u64,
+    #[serde(default = "default_sweep_age_secs")]
+    pub sweep_age_secs: u64,
+    /// Idle time after which a pinned session is ended, 0 disables sessions
+    /// Keep it below `sweep_age_secs` when the sweeper is enabled
+    #[serde(default = "default_session_ttl_secs")]
+    pub session_ttl_secs: u64,
+    /// Turns after which a session moves to a fresh conversation, 0 disables
+    #[serde(default)]
+    pub max_session_depth: usize,
+    #[serde(default)]
     pub skip_warning: bool,
     #[serde(default)]
     pub skip_restricted: bool,
     #[serde(default)]
     pub skip_non_pro: bool,
+    #[serde(default)]
+    pub override_models: Vec<String>,
+    #[serde(default = "default_pro_models")]
+    pub pro_models: Vec<String>,
+    #[serde(default)]
+    pub model_aliases: HashMap<String, String>,
+    /// Trim and lowercase requested model names, matching configured names regardless of case
+    #[serde(default = "default_normalize_models")]
+    pub normalize_models: bool,
+    #[serde(default)]
+    pub known_models: Vec<String>,
+    /// Seconds between refreshes of the model list from the account, 0 disables
+    #[serde(default)]
+    pub model_refresh_secs: u64,
+    /// Seconds a refreshed model list is used before falling back to `known_models`
+    #[serde(default = "default_model_cache_ttl_secs")]
+    pub model_cache_ttl_secs: u64,
+    #[serde(default)]
+    pub blocked_models: Vec<String>,
+    #[serde(default)]
+    pub model_fallbacks: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub model_profiles: HashMap<String, ModelProfile>,
+    #[serde(default)]
+    pub name_conversations: bool,
+    #[serde(default = "default_conversation_name_template")]
+    pub conversation_name_template: String,
+    #[serde(default = "default_conversation_retries")]
+    pub conversation_retries: usize,
+
+    // Image settings
+    #[serde(default = "default_max_image_size")]
+    pub max_image_size: usize,
+    #[serde(default = "default_max_image_total_size")]
+    pub max_image_total_size: usize,
+    #[serde(default = "default_max_image_dimension")]
+    pub max_image_dimension: u32,
+    #[serde(default = "default_image_upload_concurrency")]
+    pub image_upload_concurrency: usize,
+    /// Directory `file` images may be read from, disabled when empty
+    #[serde(default)]
+    pub image_file_root: String,
 
     // Proxy configurations
     pub rproxy: String,
+    #[serde(default)]
+    pub rproxy_ca_file: String,
+    #[serde(default)]
+    pub rproxy_accept_invalid_certs: bool,
+    /// Endpoints tried in order after `rproxy` fails to connect
+    #[serde(default)]
+    pub rproxy_fallbacks: Vec<String>,
+    /// Seconds an endpoint is skipped after a connection failure
+    #[serde(default = "default_endpoint_cooldown_secs")]
+    pub endpoint_cooldown_secs: u64,
 
     // Prompt configurations
     pub use_real_roles: bool,
     pub custom_h: Option<String>,
     pub custom_a: Option<String>,
+    #[serde(default)]
+    pub logprobs_policy: LogprobsPolicy,
+    #[serde(default)]
+    pub empty_response_policy: EmptyResponsePolicy,
+    /// Text sent instead of an empty response with the `placeholder` policy
+    #[serde(default = "default_empty_response_placeholder")]
+    pub empty_response_placeholder: String,
+    /// Exact contents of client probes answered without calling Claude.ai
+    #[serde(default = "default_probe_messages")]
+    pub probe_messages: Vec<String>,
+    /// Canned response to a probe
+    #[serde(default = "default_probe_response")]
+    pub probe_response: String,
+    /// Markdown formatting removed from the response, not applied when tools are sent
+    #[serde(default)]
+    pub strip_markdown: MarkdownStrip,
+    /// Strip `leading_pattern` from the start of the response
+    #[serde(default)]
+    pub trim_leading: bool,
+    /// Pattern matched at the start of the response only
+    #[serde(default = "default_leading_pattern")]
+    pub leading_pattern: String,
+    /// Strip the start of the response when it repeats the end of the assistant prefill
+    #[serde(default = "default_trim_prefill_overlap")]
+    pub trim_prefill_overlap: bool,
+    /// Shortest repeated text stripped, in bytes
+    #[serde(default = "default_prefill_min_overlap")]
+    pub prefill_min_overlap: usize,
+    /// Substitute `{{variables}}` in the system prompt and messages
+    #[serde(default = "default_templating")]
+    pub templating: bool,
+    /// Global template variables, overridden by the `variables` of a request
+    #[serde(default)]
+    pub template_variables: HashMap<String, String>,
+    /// Reject requests using unknown variables instead of keeping the token
+    #[serde(default)]
+    pub strict_variables: bool,
+    /// Text put before the role name of every turn in the prompt
+    #[serde(default = "default_turn_separator")]
+    pub turn_separator: String,
+    /// Cut the output where the model starts a new turn on its own
+    #[serde(default = "default_turn_stops")]
+    pub turn_stops: bool,
+    /// Seconds a stream may wait for its first event before switching cookies, disabled when 0
+    #[serde(default)]
+    pub first_token_timeout_secs: u64,
+    /// Seconds a cookie rests after missing the first token deadline
+    #[serde(default = "default_first_token_cooldown_secs")]
+    pub first_token_cooldown_secs: u64,
+    /// Never return client stop sequences in the output
+    #[serde(default = "default_trim_stop_sequences")]
+    pub trim_stop_sequences: bool,
     pub custom_prompt: String,
+    /// Size in bytes above which the pasted history is split into several attachments, 0 disables
+    #[serde(default)]
+    pub attachment_max_bytes: usize,
     pub padtxt_file: String,
     pub padtxt_len: usize,
+    #[serde(default)]
+    pub system_mode: SystemMode,
+    #[serde(default)]
+    pub jailbreak_position: JailbreakPosition,
+    #[serde(default)]
+    pub system_prepend: String,
+    #[serde(default)]
+    pub system_append: String,
+    /// IANA timezone sent to Claude.ai
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    /// Timezones of single models, overriding `timezone`
+    #[serde(default)]
+    pub model_timezones: HashMap<String, String>,
+    /// Text joining the `system` field and every system message into one system prompt
+    #[serde(default = "default_system_separator")]
+    pub system_separator: String,
+    #[serde(default)]
+    pub strip_tags: bool,
+    #[serde(default = "default_strip_open_tag")]
+    pub strip_open_tag: String,
+    #[serde(default = "default_strip_close_tag")]
+    pub strip_close_tag: String,
+    /// Inline thinking tags, takes precedence over `strip_tags` which is ignored when enabled
+    #[serde(default)]
+    pub thinking_tags: ThinkingTags,
+    #[serde(default = "default_strip_open_tag")]
+    pub thinking_open_tag: String,
+    #[serde(default = "default_strip_close_tag")]
+    pub thinking_close_tag: String,
+    #[serde(default = "default_ignored_system_prompts")]
+    pub ignored_system_prompts: Vec<String>,
+    /// Insert `new_chat_sentinel` between the system prompt and the history of a new conversation
+    #[serde(default)]
+    pub inject_new_chat: bool,
+    #[serde(default = "default_new_chat_sentinel")]
+    pub new_chat_sentinel: String,
+    #[serde(default)]
+    pub max_history_messages: usize,
+    #[serde(default)]
+    pub context_limits: HashMap<String, usize>,
+    #[serde(default)]
+    pub trim_strategy: TrimStrategy,
+    #[serde(default)]
+    pub tokenizer: TokenizerKind,
 
     // Skip field
     #[serde(skip)]
@@ -76,15 +583,145 @@ pub struct Config {
     pub pad_tokens: Vec<String>,
 }
 
+/// Default sampling parameters of a model
+/// Applied when the client omits the value
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ModelProfile {
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub top_k: Option<u64>,
+    #[serde(default)]
+    pub max_tokens: Option<u64>,
+}
+
+/// Strategy to drop messages when the prompt exceeds the context window
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TrimStrategy {
+    /// Drop the oldest messages first
+    #[default]
+    OldestFirst,
+    /// Drop messages from the middle of the conversation outwards
+    MiddleOut,
+}
+
+/// How thinking is returned on the Claude API
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ThinkingTags {
+    /// Native thinking blocks only
+    #[default]
+    Off,
+    /// Thinking wrapped in tags inside the text, instead of thinking blocks
+    Inline,
+    /// Native thinking blocks followed by the tagged text
+    Both,
+}
+
+/// Where messages flagged as jailbreak are placed in the prompt
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JailbreakPosition {
+    /// After the whole history, as the final instruction
+    #[default]
+    PostHistory,
+    /// Before the history, right after the leading system messages
+    PrePrompt,
+}
+
+/// How the system prompt is sent to Claude.ai
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SystemMode {
+    /// Prepend the system prompt to the pasted conversation
+    #[default]
+    MergeIntoPrompt,
+    /// Send the system prompt in the `system` field of the request
+    NativeSystem,
+    /// Drop the system prompt of the client
+    Discard,
+}
+
+/// Response to OpenAI clients asking for `logprobs`, which Claude.ai does not provide
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogprobsPolicy {
+    /// Reject the request with a 400 explaining logprobs are unsupported
+    #[default]
+    Reject,
+    /// Serve the request with `logprobs: null` in every choice
+    Null,
+}
+
+/// Behavior when Claude.ai returns an empty or whitespace-only response
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyResponsePolicy {
+    /// Return the empty response as is
+    #[default]
+    Pass,
+    /// Fail with an error
+    Error,
+    /// Retry once on a fresh conversation, then fail
+    Retry,
+    /// Replace it with `empty_response_placeholder`
+    Placeholder,
+}
+
+/// Behavior when every cookie is exhausted
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExhaustedPolicy {
+    /// Fail immediately, reporting the earliest reset time
+    #[default]
+    FailFast,
+    /// Wait for a cookie to reset, up to `cookie_wait_secs`
+    Wait,
+    /// Forward the request to the official API with `api_key`
+    FallbackApiKey,
+}
+
+/// Order in which valid cookies are dispatched
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CookieStrategy {
+    /// Cycle through the pool, returned cookies go to the back
+    #[default]
+    RoundRobin,
+    /// Cookie dispatched the longest time ago
+    LeastRecentlyUsed,
+    /// Uniformly random cookie
+    Random,
+    /// Random cookie, proportional to its `weight`
+    Weighted,
+}
+
 /// Reason why a cookie is considered useless
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub enum Reason {
     NonPro,
     Banned,
+    Suspended,
     Null,
     Unverified,
     Restricted(i64),
     TooManyRequest(i64),
+    /// Rejected by Claude.ai, with the upstream status and a short reason
+    Rejected(u16, String),
+}
+
+impl Reason {
+    /// Upstream status code behind the reason, if known
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Reason::TooManyRequest(_) => Some(429),
+            Reason::Rejected(s, _) => Some(*s),
+            _ => None,
+        }
+    }
 }
 
 impl Display for Reason {
@@ -92,6 +729,7 @@ impl Display for Reason {
         match self {
             Reason::NonPro => write!(f, "NonPro"),
             Reason::Banned => write!(f, "Banned"),
+            Reason::Suspended => write!(f, "Suspended"),
             Reason::Null => write!(f, "Null"),
             Reason::Unverified => write!(f, "Unverified"),
             Reason::Restricted(i) => {
@@ -106,6 +744,7 @@ impl Display for Reason {
                     .unwrap_or("Invalid date".to_string().yellow());
                 write!(f, "429 Too many request: until {}", time)
             }
+            Reason::Rejected(s, r) => write!(f, "Rejected ({}): {}", s, r),
         }
     }
 }
@@ -143,6 +782,23 @@ pub struct CookieStatus {
     pub reset_time: Option<i64>,
     pub discord: Option<String>,
     pub due: Option<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Share of dispatches under the `weighted` strategy, at least 1
+    #[serde(default, skip_serializing_if = "is_default_weight")]
+    pub weight: u32,
+    /// Milliseconds timestamp of the last dispatch
+    #[serde(skip)]
+    pub last_used: Option<i64>,
+    #[serde(skip)]
+    pub org_uuid: Option<String>,
+    #[serde(skip)]
+    pub capabilities: Vec<String>,
+    #[serde(skip)]
+    pub bootstrapped_at: Option<i64>,
+    /// Models Claude.ai refused for the account of this cookie
+    #[serde(skip)]
+    pub unavailable_models: HashSet<String>,
 }
 
 impl PartialOrd for CookieStatus {
@@ -182,6 +838,10 @@ impl Hash for CookieStatus {
 /// Default cookie value for testing purposes
 const PLACEHOLDER_COOKIE: &str = "sk-ant-REDACTED";
 
+fn is_default_weight(weight: &u32) -> bool {
+    *weight <= 1
+}
+
 /// Function to validate the reset time of a cookie while deserializing
 fn validate_reset<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
 where
@@ -226,8 +886,22 @@ impl CookieStatus {
             reset_time,
             discord,
             due,
+            ..Default::default()
         }
     }
+
+    /// Weight of the cookie, unset weights count as 1
+    pub fn weight(&self) -> u32 {
+        self.weight.max(1)
+    }
+
+    /// Check if the cached bootstrap result can be reused
+    pub fn bootstrap_fresh(&self) -> bool {
+        self.org_uuid.is_some()
+            && self
+                .bootstrapped_at
+                .is_some_and(|t| chrono::Utc::now().timestamp() - t < BOOTSTRAP_CACHE_SECS)
+    }
 }
 
 /// A struct representing a cookie
@@ -256,6 +930,13 @@ impl CookieInfo {
         // Clear the cookie
         self.inner.clear();
     }
+
+    /// Hash identifying the cookie in logs without revealing it, as in audit records
+    pub fn digest(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        self.to_string().hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
 }
 
 impl From<&str> for CookieInfo {
@@ -334,6 +1015,11 @@ impl Default for Config {
     fn default() -> Self {
         Self {
             max_retries: default_max_retries(),
+            shutdown_grace_secs: default_shutdown_grace_secs(),
+            warmup_cookies: false,
+            warmup_concurrency: default_warmup_concurrency(),
+            bootstrap_retries: default_bootstrap_retries(),
+            bootstrap_backoff_ms: default_bootstrap_backoff_ms(),
             check_update: true,
             auto_update: false,
             cookie_array: vec![
@@ -347,24 +1033,132 @@ impl Default for Config {
             ],
             wasted_cookie: Vec::new(),
             password: String::new(),
+            admin_password: String::new(),
+            key_tags: HashMap::new(),
+            max_streams_per_key: 0,
+            key_max_streams: HashMap::new(),
             proxy: String::new(),
             ip: "127.0.0.1".to_string(),
             port: 8484,
+            unix_socket: String::new(),
+            tls_cert: String::new(),
+            tls_key: String::new(),
+            tls_reload_secs: default_tls_reload_secs(),
             max_connections: default_max_connections(),
+            max_concurrent_requests: 0,
+            max_queue_len: default_max_queue_len(),
+            queue_timeout_secs: default_queue_timeout_secs(),
+            batch_concurrency: default_batch_concurrency(),
+            max_batch_size: default_max_batch_size(),
+            rate_limit_rpm: 0,
+            rate_limit_burst: default_rate_limit_burst(),
+            rate_limit_by_ip: false,
+            dedup_requests: false,
+            loop_max_repeats: 0,
+            loop_window_secs: default_loop_window_secs(),
+            max_n: default_max_n(),
+            json_mode_retry: default_json_mode_retry(),
+            legacy_functions: default_legacy_functions(),
+            keep_alive_secs: default_keep_alive_secs(),
+            forward_rate_limit_headers: false,
+            stream_progress_secs: 0,
+            cookie_headers: false,
+            sse_event_ids: default_sse_event_ids(),
+            sse_retry_ms: default_sse_retry_ms(),
+            coalesce_ms: 0,
+            coalesce_chars: default_coalesce_chars(),
             rproxy: String::new(),
+            rproxy_ca_file: String::new(),
+            rproxy_accept_invalid_certs: false,
+            rproxy_fallbacks: vec![],
+            endpoint_cooldown_secs: default_endpoint_cooldown_secs(),
             use_real_roles: true,
             custom_prompt: String::new(),
+            attachment_max_bytes: 0,
             padtxt_file: String::new(),
             padtxt_len: 4000,
+            system_mode: SystemMode::default(),
+            jailbreak_position: JailbreakPosition::default(),
+            system_prepend: String::new(),
+            system_append: String::new(),
+            timezone: default_timezone(),
+            model_timezones: HashMap::new(),
+            system_separator: default_system_separator(),
+            strip_tags: false,
+            strip_open_tag: default_strip_open_tag(),
+            strip_close_tag: default_strip_close_tag(),
+            thinking_tags: ThinkingTags::Off,
+            thinking_open_tag: default_strip_open_tag(),
+            thinking_close_tag: default_strip_close_tag(),
+            ignored_system_prompts: default_ignored_system_prompts(),
+            inject_new_chat: false,
+            new_chat_sentinel: default_new_chat_sentinel(),
+            max_history_messages: 0,
+            context_limits: HashMap::new(),
+            trim_strategy: TrimStrategy::default(),
+            tokenizer: TokenizerKind::default(),
             custom_h: None,
             custom_a: None,
+            logprobs_policy: LogprobsPolicy::Reject,
+            empty_response_policy: EmptyResponsePolicy::default(),
+            empty_response_placeholder: default_empty_response_placeholder(),
+            probe_messages: default_probe_messages(),
+            probe_response: default_probe_response(),
+            strip_markdown: MarkdownStrip::Off,
+            trim_leading: false,
+            leading_pattern: default_leading_pattern(),
+            trim_prefill_overlap: default_trim_prefill_overlap(),
+            prefill_min_overlap: default_prefill_min_overlap(),
+            templating: default_templating(),
+            template_variables: HashMap::new(),
+            strict_variables: false,
+            turn_separator: default_turn_separator(),
+            turn_stops: default_turn_stops(),
+            trim_stop_sequences: default_trim_stop_sequences(),
+            first_token_timeout_secs: 0,
+            first_token_cooldown_secs: default_first_token_cooldown_secs(),
             rquest_proxy: None,
             pad_tokens: Vec::new(),
             pass_params: false,
+            api_key: String::new(),
+            api_mode: false,
+            exhausted_policy: ExhaustedPolicy::default(),
+            cookie_strategy: CookieStrategy::default(),
+            cookie_wait_secs: default_cookie_wait_secs(),
             preserve_chats: false,
+            audit_dir: String::new(),
+            debug_endpoints: false,
+            log_events: false,
+            compression: false,
+            max_body_size: default_max_body_size(),
+            pretty_json: false,
+            redact_patterns: default_redact_patterns(),
+            redact_content: false,
+            sweep_interval_secs: 0,
+            sweep_age_secs: default_sweep_age_secs(),
+            session_ttl_secs: default_session_ttl_secs(),
+            max_session_depth: 0,
             skip_warning: false,
             skip_restricted: false,
             skip_non_pro: false,
+            override_models: Vec::new(),
+            pro_models: default_pro_models(),
+            model_aliases: HashMap::new(),
+            normalize_models: default_normalize_models(),
+            known_models: Vec::new(),
+            model_refresh_secs: 0,
+            model_cache_ttl_secs: default_model_cache_ttl_secs(),
+            blocked_models: Vec::new(),
+            model_fallbacks: HashMap::new(),
+            model_profiles: HashMap::new(),
+            name_conversations: false,
+            conversation_name_template: default_conversation_name_template(),
+            conversation_retries: default_conversation_retries(),
+            max_image_size: default_max_image_size(),
+            max_image_total_size: default_max_image_total_size(),
+            max_image_dimension: default_max_image_dimension(),
+            image_upload_concurrency: default_image_upload_concurrency(),
+            image_file_root: String::new(),
         }
     }
 }
@@ -401,14 +1195,54 @@ impl Display for Config {
 }
 
 impl Config {
+    /// Timezone of a request, from the header, the model or the config in that order
+    /// Invalid names fall back to UTC
+    pub fn timezone_for(&self, requested: Option<&str>, model: &str) -> String {
+        let tz = requested
+            .or(self.model_timezones.get(model).map(|t| t.as_str()))
+            .unwrap_or(&self.timezone)
+            .trim();
+        if tz.parse::<chrono_tz::Tz>().is_ok() {
+            return tz.to_string();
+        }
+        warn!(
+            "Invalid timezone `{}`, falling back to {}",
+            tz, FALLBACK_TIME_ZONE
+        );
+        FALLBACK_TIME_ZONE.to_string()
+    }
+
+    /// Cooldown of a cookie that missed the first token deadline
+    pub fn first_token_cooldown(&self) -> Reason {
+        let until = chrono::Utc::now().timestamp() + self.first_token_cooldown_secs as i64;
+        Reason::TooManyRequest(until)
+    }
+
+    /// Concurrent stream cap of the API key, 0 when unlimited
+    pub fn stream_limit(&self, key: &str) -> usize {
+        self.key_max_streams
+            .get(key)
+            .copied()
+            .unwrap_or(self.max_streams_per_key)
+    }
+
     pub fn auth(&self, key: &str) -> bool {
-        key == self.password
+        key == self.password || self.key_tags.contains_key(key)
     }
 
-    /// Load the configuration from the file
-    pub fn load() -> Result<Self, ClewdrError> {
-        // try to read from pwd
-        let file_string = std::fs::read_to_string(CONFIG_NAME).or_else(|e| {
+    /// Check the admin password, the normal password is used when it is not set
+    ///
+    /// Per-key credentials from `key_tags` are never admin credentials
+    pub fn admin_auth(&self, key: &str) -> bool {
+        if self.admin_password.is_empty() {
+            return key == self.password;
+        }
+        key == self.admin_password
+    }
+
+    /// Read the config file from pwd, or next to the executable
+    fn read_file() -> std::io::Result<String> {
+        std::fs::read_to_string(CONFIG_NAME).or_else(|e| {
             if e.kind() == std::io::ErrorKind::NotFound {
                 // try to read from exec path
                 let exec_path = std::env::current_exe()?;
@@ -421,8 +1255,20 @@ impl Config {
             } else {
                 Err(e)
             }
-        });
-        match file_string {
+        })
+    }
+
+    /// Read the configuration of a running instance, without writing the file back
+    pub fn reload() -> Result<Self, ClewdrError> {
+        // cookies of the arg file were merged into the file on startup
+        let mut config: Config = toml::de::from_str(&Self::read_file()?)?;
+        config.load_padtxt();
+        Ok(config.validate())
+    }
+
+    /// Load the configuration from the file
+    pub fn load() -> Result<Self, ClewdrError> {
+        match Self::read_file() {
             Ok(file_string) => {
                 // parse the config file
                 let mut config: Config = toml::de::from_str(&file_string)?;
@@ -490,16 +1336,358 @@ impl Config {
         self.pad_tokens = tokens;
     }
 
-    /// API endpoint of server
+    /// API endpoint of server, the first one not cooling down
     pub fn endpoint(&self) -> String {
-        if self.rproxy.is_empty() {
+        let endpoints = self.endpoints();
+        endpoints
+            .iter()
+            .find(|e| endpoint_healthy(e))
+            .unwrap_or(&endpoints[0])
+            .clone()
+    }
+
+    /// API endpoints in order of priority, never empty
+    pub fn endpoints(&self) -> Vec<String> {
+        let primary = if self.rproxy.is_empty() {
             ENDPOINT.to_string()
         } else {
             self.rproxy.clone()
+        };
+        let mut endpoints = vec![primary];
+        for e in &self.rproxy_fallbacks {
+            if !e.is_empty() && !endpoints.contains(e) {
+                endpoints.push(e.clone());
+            }
         }
+        endpoints
+    }
+
+    /// Context window of the model, if configured
+    pub fn context_limit(&self, model: &str) -> Option<usize> {
+        self.context_limits.get(model).copied()
+    }
+
+    /// Context window of the model with the 1M context beta enabled
+    pub fn extended_context_limit(&self, model: &str) -> Option<usize> {
+        self.context_limit(model)
+            .map(|l| l.max(EXTENDED_CONTEXT_LIMIT))
+    }
+
+    /// Passwords and API keys of the proxy itself
+    pub fn secrets(&self) -> impl Iterator<Item = &str> {
+        [self.password.as_str(), self.admin_password.as_str()]
+            .into_iter()
+            .chain(self.key_tags.keys().map(|k| k.as_str()))
+            .filter(|s| !s.is_empty())
+    }
+
+    /// Stripper of markdown formatting, if enabled and the response has no tool calls
+    pub fn markdown_stripper(&self, tools: bool) -> Option<MarkdownStripper> {
+        if tools {
+            return None;
+        }
+        MarkdownStripper::new(self.strip_markdown)
+    }
+
+    /// Trimmer of the response prefix, if enabled and the pattern is valid
+    pub fn leading_trimmer(&self) -> Option<LeadingTrimmer> {
+        if !self.trim_leading || self.leading_pattern.is_empty() {
+            return None;
+        }
+        match Regex::new(&self.leading_pattern) {
+            Ok(re) => Some(LeadingTrimmer::new(re)),
+            Err(e) => {
+                warn!("Invalid leading pattern: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Trimmer of the prefill repeated by the response, if enabled and there is a prefill
+    pub fn prefill_trimmer(&self, prefill: Option<&str>) -> Option<PrefillTrimmer> {
+        let prefill = prefill.filter(|p| !p.trim().is_empty())?;
+        self.trim_prefill_overlap
+            .then(|| PrefillTrimmer::new(prefill, self.prefill_min_overlap))
+    }
+
+    /// Apply the empty response policy to a complete response
+    pub fn check_empty(&self, text: String) -> Result<String, ClewdrError> {
+        if !text.trim().is_empty() {
+            return Ok(text);
+        }
+        warn!("Empty response from Claude.ai");
+        match self.empty_response_policy {
+            EmptyResponsePolicy::Pass => Ok(text),
+            EmptyResponsePolicy::Error | EmptyResponsePolicy::Retry => {
+                Err(ClewdrError::EmptyResponse)
+            }
+            EmptyResponsePolicy::Placeholder => Ok(self.empty_response_placeholder.clone()),
+        }
+    }
+
+    /// Stripper of tagged regions in the output, if enabled
+    /// Inline thinking tags take precedence, the tagged thinking is kept
+    pub fn tag_stripper(&self) -> Option<TagStripper> {
+        if !self.strip_tags
+            || self.strip_open_tag.is_empty()
+            || self.strip_close_tag.is_empty()
+            || self.thinking_tags != ThinkingTags::Off
+        {
+            return None;
+        }
+        Some(TagStripper::new(
+            &self.strip_open_tag,
+            &self.strip_close_tag,
+        ))
+    }
+
+    /// SSE comment pings sent when the stream is idle, disabled when 0
+    pub fn keep_alive(&self) -> Option<KeepAlive> {
+        (self.keep_alive_secs > 0).then(|| {
+            KeepAlive::new()
+                .interval(Duration::from_secs(self.keep_alive_secs))
+                .text("ping")
+        })
+    }
+
+    /// Resolve model aliases and check the model against the known models and `blocked_models`
+    /// The `-thinking` suffix used by OpenAI clients is kept
+    pub fn resolve_model(&self, model: &str) -> Result<String, ClewdrError> {
+        let model = self.normalize_model(model);
+        let model = model.as_str();
+        if self.is_blocked(model) {
+            return Err(ClewdrError::InvalidModel(model.to_string()));
+        }
+        if let Some(m) = self.model_alias(model) {
+            return self.check_known(m).map(|_| m.to_string());
+        }
+        let (base, suffix) = match model.strip_suffix("-thinking") {
+            Some(base) => (base, "-thinking"),
+            None => (model, ""),
+        };
+        let base = self.model_alias(base).map_or(base, |m| m.as_str());
+        self.check_known(base)?;
+        Ok(format!("{}{}", base, suffix))
+    }
+
+    /// Resolve the model followed by its usable `model_fallbacks`
+    /// Also returns whether the requested model itself was unusable,
+    /// its error is returned when no fallback is usable either
+    pub fn model_chain(&self, model: &str) -> Result<(Vec<String>, bool), ClewdrError> {
+        let model = self.normalize_model(model);
+        let model = model.as_str();
+        let fallbacks = self
+            .model_fallbacks
+            .iter()
+            .find(|(k, _)| self.same_model(k, model))
+            .map(|(_, v)| v)
+            .into_iter()
+            .flatten()
+            .filter_map(|m| self.resolve_model(m).ok());
+        match self.resolve_model(model) {
+            Ok(m) => Ok((std::iter::once(m).chain(fallbacks).collect(), false)),
+            Err(e) => {
+                let chain = fallbacks.collect::<Vec<_>>();
+                if chain.is_empty() {
+                    return Err(e);
+                }
+                Ok((chain, true))
+            }
+        }
+    }
+
+    fn check_known(&self, model: &str) -> Result<(), ClewdrError> {
+        let model = model.trim_end_matches("-thinking");
+        if self.is_blocked(model) {
+            return Err(ClewdrError::InvalidModel(model.to_string()));
+        }
+        let known = self.current_models();
+        if known.is_empty() || known.iter().any(|m| self.same_model(m, model)) {
+            return Ok(());
+        }
+        Err(ClewdrError::InvalidModel(model.to_string()))
+    }
+
+    fn is_blocked(&self, model: &str) -> bool {
+        let model = model.trim_end_matches("-thinking");
+        self.blocked_models
+            .iter()
+            .any(|m| self.same_model(m, model))
+    }
+
+    /// Requested model name, trimmed and lowercased if `normalize_models` is set
+    pub fn normalize_model(&self, model: &str) -> String {
+        if self.normalize_models {
+            model.trim().to_lowercase()
+        } else {
+            model.to_string()
+        }
+    }
+
+    /// Compare model names, ignoring case if `normalize_models` is set
+    fn same_model(&self, a: &str, b: &str) -> bool {
+        if self.normalize_models {
+            a.eq_ignore_ascii_case(b)
+        } else {
+            a == b
+        }
+    }
+
+    /// Target of the alias of the model, if any
+    fn model_alias(&self, model: &str) -> Option<&String> {
+        self.model_aliases.get(model).or_else(|| {
+            self.model_aliases
+                .iter()
+                .find(|(k, _)| self.same_model(k, model))
+                .map(|(_, v)| v)
+        })
+    }
+
+    /// Models listed by `/v1/models`, known models and aliases that are not blocked
+    pub fn listed_models(&self) -> Vec<String> {
+        self.current_models()
+            .iter()
+            .chain(self.model_aliases.keys())
+            .filter(|m| self.resolve_model(m).is_ok())
+            .unique()
+            .sorted()
+            .cloned()
+            .collect()
+    }
+
+    /// Human and assistant role names used in the prompt
+    pub fn role_names(&self) -> (&str, &str) {
+        (
+            self.custom_h.as_deref().unwrap_or("Human"),
+            self.custom_a.as_deref().unwrap_or("Assistant"),
+        )
+    }
+
+    /// Separator of a human and an assistant turn, e.g. `\n\nHuman:`
+    pub fn turn_separators(&self) -> (String, String) {
+        let (h, a) = self.role_names();
+        (
+            format!("{}{}:", self.turn_separator, h),
+            format!("{}{}:", self.turn_separator, a),
+        )
+    }
+
+    /// Stops cutting the output at a turn the model starts itself,
+    /// derived from the separators so they stay in sync
+    pub fn default_stop_sequences(&self) -> Vec<String> {
+        if !self.turn_stops || self.turn_separator.is_empty() {
+            return vec![];
+        }
+        let (h, a) = self.turn_separators();
+        vec![h, a]
+    }
+
+    /// Fields changed in `new` that are only read on startup
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        [
+            ("ip", self.ip != new.ip),
+            ("port", self.port != new.port),
+            ("unix_socket", self.unix_socket != new.unix_socket),
+            ("tls_cert", self.tls_cert != new.tls_cert),
+            ("tls_key", self.tls_key != new.tls_key),
+            (
+                "max_connections",
+                self.max_connections != new.max_connections,
+            ),
+            ("rate_limit_rpm", self.rate_limit_rpm != new.rate_limit_rpm),
+            (
+                "loop_max_repeats",
+                self.loop_max_repeats != new.loop_max_repeats,
+            ),
+            (
+                "loop_window_secs",
+                self.loop_window_secs != new.loop_window_secs,
+            ),
+            (
+                "rate_limit_burst",
+                self.rate_limit_burst != new.rate_limit_burst,
+            ),
+            ("rproxy_ca_file", self.rproxy_ca_file != new.rproxy_ca_file),
+            (
+                "rproxy_accept_invalid_certs",
+                self.rproxy_accept_invalid_certs != new.rproxy_accept_invalid_certs,
+            ),
+            (
+                "redact_patterns",
+                self.redact_patterns != new.redact_patterns,
+            ),
+            ("redact_content", self.redact_content != new.redact_content),
+            (
+                "debug_endpoints",
+                self.debug_endpoints != new.debug_endpoints,
+            ),
+            ("compression", self.compression != new.compression),
+            ("max_body_size", self.max_body_size != new.max_body_size),
+            (
+                "shutdown_grace_secs",
+                self.shutdown_grace_secs != new.shutdown_grace_secs,
+            ),
+            (
+                "sweep_interval_secs",
+                self.sweep_interval_secs != new.sweep_interval_secs,
+            ),
+            (
+                "model_refresh_secs",
+                self.model_refresh_secs != new.model_refresh_secs,
+            ),
+            (
+                "tls_reload_secs",
+                self.tls_reload_secs != new.tls_reload_secs,
+            ),
+            // the reverse proxy client is built once
+            ("rproxy", self.rproxy != new.rproxy),
+            (
+                "rproxy_fallbacks",
+                self.rproxy_fallbacks != new.rproxy_fallbacks,
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(field, _)| field)
+        .collect()
+    }
+
+    /// Check if the model is only available to Pro accounts
+    pub fn requires_pro(&self, model: &str) -> bool {
+        self.pro_models.iter().any(|m| model.contains(m.as_str()))
+    }
+
+    /// Name of a new conversation
+    /// Supports `{timestamp}`, `{model}` and `{uuid}` placeholders
+    pub fn conversation_name(&self, model: &str, uuid: &str) -> String {
+        if !self.name_conversations {
+            return String::new();
+        }
+        self.conversation_name_template
+            .replace("{timestamp}", &chrono::Utc::now().timestamp().to_string())
+            .replace("{model}", model)
+            .replace("{uuid}", uuid)
+    }
+
+    /// Static prefix of conversation names, used to recognize clewdr chats
+    pub fn conversation_name_prefix(&self) -> Option<&str> {
+        if !self.name_conversations {
+            return None;
+        }
+        let prefix = self
+            .conversation_name_template
+            .split('{')
+            .next()
+            .unwrap_or_default();
+        (!prefix.is_empty()).then_some(prefix)
     }
 
     /// address of proxy
+    /// Whether a certificate and key are configured
+    pub fn tls_enabled(&self) -> bool {
+        !self.tls_cert.is_empty() && !self.tls_key.is_empty()
+    }
+
     pub fn address(&self) -> String {
         format!("{}:{}", self.ip, self.port)
     }
@@ -538,6 +1726,12 @@ impl Config {
         }
         self.ip = self.ip.trim().to_string();
         self.rproxy = self.rproxy.trim().to_string();
+        self.rproxy_fallbacks = self
+            .rproxy_fallbacks
+            .iter()
+            .map(|e| e.trim().trim_end_matches('/').to_string())
+            .filter(|e| !e.is_empty())
+            .collect();
         self.proxy = self.proxy.trim().to_string();
         let proxy = if self.proxy.is_empty() {
             None
@@ -581,9 +1775,7 @@ impl Config {
                 }
                 Some(CookieStatus {
                     cookie: c,
-                    reset_time: None,
-                    discord: None,
-                    due: None,
+                    ..Default::default()
                 })
             })
             .collect::<Vec<_>>();
@@ -593,3 +1785,41 @@ impl Config {
         self.cookie_array.extend(new_array);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_names_are_normalized() {
+        let mut config = Config::default();
+        config
+            .model_aliases
+            .insert("Opus".to_string(), "claude-opus-4".to_string());
+        config.known_models = vec!["claude-opus-4".to_string(), "Claude-Sonnet-4".to_string()];
+        config.blocked_models = vec!["Claude-Haiku".to_string()];
+        assert_eq!(config.resolve_model(" OPUS ").unwrap(), "claude-opus-4");
+        assert_eq!(
+            config.resolve_model("claude-sonnet-4-thinking").unwrap(),
+            "claude-sonnet-4-thinking"
+        );
+        assert!(config.resolve_model("claude-haiku").is_err());
+        config.normalize_models = false;
+        assert!(config.resolve_model("CLAUDE-OPUS-4").is_err());
+    }
+
+    #[test]
+    fn tagged_keys_are_not_admin() {
+        let mut config = Config::default();
+        config.password = "main".to_string();
+        config
+            .key_tags
+            .insert("team".to_string(), vec!["a".to_string()]);
+        assert!(config.auth("team"));
+        assert!(!config.admin_auth("team"));
+        assert!(config.admin_auth("main"));
+        config.admin_password = "admin".to_string();
+        assert!(!config.admin_auth("main"));
+        assert!(config.admin_auth("admin"));
+    }
+}
@@ -1,27 +1,48 @@
+use arc_swap::ArcSwap;
 use colored::Colorize;
-use std::collections::{HashMap, HashSet, VecDeque};
+use futures::FutureExt;
+use itertools::Itertools;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    panic::AssertUnwindSafe,
+    sync::Arc,
+    time::Duration,
+};
 use tokio::{
     select,
     sync::{mpsc::Receiver, oneshot},
-    time::{Instant, Interval},
+    time::{Instant, Interval, sleep},
 };
 use tracing::{error, info, warn};
 
 use crate::{
-    config::{Config, CookieStatus, Reason, UselessCookie},
+    config::{Config, CookieStatus, ExhaustedPolicy, Reason, UselessCookie},
     error::ClewdrError,
+    state::has_pro,
 };
 
+/// Request for a cookie, limited to cookies with one of the tags if any
+/// and to cookies able to serve the model if set
+#[derive(Debug)]
+pub struct CookieRequest {
+    pub tx: oneshot::Sender<Result<CookieStatus, ClewdrError>>,
+    pub tags: Vec<String>,
+    pub model: Option<String>,
+}
+
 pub struct CookieManager {
     valid: VecDeque<CookieStatus>,
     dispatched: HashMap<CookieStatus, Instant>,
     exhausted: HashSet<CookieStatus>,
     invalid: HashSet<UselessCookie>,
-    req_rx: Receiver<oneshot::Sender<Result<CookieStatus, ClewdrError>>>,
+    req_rx: Receiver<CookieRequest>,
     ret_rx: Receiver<(CookieStatus, Option<Reason>)>,
     submit_rx: Receiver<CookieStatus>,
     config: Config,
+    /// Latest config, so saving keeps reloaded settings
+    live_config: Arc<ArcSwap<Config>>,
     interval: Interval,
+    waiters: VecDeque<CookieRequest>,
 }
 
 impl CookieStatus {
@@ -41,12 +62,32 @@ impl CookieStatus {
     }
 }
 
+impl CookieStatus {
+    /// Check if the cookie has one of the tags, any cookie matches no tags
+    pub fn has_tag(&self, tags: &[String]) -> bool {
+        tags.is_empty() || self.tags.iter().any(|t| tags.contains(t))
+    }
+
+    /// Check if the account may serve the model
+    /// Cookies not bootstrapped yet are given the benefit of the doubt
+    pub fn supports(&self, model: Option<&str>, config: &Config) -> bool {
+        let Some(model) = model else {
+            return true;
+        };
+        if self.unavailable_models.contains(model) {
+            return false;
+        }
+        self.capabilities.is_empty() || !config.requires_pro(model) || has_pro(&self.capabilities)
+    }
+}
+
 impl CookieManager {
     pub fn new(
         mut config: Config,
-        req_rx: Receiver<oneshot::Sender<Result<CookieStatus, ClewdrError>>>,
+        req_rx: Receiver<CookieRequest>,
         ret_rx: Receiver<(CookieStatus, Option<Reason>)>,
         submit_rx: Receiver<CookieStatus>,
+        live_config: Arc<ArcSwap<Config>>,
     ) -> Self {
         config.cookie_array = config.cookie_array.into_iter().map(|c| c.reset()).collect();
         let valid = VecDeque::from_iter(config.cookie_array.iter().filter_map(|c| {
@@ -73,10 +114,12 @@ impl CookieManager {
             invalid,
             req_rx,
             config,
+            live_config,
             ret_rx,
             submit_rx,
             dispatched,
             interval,
+            waiters: VecDeque::new(),
         }
     }
 
@@ -91,6 +134,7 @@ impl CookieManager {
     }
 
     fn save(&mut self) {
+        self.config = Config::clone(&self.live_config.load());
         self.config.cookie_array = self
             .valid
             .iter()
@@ -104,8 +148,10 @@ impl CookieManager {
         });
     }
 
-    /// Try to dispatch a cookie from the valid set
-    fn dispatch(&mut self) -> Result<CookieStatus, ClewdrError> {
+    /// Try to dispatch a cookie with one of the tags and able to serve the model from the valid set
+    fn dispatch(&mut self, req: &CookieRequest) -> Result<CookieStatus, ClewdrError> {
+        let tags = &req.tags;
+        let model = req.model.as_deref();
         let mut reset_cookies = Vec::new();
         self.exhausted.retain(|cookie| {
             let reset_cookie = cookie.clone().reset();
@@ -118,16 +164,97 @@ impl CookieManager {
         });
         self.valid.extend(reset_cookies);
         self.save();
-        // randomly select a cookie from valid cookies and remove it from the set
-        let cookie = self
+        let candidates = self
             .valid
-            .pop_front()
-            .ok_or(ClewdrError::NoCookieAvailable)?;
+            .iter()
+            .positions(|c| c.has_tag(tags) && c.supports(model, &self.config))
+            .collect::<Vec<_>>();
+        let Some(mut cookie) = self
+            .config
+            .cookie_strategy
+            .selector()
+            .pick(&self.valid, &candidates)
+            .and_then(|i| self.valid.remove(i))
+        else {
+            if let Some(model) = model.filter(|_| self.valid.iter().any(|c| c.has_tag(tags))) {
+                return Err(ClewdrError::ModelUnavailable(format!(
+                    "no cookie can serve {}",
+                    model
+                )));
+            }
+            if !tags.is_empty() && !self.valid.is_empty() {
+                return Err(ClewdrError::NoTaggedCookie(tags.join(", ")));
+            }
+            return Err(self.exhausted_error());
+        };
+        cookie.last_used = Some(chrono::Utc::now().timestamp_millis());
         let instant = Instant::now();
         self.dispatched.insert(cookie.clone(), instant);
         Ok(cookie)
     }
 
+    /// Error for an empty pool, with the earliest reset time if any
+    fn exhausted_error(&self) -> ClewdrError {
+        let Some(t) = self.exhausted.iter().filter_map(|c| c.reset_time).min() else {
+            return ClewdrError::NoCookieAvailable;
+        };
+        let reset = chrono::DateTime::from_timestamp(t, 0)
+            .map(|t| t.to_rfc3339())
+            .unwrap_or(t.to_string());
+        ClewdrError::CookiesExhausted(reset)
+    }
+
+    /// Time until the earliest exhausted cookie resets
+    fn next_reset(&self) -> Duration {
+        let now = chrono::Utc::now().timestamp();
+        self.exhausted
+            .iter()
+            .filter_map(|c| c.reset_time)
+            .min()
+            .map(|t| Duration::from_secs((t - now).max(1) as u64))
+            .unwrap_or(Duration::from_secs(60))
+    }
+
+    /// Dispatch a cookie to the requester, putting it back if the requester is gone
+    fn send(&mut self, req: CookieRequest) {
+        let cookie = self.dispatch(&req);
+        if let Err(Ok(c)) = req.tx.send(cookie) {
+            error!("Failed to send cookie");
+            self.dispatched.remove(&c);
+            self.valid.push_front(c);
+        }
+    }
+
+    /// Hand cookies to requests waiting for the pool to refill
+    fn serve_waiters(&mut self) {
+        while let Some(req) = self.waiters.pop_front() {
+            if req.tx.is_closed() {
+                continue;
+            }
+            if !self.available(&req) {
+                self.waiters.push_front(req);
+                return;
+            }
+            self.send(req);
+        }
+    }
+
+    /// Check if a cookie matching the request can be dispatched
+    fn available(&self, req: &CookieRequest) -> bool {
+        self.valid
+            .iter()
+            .any(|c| c.has_tag(&req.tags) && c.supports(req.model.as_deref(), &self.config))
+            || self.has_reset()
+    }
+
+    /// Check if any exhausted cookie is due to reset
+    fn has_reset(&self) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        self.exhausted
+            .iter()
+            .any(|c| c.reset_time.is_none_or(|t| t < now))
+    }
+
     /// Collect the cookie and update the state
     fn collect(&mut self, mut cookie: CookieStatus, reason: Option<Reason>) {
         let Some(_) = self.dispatched.remove(&cookie) else {
@@ -155,6 +282,18 @@ impl CookieManager {
                 self.invalid
                     .insert(UselessCookie::new(cookie.cookie, reason));
             }
+            Reason::Suspended => {
+                error!(
+                    "{}",
+                    format!(
+                        "Account suspended, retiring cookie {} permanently",
+                        cookie.cookie.digest()
+                    )
+                    .red()
+                );
+                self.invalid
+                    .insert(UselessCookie::new(cookie.cookie, reason));
+            }
             r => {
                 self.invalid.insert(UselessCookie::new(cookie.cookie, r));
             }
@@ -179,11 +318,32 @@ impl CookieManager {
     }
 
     /// Run the cookie manager
+    /// Restarts the event loop if it panics, so cookies are not lost from rotation
+    pub async fn run(mut self) {
+        loop {
+            if let Err(e) = AssertUnwindSafe(self.serve()).catch_unwind().await {
+                let msg = e
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| e.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                error!(
+                    "{}",
+                    format!("Cookie manager crashed, restarting: {}", msg).red()
+                );
+                self.save();
+            }
+        }
+    }
+
+    /// Event loop of the cookie manager
     /// This function will run in a loop and handle the requests and returns
     /// from the channels
-    pub async fn run(mut self) {
+    async fn serve(&mut self) {
         loop {
             self.log();
+            self.serve_waiters();
+            let wake = self.next_reset();
             select! {
                 biased;
                 Some((cookie, reason)) = self.ret_rx.recv() => self.collect(cookie, reason),
@@ -205,15 +365,19 @@ impl CookieManager {
                         self.valid.push_back(cookie);
                     }
                 }
-                Some(sender) = self.req_rx.recv() => {
-                    let cookie = self.dispatch();
-                    if let Err(e) = sender.send(cookie) {
-                        error!("Failed to send cookie");
-                        if let Ok(c) = e {
-                            self.valid.push_back(c);
-                        }
+                Some(req) = self.req_rx.recv() => {
+                    let wait = self.config.exhausted_policy == ExhaustedPolicy::Wait
+                        && (!self.waiters.is_empty() || !self.available(&req));
+                    if wait {
+                        // keep the request until a cookie is returned or resets
+                        self.waiters.push_back(req);
+                    } else {
+                        self.send(req);
                     }
                 }
+                _ = sleep(wake), if !self.waiters.is_empty() => {
+                    self.serve_waiters();
+                }
             }
         }
     }
@@ -0,0 +1,74 @@
+use axum::{
+    Json,
+    extract::{Query, State},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::{info, warn};
+
+use crate::{
+    error::ClewdrError,
+    messages::{Auth, ClientRequestBody, ValidJson},
+    state::AppState,
+};
+
+/// Query parameters of the replay endpoint
+#[derive(Deserialize, Debug)]
+pub struct ReplayQuery {
+    /// Send the request to Claude.ai after transforming it
+    #[serde(default)]
+    dispatch: bool,
+    /// Transform with the OpenAI pipeline instead of the Claude one
+    #[serde(default)]
+    oai: bool,
+}
+
+/// Axum handler to replay a dumped request (e.g. `0.req.json`) through the pipeline
+pub async fn api_replay(
+    Auth(_): Auth,
+    State(mut state): State<AppState>,
+    Query(q): Query<ReplayQuery>,
+    ValidJson(mut p): ValidJson<ClientRequestBody>,
+) -> Response {
+    info!("Replaying request, dispatch: {}", q.dispatch);
+    // replay is always non-stream so the output can be returned as json
+    p.stream = false;
+    let body = if q.oai {
+        state.transform_oai(p.clone())
+    } else {
+        state.transform_anthropic(p.clone())
+    };
+    let mut result = json!({ "request_body": body });
+    if q.dispatch {
+        result["output"] = match state.replay(p).await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Replay failed: {}", e);
+                json!({ "error": e.to_string() })
+            }
+        };
+    }
+    Json(result).into_response()
+}
+
+impl AppState {
+    /// Send the request to Claude.ai with a cookie from the pool
+    async fn replay(&mut self, p: ClientRequestBody) -> Result<Value, ClewdrError> {
+        self.request_cookie().await?;
+        let res = async {
+            self.bootstrap().await?;
+            self.try_message(p, &mut None).await
+        }
+        .await;
+        if let Err(e) = self.delete_chat().await {
+            warn!("Failed to delete chat: {}", e);
+        }
+        let reason = res.as_ref().err().and_then(ClewdrError::cookie_reason);
+        self.return_cookie(reason).await;
+        let bytes = axum::body::to_bytes(res?.into_body(), usize::MAX)
+            .await
+            .map_err(|e| ClewdrError::AssetError(e.to_string()))?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+}
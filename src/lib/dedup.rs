@@ -0,0 +1,173 @@
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::{StreamExt, pin_mut, stream};
+use tokio::{spawn, sync::Notify};
+use tracing::{info, warn};
+
+use crate::{error::ClewdrError, state::AppState};
+
+/// Response of an in-flight request, shared with identical requests
+#[derive(Default)]
+struct Shared {
+    head: Option<(StatusCode, HeaderMap)>,
+    chunks: Vec<Bytes>,
+    done: bool,
+}
+
+/// In-flight request that duplicate callers attach to
+#[derive(Default)]
+struct InFlight {
+    shared: Mutex<Shared>,
+    notify: Notify,
+}
+
+impl InFlight {
+    fn update(&self, f: impl FnOnce(&mut Shared)) {
+        if let Ok(mut s) = self.shared.lock() {
+            f(&mut s);
+        }
+        self.notify.notify_waiters();
+    }
+
+    /// Wait for the response head
+    async fn head(&self) -> Option<(StatusCode, HeaderMap)> {
+        loop {
+            let notified = self.notify.notified();
+            pin_mut!(notified);
+            notified.as_mut().enable();
+            {
+                let s = self.shared.lock().ok()?;
+                if let Some(head) = s.head.clone() {
+                    return Some(head);
+                }
+                if s.done {
+                    return None;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Wait for the chunk at the given index, `None` when the body is finished
+    async fn chunk(&self, index: usize) -> Option<Bytes> {
+        loop {
+            let notified = self.notify.notified();
+            pin_mut!(notified);
+            notified.as_mut().enable();
+            {
+                let s = self.shared.lock().ok()?;
+                if let Some(chunk) = s.chunks.get(index) {
+                    return Some(chunk.clone());
+                }
+                if s.done {
+                    return None;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Build a response replaying the shared body from the start
+    async fn response(self: Arc<Self>) -> Response {
+        let Some((status, headers)) = self.head().await else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        let body = stream::unfold((self, 0), |(f, i)| async move {
+            let chunk = f.chunk(i).await?;
+            Some((Ok::<_, std::io::Error>(chunk), (f, i + 1)))
+        });
+        let mut res = Body::from_stream(body).into_response();
+        *res.status_mut() = status;
+        *res.headers_mut() = headers;
+        res
+    }
+}
+
+/// Registry of in-flight requests keyed by a hash of the API key and body
+#[derive(Clone, Default)]
+pub struct Deduplicator {
+    inflight: Arc<Mutex<HashMap<u64, Arc<InFlight>>>>,
+}
+
+impl Deduplicator {
+    /// Join an identical in-flight request, or register a new one
+    /// Returns the entry and whether the caller should dispatch it
+    fn join(&self, key: u64) -> (Arc<InFlight>, bool) {
+        let Ok(mut map) = self.inflight.lock() else {
+            return (Arc::default(), true);
+        };
+        if let Some(f) = map.get(&key) {
+            return (f.clone(), false);
+        }
+        let f = Arc::new(InFlight::default());
+        map.insert(key, f.clone());
+        (f, true)
+    }
+
+    fn remove(&self, key: u64) {
+        if let Ok(mut map) = self.inflight.lock() {
+            map.remove(&key);
+        }
+    }
+}
+
+/// Middleware attaching duplicate requests to the one already in flight
+pub async fn dedup_layer(State(s): State<AppState>, req: Request, next: Next) -> Response {
+    if !s.config.dedup_requests {
+        return next.run(req).await;
+    }
+    let (parts, body) = req.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, s.config.max_body_size).await else {
+        return ClewdrError::RequestTooLarge.into_response();
+    };
+    let mut hasher = DefaultHasher::new();
+    for name in ["x-api-key", "authorization"] {
+        parts
+            .headers
+            .get(name)
+            .map(|v| v.as_bytes())
+            .hash(&mut hasher);
+    }
+    parts.uri.path().hash(&mut hasher);
+    bytes.hash(&mut hasher);
+    let key = hasher.finish();
+
+    let (inflight, leader) = s.dedup.join(key);
+    if !leader {
+        info!("Duplicate request attached to the one in flight");
+        return inflight.response().await;
+    }
+    let req = Request::from_parts(parts, Body::from(bytes));
+    let res = next.run(req).await;
+    let (parts, body) = res.into_parts();
+    inflight.update(|s| s.head = Some((parts.status, parts.headers.clone())));
+    // pump the body in the background so every caller gets the full response
+    let pump = inflight.clone();
+    let dedup = s.dedup.clone();
+    spawn(async move {
+        let mut body = body.into_data_stream();
+        while let Some(chunk) = body.next().await {
+            match chunk {
+                Ok(chunk) => pump.update(|s| s.chunks.push(chunk)),
+                Err(e) => {
+                    warn!("Failed to read response body: {}", e);
+                    break;
+                }
+            }
+        }
+        dedup.remove(key);
+        pump.update(|s| s.done = true);
+    });
+    inflight.response().await
+}
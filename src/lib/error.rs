@@ -1,19 +1,23 @@
+use axum::{
+    Json,
+    http::{HeaderValue, header::RETRY_AFTER},
+    response::IntoResponse,
+};
 use colored::Colorize;
-use futures::{Stream, stream};
+use futures::Stream;
 use rquest::{Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
 use std::{convert::Infallible, fmt::Display};
 use tokio::sync::{mpsc::error::SendError, oneshot};
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
 use crate::{
-    config::{CookieStatus, Reason},
-    messages::non_stream_message,
-    types::message::{
-        ContentBlock, ContentBlockDelta, Message, MessageDeltaContent, MessageStartContent,
-        StreamEvent,
-    },
+    config::Reason,
+    cookie::CookieRequest,
+    messages::{non_stream_message, stream_message},
+    ratelimit::RESET_HEADER,
+    types::message::Message,
 };
 
 #[derive(thiserror::Error, Debug)]
@@ -35,9 +39,31 @@ pub enum ClewdrError {
     #[error("Tokio oneshot recv error: {0}")]
     CookieDispatchError(#[from] oneshot::error::RecvError),
     #[error("Tokio mpsc send error: {0}")]
-    CookieReqError(#[from] SendError<oneshot::Sender<Result<CookieStatus, ClewdrError>>>),
+    CookieReqError(#[from] SendError<CookieRequest>),
     #[error("No cookie available")]
     NoCookieAvailable,
+    #[error("No cookie available with tags: {0}")]
+    NoTaggedCookie(String),
+    #[error("All cookies exhausted, earliest reset at {0}")]
+    CookiesExhausted(String),
+    #[error("Output is not valid JSON: {0}")]
+    InvalidJson(String),
+    #[error("Invalid model: {0}")]
+    InvalidModel(String),
+    #[error("Model {0} requires a Pro account, but no Pro cookie is available")]
+    ModelRequiresPro(String),
+    #[error("Request body too large, the limit is set by max_body_size")]
+    RequestTooLarge,
+    #[error("Identical request sent {0} times in a row, is the client stuck in a loop?")]
+    RequestLoop(usize),
+    #[error("Server busy, retry after {0} seconds")]
+    ServerBusy(u64),
+    #[error("Rate limit exceeded, retry after {0} seconds")]
+    RateLimited(u64),
+    #[error("Too many concurrent streams, the limit of this key is {0}")]
+    TooManyStreams(usize),
+    #[error("Model is not available to this account: {0}")]
+    ModelUnavailable(String),
     #[error("Invalid Cookie, reason: {0}")]
     InvalidCookie(Reason),
     #[error("Json error: {0}")]
@@ -62,6 +88,22 @@ pub enum ClewdrError {
     PathNotFound(String),
     #[error("Invalid timestamp: {0}")]
     TimestampError(i64),
+    #[error("Image error: {0}")]
+    ImageError(#[from] image::ImageError),
+    #[error("Image too large: {0}")]
+    ImageTooLarge(String),
+    #[error("Unsupported image: {0}")]
+    UnsupportedImage(String),
+    #[error("Prompt too long: {0}")]
+    PromptTooLong(String),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Claude.ai returned an empty response")]
+    EmptyResponse,
+    #[error("Account suspended: {0}")]
+    AccountSuspended(String),
+    #[error("No first token within {0} seconds")]
+    FirstTokenTimeout(u64),
 }
 
 /// HTTP error response
@@ -121,6 +163,54 @@ impl Display for InnerHttpError {
     }
 }
 
+/// Messages of Claude.ai for suspended or disabled accounts
+const SUSPENDED_SIGNATURES: [&str; 3] = [
+    "account has been disabled",
+    "account has been suspended",
+    "organization has been disabled",
+];
+
+/// Fragments of captcha and challenge pages
+const CAPTCHA_SIGNATURES: [&str; 3] = ["captcha", "challenge-platform", "cf-chl"];
+
+/// Short reason why Claude.ai rejected the cookie, `None` if the error is not about the cookie
+fn rejection_reason(status: StatusCode, body: &str) -> Option<String> {
+    if !matches!(status.as_u16(), 401 | 403) {
+        return None;
+    }
+    let body = body.to_lowercase();
+    if CAPTCHA_SIGNATURES.iter().any(|s| body.contains(s)) {
+        return Some("captcha".to_string());
+    }
+    if status != StatusCode::UNAUTHORIZED {
+        return None;
+    }
+    let reason = if body.contains("expired") {
+        "session expired"
+    } else {
+        "invalid session"
+    };
+    Some(reason.to_string())
+}
+
+/// Error for a cookie rejected by Claude.ai
+fn rejected(status: StatusCode, reason: String) -> ClewdrError {
+    warn!(
+        "Cookie rejected by Claude.ai, status: {}, reason: {}",
+        status.as_u16().to_string().red(),
+        reason.as_str().yellow()
+    );
+    ClewdrError::InvalidCookie(Reason::Rejected(status.as_u16(), reason))
+}
+
+/// Messages of errors caused by the account tier rather than the model name
+const MODEL_UNAVAILABLE_SIGNATURES: [&str; 4] = [
+    "not available on your plan",
+    "model is not available",
+    "does not have access to",
+    "upgrade your plan",
+];
+
 /// Check response from Claude Web
 pub async fn check_res_err(res: Response) -> Result<Response, ClewdrError> {
     let status = res.status();
@@ -141,6 +231,10 @@ pub async fn check_res_err(res: Response) -> Result<Response, ClewdrError> {
         )
     })?;
     let Ok(err) = serde_json::from_str::<HttpError>(&text) else {
+        // challenge pages are HTML
+        if let Some(reason) = rejection_reason(status, &text) {
+            return Err(rejected(status, reason));
+        }
         let inner = InnerHttpError {
             message: json!("Failed to parse error response"),
             r#type: "error".to_string(),
@@ -153,6 +247,27 @@ pub async fn check_res_err(res: Response) -> Result<Response, ClewdrError> {
     };
     let err_clone = err.clone();
     let inner_error = err.error;
+    // a suspended account never recovers, unlike rate limits
+    if matches!(status.as_u16(), 401 | 403) {
+        let msg = inner_error.message.to_string().to_lowercase();
+        if SUSPENDED_SIGNATURES.iter().any(|s| msg.contains(s)) {
+            return Err(ClewdrError::AccountSuspended(
+                inner_error.message.to_string(),
+            ));
+        }
+    }
+    // the model exists, but not for this account
+    if matches!(status.as_u16(), 400 | 403 | 404) {
+        let msg = inner_error.message.to_string().to_lowercase();
+        if MODEL_UNAVAILABLE_SIGNATURES.iter().any(|s| msg.contains(s)) {
+            return Err(ClewdrError::ModelUnavailable(
+                inner_error.message.to_string(),
+            ));
+        }
+    }
+    if let Some(reason) = rejection_reason(status, &inner_error.message.to_string()) {
+        return Err(rejected(status, reason));
+    }
     // check if the error is a rate limit error
     if status == 429 {
         // get the reset time from the error message
@@ -167,56 +282,243 @@ pub async fn check_res_err(res: Response) -> Result<Response, ClewdrError> {
             return Err(ClewdrError::InvalidCookie(Reason::TooManyRequest(time)));
         }
     }
+    // check if the prompt exceeds the context window
+    if let Some(msg) = inner_error
+        .message
+        .as_str()
+        .filter(|m| m.to_lowercase().contains("prompt is too long"))
+    {
+        return Err(ClewdrError::PromptTooLong(msg.to_string()));
+    }
     Err(ClewdrError::OtherHttpError(status, err_clone))
 }
+
+/// Parse `<tokens> tokens > <limit> maximum` from a prompt too long message
+pub fn parse_prompt_too_long(msg: &str) -> Option<(usize, usize)> {
+    let (tokens, rest) = msg.split_once(" tokens > ")?;
+    let tokens = tokens.rsplit(' ').next()?.parse().ok()?;
+    let limit = rest.split(' ').next()?.parse().ok()?;
+    Some((tokens, limit))
+}
 impl ClewdrError {
     /// Convert a ClewdrError to a Stream of Claude API events
     pub fn error_stream(
         &self,
     ) -> impl Stream<Item = Result<axum::body::Bytes, Infallible>> + use<> {
-        let msg_start_content = MessageStartContent::default();
-        let msg_start_block = StreamEvent::MessageStart {
-            message: msg_start_content,
-        };
-        let content_block = ContentBlock::Text {
-            text: String::new(),
-        };
-        let content_block_start = StreamEvent::ContentBlockStart {
-            index: 0,
-            content_block,
-        };
-        let content_block_delta = ContentBlockDelta::TextDelta {
-            text: format!("ClewdR Error: {self}"),
+        stream_message(format!("ClewdR Error: {self}"))
+    }
+
+    pub fn error_body(&self) -> Message {
+        non_stream_message(self.to_string())
+    }
+
+    /// Anthropic error type matching the status code
+    pub fn error_type(&self) -> &'static str {
+        match self.status() {
+            StatusCode::BAD_REQUEST => "invalid_request_error",
+            StatusCode::UNAUTHORIZED => "authentication_error",
+            StatusCode::FORBIDDEN => "permission_error",
+            StatusCode::NOT_FOUND => "not_found_error",
+            StatusCode::PAYLOAD_TOO_LARGE => "request_too_large",
+            StatusCode::TOO_MANY_REQUESTS => "rate_limit_error",
+            StatusCode::SERVICE_UNAVAILABLE => "overloaded_error",
+            _ => "api_error",
+        }
+    }
+
+    /// Error in the Anthropic error envelope, as sent by the `error` stream event
+    pub fn error_envelope(&self) -> Value {
+        json!({
+            "type": "error",
+            "error": { "type": self.error_type(), "message": self.to_string() },
+        })
+    }
+
+    /// Check if Claude.ai rejected the UUID of a new conversation
+    pub fn is_conversation_conflict(&self) -> bool {
+        matches!(
+            self,
+            ClewdrError::OtherHttpError(
+                StatusCode::CONFLICT | StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY,
+                _
+            )
+        )
+    }
+
+    /// Check if the error means no cookie could be dispatched
+    pub fn is_no_cookie(&self) -> bool {
+        matches!(
+            self,
+            ClewdrError::NoCookieAvailable
+                | ClewdrError::NoTaggedCookie(_)
+                | ClewdrError::CookiesExhausted(_)
+        )
+    }
+
+    /// Check if the error may go away on retry, such as a network error or a 5xx response
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ClewdrError::RquestError(e) => !e.is_builder() && !e.is_decode(),
+            ClewdrError::OtherHttpError(c, _) => c.is_server_error(),
+            _ => false,
+        }
+    }
+
+    /// HTTP status code returned to the client for this error
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ClewdrError::TooManyRetries => StatusCode::TOO_MANY_REQUESTS,
+            ClewdrError::NoCookieAvailable
+            | ClewdrError::NoTaggedCookie(_)
+            | ClewdrError::ServerBusy(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ClewdrError::CookiesExhausted(_)
+            | ClewdrError::RequestLoop(_)
+            | ClewdrError::RateLimited(_)
+            | ClewdrError::TooManyStreams(_) => StatusCode::TOO_MANY_REQUESTS,
+            ClewdrError::ModelRequiresPro(_) | ClewdrError::InvalidModel(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            ClewdrError::ModelUnavailable(_) => StatusCode::FORBIDDEN,
+            ClewdrError::InvalidCookie(Reason::TooManyRequest(_))
+            | ClewdrError::InvalidCookie(Reason::Restricted(_)) => StatusCode::TOO_MANY_REQUESTS,
+            ClewdrError::InvalidCookie(_) | ClewdrError::AccountSuspended(_) => {
+                StatusCode::BAD_GATEWAY
+            }
+            ClewdrError::OtherHttpError(c, _) => *c,
+            ClewdrError::EventSourceError(_)
+            | ClewdrError::InvalidJson(_)
+            | ClewdrError::RquestError(_)
+            | ClewdrError::EmptyResponse
+            | ClewdrError::UTF8Error(_) => StatusCode::BAD_GATEWAY,
+            ClewdrError::ImageError(_)
+            | ClewdrError::UnsupportedImage(_)
+            | ClewdrError::PromptTooLong(_)
+            | ClewdrError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            ClewdrError::ImageTooLarge(_) | ClewdrError::RequestTooLarge => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            ClewdrError::FirstTokenTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ClewdrError::ZipError(_)
+            | ClewdrError::AssetError(_)
+            | ClewdrError::InvalidVersion(_)
+            | ClewdrError::ParseIntError(_)
+            | ClewdrError::UrlParseError(_)
+            | ClewdrError::CookieDispatchError(_)
+            | ClewdrError::CookieReqError(_)
+            | ClewdrError::JsonError(_)
+            | ClewdrError::TomlDeError(_)
+            | ClewdrError::TomlSeError(_)
+            | ClewdrError::RegexError(_)
+            | ClewdrError::UnexpectedNone
+            | ClewdrError::IoError(_)
+            | ClewdrError::PathNotFound(_)
+            | ClewdrError::TimestampError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Reason to return the cookie with, if the error is caused by the cookie
+    pub fn cookie_reason(&self) -> Option<Reason> {
+        match self {
+            ClewdrError::InvalidCookie(r) => Some(r.clone()),
+            ClewdrError::AccountSuspended(_) => Some(Reason::Suspended),
+            _ => None,
+        }
+    }
+
+    /// Status code and JSON body returned to the client for this error
+    pub fn status_and_body(&self) -> (StatusCode, Value) {
+        let body = match self {
+            // pass the upstream error through
+            ClewdrError::OtherHttpError(_, e) => json!(e),
+            // same envelope as the Anthropic API
+            ClewdrError::InvalidRequest(m) => json!({
+                "type": "error",
+                "error": { "type": "invalid_request_error", "message": m },
+            }),
+            // rejected before reaching Claude.ai
+            ClewdrError::ServerBusy(_)
+            | ClewdrError::RateLimited(_)
+            | ClewdrError::TooManyStreams(_) => self.error_envelope(),
+            e => json!(e.error_body()),
         };
-        let content_block_delta = StreamEvent::ContentBlockDelta {
-            index: 0,
-            delta: content_block_delta,
+        (self.status(), body)
+    }
+
+    /// Convert a ClewdrError to a stream or json response
+    pub fn error_response(&self, stream: bool) -> axum::response::Response {
+        let mut res = if !stream || matches!(self, ClewdrError::OtherHttpError(..)) {
+            let (status, body) = self.status_and_body();
+            (status, Json(body)).into_response()
+        } else {
+            let body = axum::body::Body::from_stream(self.error_stream());
+            (self.status(), body).into_response()
         };
-        let content_block_end = StreamEvent::ContentBlockStop { index: 0 };
-        let message_delta = StreamEvent::MessageDelta {
-            delta: MessageDeltaContent::default(),
-            usage: None,
+        self.apply_reset_headers(&mut res);
+        res
+    }
+
+    /// Rate limit headers of a rejected request or an exhausted pool, so clients know when to retry
+    pub fn apply_reset_headers(&self, res: &mut axum::response::Response) {
+        let headers = res.headers_mut();
+        let secs = match self {
+            ClewdrError::ServerBusy(secs) | ClewdrError::RateLimited(secs) => *secs as i64,
+            ClewdrError::CookiesExhausted(reset) => {
+                let Ok(time) = chrono::DateTime::parse_from_rfc3339(reset) else {
+                    return;
+                };
+                if let Ok(v) = HeaderValue::from_str(reset) {
+                    headers.insert(RESET_HEADER, v);
+                }
+                time.timestamp() - chrono::Utc::now().timestamp()
+            }
+            _ => return,
         };
-        let message_stop = StreamEvent::MessageStop;
-        let vec = vec![
-            msg_start_block,
-            content_block_start,
-            content_block_delta,
-            content_block_end,
-            message_delta,
-            message_stop,
-        ];
-        let vec = vec.into_iter().map(|e| {
-            let e = serde_json::to_string(&e).unwrap();
-            // SSE format
-            let e = format!("data: {e}\n\n");
-            let bytes = axum::body::Bytes::from(e);
-            Ok::<axum::body::Bytes, Infallible>(bytes)
-        });
-        stream::iter(vec)
+        headers.insert(RETRY_AFTER, HeaderValue::from(secs.max(1)));
+    }
+}
+
+impl IntoResponse for ClewdrError {
+    fn into_response(self) -> axum::response::Response {
+        self.error_response(false)
     }
+}
 
-    pub fn error_body(&self) -> Message {
-        non_stream_message(self.to_string())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_map_to_status_codes() {
+        assert_eq!(
+            ClewdrError::NoCookieAvailable.status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            ClewdrError::InvalidCookie(Reason::TooManyRequest(0)).status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            ClewdrError::InvalidCookie(Reason::Null).status(),
+            StatusCode::BAD_GATEWAY
+        );
+        assert_eq!(
+            ClewdrError::ImageTooLarge(String::new()).status(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(
+            ClewdrError::UnexpectedNone.status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[test]
+    fn rejections_use_the_error_envelope() {
+        let (status, body) = ClewdrError::RateLimited(3).status_and_body();
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body["type"], "error");
+        assert_eq!(body["error"]["type"], "rate_limit_error");
+        let res = ClewdrError::ServerBusy(0).into_response();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[RETRY_AFTER], "1");
     }
 }
@@ -0,0 +1,118 @@
+use tokio::sync::broadcast::{self, Receiver, Sender, error::RecvError};
+use tracing::{debug, warn};
+
+use crate::{config::Reason, error::ClewdrError, state::AppState};
+
+/// Events buffered for slow subscribers before they lag
+const EVENT_CAPACITY: usize = 1024;
+
+/// Why a request failed, as seen by subscribers
+#[derive(Debug, Clone)]
+pub enum Failure {
+    /// The cookie hit its rate limit
+    RateLimited(String),
+    /// The cookie is unusable, with the upstream status if known
+    InvalidCookie { reason: String, status: Option<u16> },
+    /// Any other error
+    Other(String),
+}
+
+impl From<&ClewdrError> for Failure {
+    fn from(e: &ClewdrError) -> Self {
+        match e {
+            ClewdrError::InvalidCookie(r @ Reason::TooManyRequest(_)) => {
+                Failure::RateLimited(r.to_string())
+            }
+            ClewdrError::InvalidCookie(r) => Failure::InvalidCookie {
+                reason: r.to_string(),
+                status: r.status(),
+            },
+            ClewdrError::AccountSuspended(_) => Failure::InvalidCookie {
+                reason: Reason::Suspended.to_string(),
+                status: None,
+            },
+            e => Failure::Other(e.to_string()),
+        }
+    }
+}
+
+/// Lifecycle event of a request, cookies are identified by their digest
+#[derive(Debug, Clone)]
+pub enum RequestEvent {
+    /// A request was accepted by a handler
+    Received { model: String, stream: bool },
+    /// A cookie was borrowed for the request
+    CookieSelected { cookie: String },
+    /// The prompt was sent to Claude.ai
+    UpstreamSent { conversation: String },
+    /// The first upstream event of a stream arrived
+    FirstToken,
+    /// An attempt finished successfully
+    Completed { cookie: String, latency_ms: u64 },
+    /// An attempt failed
+    Errored {
+        cookie: String,
+        failure: Failure,
+        latency_ms: u64,
+    },
+}
+
+/// Broadcast channel of request lifecycle events
+/// Publishing never blocks, events are dropped when nobody listens
+#[derive(Clone, Debug)]
+pub struct EventBus {
+    tx: Sender<RequestEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self {
+            tx: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+}
+
+impl EventBus {
+    pub fn publish(&self, event: RequestEvent) {
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> Receiver<RequestEvent> {
+        self.tx.subscribe()
+    }
+}
+
+/// Receive the next event, skipping over lagged ones
+/// Returns `None` once the bus is closed
+pub async fn next_event(rx: &mut Receiver<RequestEvent>) -> Option<RequestEvent> {
+    loop {
+        match rx.recv().await {
+            Ok(e) => return Some(e),
+            Err(RecvError::Lagged(n)) => warn!("Event subscriber lagged, {} events dropped", n),
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// Example subscriber logging every lifecycle event, enabled by `log_events`
+pub async fn run_event_log(state: AppState) {
+    if !state.config.log_events {
+        return;
+    }
+    let mut rx = state.events.subscribe();
+    while let Some(event) = next_event(&mut rx).await {
+        match event {
+            // never log cookies in clear
+            RequestEvent::CookieSelected { .. } => debug!("Event: cookie selected"),
+            RequestEvent::Completed { latency_ms, .. } => {
+                debug!("Event: completed in {} ms", latency_ms)
+            }
+            RequestEvent::Errored {
+                failure,
+                latency_ms,
+                ..
+            } => debug!("Event: errored in {} ms: {:?}", latency_ms, failure),
+            e => debug!("Event: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use std::{sync::atomic::Ordering, time::Duration};
+
+use axum::{extract::State, http::StatusCode};
+use tracing::{info, warn};
+
+use crate::{error::ClewdrError, state::AppState};
+
+/// Interval between readiness checks while not ready
+const READY_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Liveness probe, the process is up
+pub async fn api_healthz() -> StatusCode {
+    StatusCode::OK
+}
+
+/// Readiness probe, a cookie has been validated by bootstrap
+pub async fn api_readyz(State(state): State<AppState>) -> StatusCode {
+    if state.ready.load(Ordering::Relaxed) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+impl AppState {
+    /// Borrow a cookie and bootstrap it to validate the pool
+    async fn check_ready(&mut self) -> Result<(), ClewdrError> {
+        self.request_cookie().await?;
+        let res = self.bootstrap().await;
+        let reason = res.as_ref().err().and_then(ClewdrError::cookie_reason);
+        self.return_cookie(reason).await;
+        res
+    }
+}
+
+/// Validate a cookie until the application is ready to serve
+pub async fn run_readiness(state: AppState) {
+    while !state.ready.load(Ordering::Relaxed) {
+        if let Err(e) = state.clone().check_ready().await {
+            warn!("Readiness check failed: {}", e);
+            tokio::time::sleep(READY_CHECK_INTERVAL).await;
+        }
+    }
+    info!("Ready to serve requests");
+}
@@ -0,0 +1,35 @@
+use serde_json::Value;
+
+/// Instruction for OpenAI `response_format`, `None` for plain text
+pub fn json_instruction(format: &Value) -> Option<String> {
+    match format["type"].as_str()? {
+        "json_object" => Some(
+            "Respond only with a single valid JSON object. \
+            Do not wrap it in code fences or add any text before or after it."
+                .to_string(),
+        ),
+        "json_schema" => {
+            let schema = format
+                .pointer("/json_schema/schema")
+                .map(|s| serde_json::to_string_pretty(s).unwrap_or_default())
+                .unwrap_or_default();
+            Some(format!(
+                "Respond only with a single valid JSON object matching this JSON schema. \
+                Do not wrap it in code fences or add any text before or after it.\n\
+                <schema>\n{schema}\n</schema>"
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Parse the completion as JSON, tolerating surrounding whitespace and code fences
+pub fn parse_json_output(text: &str) -> Option<Value> {
+    let text = text.trim();
+    let text = text
+        .strip_prefix("```json")
+        .or(text.strip_prefix("```"))
+        .and_then(|t| t.strip_suffix("```"))
+        .unwrap_or(text);
+    serde_json::from_str(text.trim()).ok()
+}
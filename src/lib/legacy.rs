@@ -0,0 +1,184 @@
+use std::convert::Infallible;
+
+use axum::{
+    Json,
+    body::{Body, Bytes},
+    extract::State,
+    http::header::{CONTENT_LENGTH, CONTENT_TYPE},
+    response::{IntoResponse, Response},
+};
+use colored::Colorize;
+use eventsource_stream::Eventsource;
+use futures::{Stream, StreamExt};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::{info, warn};
+
+use crate::{
+    messages::{
+        Auth, ClientRequestBody, KeepChat, ModelOverride, ProxyOverride, TimezoneOverride,
+        ValidJson,
+    },
+    state::AppState,
+    types::message::{Message, Role},
+};
+
+/// Request body of the legacy Text Completions API
+#[derive(Deserialize, Debug)]
+pub struct LegacyRequest {
+    pub model: String,
+    /// Pre-assembled `\n\nHuman: ...\n\nAssistant:` prompt
+    pub prompt: String,
+    #[serde(default)]
+    pub max_tokens_to_sample: Option<u64>,
+    #[serde(default)]
+    pub stop_sequences: Vec<String>,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub top_k: Option<u64>,
+}
+
+impl LegacyRequest {
+    /// Convert to a messages request carrying the prompt untouched
+    fn into_body(self) -> ClientRequestBody {
+        ClientRequestBody {
+            max_tokens: self.max_tokens_to_sample,
+            messages: vec![Message::new_text(Role::User, self.prompt.clone())],
+            stop_sequences: self.stop_sequences,
+            model: self.model,
+            stream: self.stream,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            raw_prompt: Some(self.prompt),
+            ..Default::default()
+        }
+    }
+}
+
+/// Map a Messages API stop reason to the legacy one
+fn legacy_stop_reason(reason: &Value) -> &'static str {
+    match reason.as_str() {
+        Some("max_tokens") => "max_tokens",
+        _ => "stop_sequence",
+    }
+}
+
+/// Legacy completion object
+fn completion(text: &str, stop_reason: Value, stop: &Value, model: &str) -> Value {
+    json!({
+        "type": "completion",
+        "completion": text,
+        "stop_reason": stop_reason,
+        "stop": stop,
+        "model": model,
+    })
+}
+
+/// Transform a Messages API response body to the legacy completion object
+fn legacy_message(body: &Value, model: &str) -> Value {
+    let text = body["content"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|b| b["text"].as_str())
+        .collect::<String>();
+    let stop_reason = legacy_stop_reason(&body["stop_reason"]);
+    let mut res = completion(&text, stop_reason.into(), &body["stop_sequence"], model);
+    res["id"] = body["id"].clone();
+    res
+}
+
+/// Transform Messages API events to legacy completion events
+fn legacy_stream(body: Body, model: String) -> impl Stream<Item = Result<Bytes, Infallible>> {
+    body.into_data_stream().eventsource().filter_map(move |e| {
+        let model = model.clone();
+        async move {
+            let e = e.ok()?;
+            let data = serde_json::from_str::<Value>(&e.data).ok()?;
+            let (name, data) = match data["type"].as_str()? {
+                // thinking deltas carry no `text` and are dropped
+                "content_block_delta" => {
+                    let text = data["delta"]["text"].as_str()?;
+                    (
+                        "completion",
+                        completion(text, Value::Null, &Value::Null, &model),
+                    )
+                }
+                "message_delta" => {
+                    let delta = &data["delta"];
+                    let stop_reason = legacy_stop_reason(&delta["stop_reason"]);
+                    let data = completion("", stop_reason.into(), &delta["stop_sequence"], &model);
+                    ("completion", data)
+                }
+                "ping" => ("ping", data),
+                "error" => ("error", data),
+                _ => return None,
+            };
+            Some(Ok(Bytes::from(format!("event: {name}\ndata: {data}\n\n"))))
+        }
+    })
+}
+
+/// Axum handler for the legacy Text Completions API
+/// The prompt is sent as is, the response is converted from the Messages API
+pub async fn api_legacy_complete(
+    Auth(key): Auth,
+    model_override: ModelOverride,
+    timezone: TimezoneOverride,
+    KeepChat(keep): KeepChat,
+    ProxyOverride(proxy): ProxyOverride,
+    State(mut state): State<AppState>,
+    ValidJson(req): ValidJson<LegacyRequest>,
+) -> Response {
+    state.set_key(&key);
+    state.keep_chat = keep;
+    state.proxy = proxy;
+    let mut p = req.into_body();
+    model_override.apply(&mut p);
+    timezone.apply(&mut p);
+    let stream = p.stream;
+    match state.config.model_chain(&p.model) {
+        Ok(chain) => p.set_model_chain(chain),
+        Err(e) => {
+            warn!("{}", e);
+            return e.error_response(stream);
+        }
+    }
+    let model = p.model.clone();
+    info!(
+        "Legacy completion received, stream mode: {}, model: {}",
+        stream.to_string().green(),
+        model.as_str().green()
+    );
+    let res = state.message_with_retries(p).await;
+    if !res.status().is_success() {
+        return res;
+    }
+    let (parts, body) = res.into_parts();
+    if stream {
+        let body = Body::from_stream(legacy_stream(body, model));
+        return Response::from_parts(parts, body);
+    }
+    let body = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .ok()
+        .and_then(|b| serde_json::from_slice::<Value>(&b).ok())
+        .unwrap_or_default();
+    let mut res = Json(legacy_message(&body, &model)).into_response();
+    // keep debug and rate limit headers
+    for (name, value) in parts.headers {
+        let Some(name) = name else {
+            continue;
+        };
+        if name != CONTENT_TYPE && name != CONTENT_LENGTH {
+            res.headers_mut().insert(name, value);
+        }
+    }
+    res
+}
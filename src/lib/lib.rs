@@ -3,17 +3,45 @@ use std::sync::LazyLock;
 use clap::Parser;
 use figlet_rs::FIGfont;
 
+pub mod admin;
+pub mod api;
+pub mod audit;
+pub mod batch;
 pub mod bootstrap;
 pub mod client;
 pub mod config;
 pub mod cookie;
+pub mod debug;
+pub mod dedup;
 pub mod error;
+pub mod events;
+pub mod health;
+pub mod json_mode;
+pub mod legacy;
+pub mod loopguard;
+pub mod markdown;
 pub mod messages;
+pub mod models;
 pub mod openai;
+pub mod pretty;
+pub mod queue;
+pub mod ratelimit;
+pub mod reload;
 pub mod router;
+pub mod selection;
+pub mod session;
+pub mod shutdown;
 pub mod state;
+pub mod stats;
+pub mod stream;
+pub mod streams;
 pub mod submit;
+pub mod sweeper;
+pub mod template;
 pub mod text;
+pub mod tls;
+pub mod tokenizer;
+pub mod tools;
 pub mod types;
 pub mod update;
 pub mod utils;
@@ -0,0 +1,88 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use colored::Colorize;
+use tracing::warn;
+
+use crate::{config::Config, error::ClewdrError, messages::api_key, state::AppState};
+
+/// Keys tracked before idle ones are pruned
+const MAX_KEYS: usize = 4096;
+
+/// Recent request hashes of every API key, to catch a client resending the same request in a loop
+#[derive(Clone, Debug)]
+pub struct LoopGuard {
+    recent: Arc<Mutex<HashMap<String, VecDeque<(u64, Instant)>>>>,
+    /// Identical consecutive requests allowed, disabled when 0
+    max_repeats: usize,
+    window: Duration,
+}
+
+impl LoopGuard {
+    /// Create a new guard, disabled when `loop_max_repeats` is 0
+    pub fn new(config: &Config) -> Self {
+        Self {
+            recent: Arc::new(Mutex::new(HashMap::new())),
+            max_repeats: config.loop_max_repeats,
+            window: Duration::from_secs(config.loop_window_secs),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.max_repeats > 0
+    }
+
+    /// Record a request, returns false when it repeats the last `max_repeats` ones within the window
+    /// Rejected requests are recorded too, so a loop stays blocked until it pauses for a window
+    fn check(&self, key: &str, hash: u64) -> bool {
+        let Ok(mut recent) = self.recent.lock() else {
+            return true;
+        };
+        let now = Instant::now();
+        if recent.len() > MAX_KEYS {
+            let window = self.window;
+            recent.retain(|_, r| r.back().is_some_and(|(_, t)| now - *t < window));
+        }
+        let ring = recent.entry(key.to_string()).or_default();
+        if ring.len() > self.max_repeats {
+            ring.pop_front();
+        }
+        ring.push_back((hash, now));
+        ring.len() <= self.max_repeats
+            || !ring
+                .iter()
+                .all(|(h, t)| *h == hash && now - *t < self.window)
+    }
+}
+
+/// Middleware rejecting a request sent identically more than `loop_max_repeats` times in a row
+pub async fn loop_guard_layer(State(s): State<AppState>, req: Request, next: Next) -> Response {
+    if !s.loop_guard.enabled() {
+        return next.run(req).await;
+    }
+    let (parts, body) = req.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, s.config.max_body_size).await else {
+        return ClewdrError::RequestTooLarge.into_response();
+    };
+    let key = api_key(&parts.headers).to_string();
+    let mut hasher = DefaultHasher::new();
+    parts.uri.path().hash(&mut hasher);
+    bytes.hash(&mut hasher);
+    if !s.loop_guard.check(&key, hasher.finish()) {
+        let e = ClewdrError::RequestLoop(s.loop_guard.max_repeats + 1);
+        warn!("{}", e.to_string().red().bold());
+        return e.into_response();
+    }
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
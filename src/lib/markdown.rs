@@ -0,0 +1,191 @@
+use std::{mem, sync::LazyLock};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// Markdown formatting removed from the output
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MarkdownStrip {
+    /// Output is passed through unchanged
+    #[default]
+    Off,
+    /// Only code fence lines are removed, the code is kept
+    CodeFences,
+    /// Code fences, headings, quotes, rules, emphasis, code spans and links are removed
+    All,
+}
+
+/// Characters that may start inline formatting, text after them is held until the line ends
+const INLINE_MARKS: &[char] = &['*', '_', '`', '~', '[', '!'];
+
+/// Characters that may still turn out to be a fence or a line prefix
+const FENCE_CHARS: &str = " \t`~";
+const PREFIX_CHARS: &str = " \t`~#>-*_+";
+
+static PREFIX_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(?: {0,3}> ?)*(?: {0,3}#{1,6}(?: +|$))?").unwrap());
+static RULE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^ {0,3}(?:(?:- *){3,}|(?:\* *){3,}|(?:_ *){3,})$").unwrap());
+static CODE_SPAN_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"`([^`]+)`").unwrap());
+/// Inline formatting and its replacement, applied in order outside code spans
+static INLINE_RES: LazyLock<Vec<(Regex, &str)>> = LazyLock::new(|| {
+    [
+        (r"!\[([^\]]*)\]\([^)]*\)", "$1"),
+        (r"\[([^\]]+)\]\([^)]*\)", "$1"),
+        (r"\*\*(.+?)\*\*", "$1"),
+        (r"__(.+?)__", "$1"),
+        (r"~~(.+?)~~", "$1"),
+        (r"\*([^*\s](?:[^*]*[^*\s])?)\*", "$1"),
+        (r"(^|\W)_([^_\s](?:[^_]*[^_\s])?)_(\W|$)", "$1$2$3"),
+    ]
+    .into_iter()
+    .map(|(re, rep)| (Regex::new(re).unwrap(), rep))
+    .collect()
+});
+
+/// Remove markdown formatting from a chunked stream
+/// The start of a line is held until it can be told apart from a fence or prefix,
+/// and text after an inline mark is held until the line ends, so marks split across chunks are matched
+#[derive(Debug)]
+pub struct MarkdownStripper {
+    all: bool,
+    /// Text of the current line not emitted yet
+    buf: String,
+    /// The start of the current line was handled
+    line_open: bool,
+    /// The current line is a fence and dropped
+    skip_line: bool,
+    in_fence: bool,
+}
+
+impl MarkdownStripper {
+    pub fn new(mode: MarkdownStrip) -> Option<Self> {
+        (mode != MarkdownStrip::Off).then(|| Self {
+            all: mode == MarkdownStrip::All,
+            buf: String::new(),
+            line_open: false,
+            skip_line: false,
+            in_fence: false,
+        })
+    }
+
+    /// Feed a chunk, returning the text that is safe to emit
+    pub fn push(&mut self, text: &str) -> String {
+        self.buf.push_str(text);
+        let mut out = String::new();
+        while let Some(i) = self.buf.find('\n') {
+            let line = self.buf.drain(..=i).collect::<String>();
+            self.end_line(&line[..i], &mut out);
+        }
+        self.emit_partial(&mut out);
+        out
+    }
+
+    /// Flush the held text at the end of the stream
+    pub fn finish(&mut self) -> String {
+        let mut out = String::new();
+        if self.buf.is_empty() {
+            return out;
+        }
+        let line = mem::take(&mut self.buf);
+        self.end_line(&line, &mut out);
+        if out.ends_with('\n') {
+            out.pop();
+        }
+        out
+    }
+
+    /// Strip formatting from a complete text
+    pub fn strip(mut self, text: &str) -> String {
+        let mut out = self.push(text);
+        out.push_str(&self.finish());
+        out
+    }
+
+    fn is_fence(line: &str) -> bool {
+        let trimmed = line.trim_start();
+        line.len() - trimmed.len() <= 3
+            && (trimmed.starts_with("```") || trimmed.starts_with("~~~"))
+    }
+
+    /// Handle a complete line, without its newline
+    fn end_line(&mut self, line: &str, out: &mut String) {
+        if mem::take(&mut self.skip_line) {
+            self.line_open = false;
+            return;
+        }
+        let line = if mem::take(&mut self.line_open) {
+            line.to_string()
+        } else if Self::is_fence(line) {
+            self.in_fence = !self.in_fence;
+            return;
+        } else {
+            self.strip_prefix(line)
+        };
+        out.push_str(&self.strip_inline(&line));
+        out.push('\n');
+    }
+
+    /// Emit as much of the incomplete line as can be decided
+    fn emit_partial(&mut self, out: &mut String) {
+        if self.skip_line {
+            self.buf.clear();
+            return;
+        }
+        if !self.line_open {
+            let undecided = if self.all { PREFIX_CHARS } else { FENCE_CHARS };
+            if self.buf.chars().all(|c| undecided.contains(c)) {
+                return;
+            }
+            if Self::is_fence(&self.buf) {
+                self.in_fence = !self.in_fence;
+                self.skip_line = true;
+                self.buf.clear();
+                return;
+            }
+            self.buf = self.strip_prefix(&self.buf);
+            self.line_open = true;
+        }
+        if !self.all || self.in_fence {
+            out.push_str(&mem::take(&mut self.buf));
+            return;
+        }
+        let safe = self.buf.find(INLINE_MARKS).unwrap_or(self.buf.len());
+        out.extend(self.buf.drain(..safe));
+    }
+
+    /// Remove heading, quote and rule markers from the start of a line
+    fn strip_prefix(&self, line: &str) -> String {
+        if !self.all || self.in_fence {
+            return line.to_string();
+        }
+        if RULE_RE.is_match(line) {
+            return String::new();
+        }
+        PREFIX_RE.replace(line, "").into_owned()
+    }
+
+    /// Remove inline formatting, keeping the content of code spans as is
+    fn strip_inline(&self, line: &str) -> String {
+        if !self.all || self.in_fence {
+            return line.to_string();
+        }
+        let mut out = String::new();
+        let mut last = 0;
+        for caps in CODE_SPAN_RE.captures_iter(line) {
+            let m = caps.get(0).unwrap();
+            out.push_str(&Self::strip_emphasis(&line[last..m.start()]));
+            out.push_str(&caps[1]);
+            last = m.end();
+        }
+        out.push_str(&Self::strip_emphasis(&line[last..]));
+        out
+    }
+
+    fn strip_emphasis(text: &str) -> String {
+        INLINE_RES.iter().fold(text.to_string(), |t, (re, rep)| {
+            re.replace_all(&t, *rep).into_owned()
+        })
+    }
+}
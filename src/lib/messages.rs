@@ -1,39 +1,54 @@
-use std::{fmt::Debug, mem, sync::LazyLock};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::Debug,
+    hash::{DefaultHasher, Hash, Hasher},
+    mem,
+};
 
 use axum::{
     Json,
-    body::Body,
-    extract::{FromRequestParts, State},
-    response::{IntoResponse, Response},
+    body::Bytes,
+    extract::{FromRequest, FromRequestParts, State},
+    http::HeaderValue,
+    response::{IntoResponse, Response, Sse},
 };
 use colored::Colorize;
 use eventsource_stream::Eventsource;
-use rquest::{StatusCode, header::ACCEPT};
-use scopeguard::defer;
-use serde::{Deserialize, Serialize};
+use futures::{FutureExt, Stream, StreamExt, future::BoxFuture};
+use rquest::{
+    Proxy, StatusCode,
+    header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, HeaderMap},
+};
+use scopeguard::{ScopeGuard, defer, guard};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use serde_json::{Value, json};
 use tokio::spawn;
 use tracing::{debug, error, info, warn};
 
 use crate::{
-    client::{SUPER_CLIENT, SetupRequest},
+    api::ApiMode,
+    audit::AuditRecord,
+    client::SetupRequest,
+    config::EmptyResponsePolicy,
     error::{ClewdrError, check_res_err},
+    events::RequestEvent,
+    json_mode::json_instruction,
+    session::SessionId,
     state::AppState,
-    text::merge_sse,
-    types::message::{ContentBlock, ImageSource, Message, Role},
+    stream::{
+        ClaudeTransformer, StreamProgress, await_first_event, coalesce_events, cut_blocks_at_stops,
+        with_event_ids,
+    },
+    text::{blocks_text, merge_sse_blocks, message_text},
+    tools::parse_tool_calls,
+    types::message::{
+        ContentBlock, ContentBlockDelta, ImageSource, Message, MessageDeltaContent,
+        MessageResponse, MessageStartContent, Role, StreamEvent, Tool,
+    },
     utils::{print_out_json, print_out_text},
 };
 
-/// Exact test message send by SillyTavern
-pub static TEST_MESSAGE: LazyLock<Message> = LazyLock::new(|| {
-    Message::new_blocks(
-        Role::User,
-        vec![ContentBlock::Text {
-            text: "Hi".to_string(),
-        }],
-    )
-});
-
 /// Claude.ai attachment
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Attachment {
@@ -45,13 +60,67 @@ pub struct Attachment {
 
 impl Attachment {
     pub fn new(content: String) -> Self {
+        Self::named(content, "paste.txt".to_string())
+    }
+
+    fn named(content: String, file_name: String) -> Self {
         Attachment {
             file_size: content.len() as u64,
             extracted_content: content,
-            file_name: "paste.txt".to_string(),
+            file_name,
             file_type: "txt".to_string(),
         }
     }
+
+    /// Split content into attachments of at most `max_bytes`, preferring line breaks
+    /// A single attachment is returned when the content fits or `max_bytes` is 0
+    pub fn split(content: String, max_bytes: usize) -> Vec<Self> {
+        if max_bytes == 0 || content.len() <= max_bytes {
+            return vec![Self::new(content)];
+        }
+        let mut chunks = vec![];
+        let mut rest = content.as_str();
+        while rest.len() > max_bytes {
+            let mut end = max_bytes;
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            if end == 0 {
+                end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+            }
+            // cut after the last line break of the chunk if there is one
+            if let Some(i) = rest[..end].rfind('\n') {
+                end = i + 1;
+            }
+            chunks.push(&rest[..end]);
+            rest = &rest[end..];
+        }
+        if !rest.is_empty() {
+            chunks.push(rest);
+        }
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(i, c)| Self::named(c.to_string(), format!("paste_{}.txt", i + 1)))
+            .collect()
+    }
+
+    /// Pointer telling the model to read split attachments in order
+    pub fn pointer(attachments: &[Self]) -> Option<String> {
+        if attachments.len() < 2 {
+            return None;
+        }
+        let names = attachments
+            .iter()
+            .map(|a| a.file_name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        Some(format!(
+            "The conversation is split across {} attachments, read them in order: {}",
+            attachments.len(),
+            names
+        ))
+    }
 }
 
 /// Request body to be sent to the Claude.ai
@@ -64,20 +133,27 @@ pub struct RequestBody {
     pub model: Option<String>,
     pub rendering_mode: String,
     pub prompt: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
     pub timezone: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u64>,
     #[serde(skip)]
     pub images: Vec<ImageSource>,
 }
 
-fn max_tokens() -> u64 {
-    4096
-}
+/// Max tokens used when neither the client nor the model profile sets it
+pub const DEFAULT_MAX_TOKENS: u64 = 4096;
 
 /// Request body sent from the client
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct ClientRequestBody {
-    #[serde(default = "max_tokens")]
-    pub max_tokens: u64,
+    #[serde(default)]
+    pub max_tokens: Option<u64>,
     pub messages: Vec<Message>,
     #[serde(default)]
     pub stop_sequences: Vec<String>,
@@ -89,11 +165,170 @@ pub struct ClientRequestBody {
     #[serde(default)]
     pub system: Value,
     #[serde(default)]
-    pub temperature: f32,
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub top_k: Option<u64>,
+    #[serde(default)]
+    pub tools: Vec<Tool>,
+    #[serde(default)]
+    pub tool_choice: Option<Value>,
+    /// Deprecated OpenAI function definitions, translated to `tools`
+    #[serde(default, skip_serializing)]
+    pub functions: Vec<Tool>,
+    /// Deprecated OpenAI counterpart of `tool_choice`
+    #[serde(default, skip_serializing)]
+    pub function_call: Option<Value>,
+    /// Whether tool calls are answered in the legacy `function_call` shape
+    #[serde(skip)]
+    pub legacy_functions: bool,
+    /// OpenAI `response_format`, used for JSON mode
+    #[serde(default)]
+    pub response_format: Option<Value>,
+    /// Number of choices to generate, OpenAI only
+    #[serde(default)]
+    pub n: Option<usize>,
+    /// OpenAI style flag to return thinking as `reasoning_content`
     #[serde(default)]
-    pub top_p: f32,
+    pub include_reasoning: bool,
+    /// OpenAI token log probabilities, never available from Claude.ai
     #[serde(default)]
-    pub top_k: u64,
+    pub logprobs: Option<bool>,
+    #[serde(default)]
+    pub top_logprobs: Option<u32>,
+    #[serde(default)]
+    pub reasoning: Option<Value>,
+    #[serde(default)]
+    pub metadata: Option<Metadata>,
+    /// Timezone requested with the `x-clewdr-timezone` header
+    #[serde(skip)]
+    pub timezone: Option<String>,
+    /// Values of `{{name}}` tokens in the prompt, never forwarded
+    #[serde(default, skip_serializing)]
+    pub variables: HashMap<String, String>,
+    /// Anthropic `service_tier`, only honored by the official API
+    #[serde(default)]
+    pub service_tier: Option<String>,
+    #[serde(skip)]
+    pub betas: Vec<String>,
+    /// Models tried when the account cannot serve `model`
+    #[serde(skip)]
+    pub fallback_models: Vec<String>,
+    /// Whether `model` is a fallback rather than the requested model
+    #[serde(skip)]
+    pub substituted: bool,
+    /// Token budget forced after Claude.ai rejected the prompt as too long
+    #[serde(skip)]
+    pub trim_limit: Option<usize>,
+    /// Pre-assembled prompt of the legacy completion API, sent without transformation
+    #[serde(skip)]
+    pub raw_prompt: Option<String>,
+}
+
+impl ClientRequestBody {
+    /// Use the first model of a chain from `Config::model_chain`, keeping the rest as fallbacks
+    pub fn set_model_chain(&mut self, (mut chain, substituted): (Vec<String>, bool)) {
+        if chain.is_empty() {
+            return;
+        }
+        let model = chain.remove(0);
+        if substituted {
+            info!(
+                "Model {} unavailable, falling back to {}",
+                self.model, model
+            );
+        }
+        self.model = model;
+        self.fallback_models = chain;
+        self.substituted = substituted;
+    }
+
+    /// Translate legacy OpenAI `functions` and `function_call` to tools
+    /// Ignored when the client also sent `tools`
+    pub fn convert_functions(&mut self) {
+        if self.functions.is_empty() {
+            return;
+        }
+        let functions = mem::take(&mut self.functions);
+        let function_call = self.function_call.take();
+        if !self.tools.is_empty() {
+            warn!("Both tools and functions were sent, ignoring functions");
+            return;
+        }
+        self.tools = functions;
+        self.tool_choice = match function_call {
+            Some(Value::String(s)) => Some(json!({ "type": s })),
+            Some(Value::Object(o)) => o
+                .get("name")
+                .map(|name| json!({ "type": "tool", "name": name })),
+            _ => None,
+        };
+        self.legacy_functions = true;
+    }
+
+    /// Replace the end user id with a stable hash, so it is never stored or forwarded in clear
+    pub fn hash_user_id(&mut self) {
+        let Some(id) = self.metadata.as_mut().and_then(|m| m.user_id.as_mut()) else {
+            return;
+        };
+        let mut hasher = DefaultHasher::new();
+        id.hash(&mut hasher);
+        *id = format!("{:016x}", hasher.finish());
+    }
+
+    /// Hashed end user id from `metadata.user_id`
+    pub fn user_id(&self) -> Option<&str> {
+        self.metadata.as_ref()?.user_id.as_deref()
+    }
+
+    /// Model to report in the fallback header, if it was substituted
+    pub fn fallback(&self) -> Option<String> {
+        self.substituted.then(|| self.model.clone())
+    }
+
+    /// Check if the client enabled a beta feature, matched by prefix
+    pub fn has_beta(&self, prefix: &str) -> bool {
+        self.betas.iter().any(|b| b.starts_with(prefix))
+    }
+
+    /// Check if the client asked for JSON output
+    pub fn json_mode(&self) -> bool {
+        self.response_format
+            .as_ref()
+            .and_then(json_instruction)
+            .is_some()
+    }
+
+    /// Text of the trailing assistant message the response continues, if any
+    pub fn prefill(&self) -> Option<String> {
+        self.messages
+            .last()
+            .filter(|m| m.role == Role::Assistant)
+            .map(message_text)
+    }
+
+    /// Check if the request is a single user message matching one of the probes
+    pub fn is_probe(&self, probes: &[String]) -> bool {
+        let [msg] = self.messages.as_slice() else {
+            return false;
+        };
+        if msg.role != Role::User {
+            return false;
+        }
+        let text = message_text(msg);
+        probes.iter().any(|p| p.trim() == text.trim())
+    }
+
+    /// Check if the client asked for token log probabilities
+    pub fn wants_logprobs(&self) -> bool {
+        self.logprobs == Some(true) || self.top_logprobs.is_some_and(|n| n > 0)
+    }
+
+    /// Check if the client asked for thinking in `reasoning_content`
+    pub fn wants_reasoning(&self) -> bool {
+        self.include_reasoning || self.reasoning.as_ref().is_some_and(|r| !r.is_null())
+    }
 }
 
 /// Thinking mode in Claude API Request
@@ -103,6 +338,30 @@ pub struct Thinking {
     r#type: String,
 }
 
+/// Request metadata in Claude API Request
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Metadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+}
+
+/// API key from `x-api-key`, falling back to an `Authorization: Bearer` token
+pub fn api_key(headers: &HeaderMap) -> &str {
+    let key = headers
+        .get("x-api-key")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if !key.is_empty() {
+        return key;
+    }
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.trim())
+        .unwrap_or_default()
+}
+
 pub struct Auth(pub String);
 
 impl FromRequestParts<AppState> for Auth {
@@ -111,12 +370,8 @@ impl FromRequestParts<AppState> for Auth {
         parts: &mut axum::http::request::Parts,
         state: &AppState,
     ) -> Result<Self, Self::Rejection> {
-        let key = parts
-            .headers
-            .get("x-api-key")
-            .and_then(|v| v.to_str().ok())
-            .unwrap_or_default();
-        if !state.config.auth(key) {
+        let key = api_key(&parts.headers);
+        if !state.live_config.load().auth(key) {
             warn!("Invalid password: {}", key);
             return Err(StatusCode::UNAUTHORIZED);
         }
@@ -124,103 +379,473 @@ impl FromRequestParts<AppState> for Auth {
     }
 }
 
+/// Known values of the `anthropic-version` header
+const KNOWN_VERSIONS: [&str; 2] = ["2023-06-01", "2023-01-01"];
+
+/// `anthropic-version` and `anthropic-beta` headers sent by the client
+pub struct AnthropicHeaders {
+    pub version: Option<String>,
+    pub betas: Vec<String>,
+}
+
+impl FromRequestParts<AppState> for AnthropicHeaders {
+    type Rejection = StatusCode;
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        _: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let version = parts
+            .headers
+            .get("anthropic-version")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim().to_string());
+        if let Some(ref v) = version {
+            if !KNOWN_VERSIONS.contains(&v.as_str()) {
+                // accept unknown versions for forward compatibility
+                warn!("Unknown anthropic-version: {}", v);
+            }
+        }
+        let betas = parts
+            .headers
+            .get_all("anthropic-beta")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect::<Vec<_>>();
+        Ok(AnthropicHeaders { version, betas })
+    }
+}
+
+impl AnthropicHeaders {
+    /// Attach the beta features to the request
+    pub fn apply(self, p: &mut ClientRequestBody) {
+        if !self.betas.is_empty() {
+            debug!("Anthropic beta features: {}", self.betas.join(", "));
+        }
+        p.betas = self.betas;
+    }
+}
+
+/// Header naming the fallback model used instead of the requested one
+pub const FALLBACK_HEADER: &str = "x-clewdr-fallback-model";
+
+/// Report a model substitution in the response headers
+pub fn apply_fallback_header(res: &mut Response, fallback: Option<String>) {
+    if let Some(value) = fallback.and_then(|m| HeaderValue::from_str(&m).ok()) {
+        res.headers_mut().insert(FALLBACK_HEADER, value);
+    }
+}
+
+/// Header with the digest of the cookie serving the request
+pub const COOKIE_HEADER: &str = "x-clewdr-cookie";
+/// Header with the organization serving the request
+pub const ORG_HEADER: &str = "x-clewdr-org";
+
+impl AppState {
+    /// Identify the cookie and organization of the response, if `cookie_headers` is set
+    /// The cookie is reported as a digest, never in clear
+    pub fn apply_cookie_headers(&self, res: &mut Response) {
+        if !self.config.cookie_headers {
+            return;
+        }
+        let digest = self.cookie.as_ref().map(|c| c.cookie.digest());
+        for (name, value) in [(COOKIE_HEADER, digest), (ORG_HEADER, self.org_uuid.clone())] {
+            if let Some(value) = value.and_then(|v| HeaderValue::from_str(&v).ok()) {
+                res.headers_mut().insert(name, value);
+            }
+        }
+    }
+}
+
+/// JSON body whose deserialization errors name the offending field
+pub struct ValidJson<T>(pub T);
+
+impl<T, S> FromRequest<S> for ValidJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ClewdrError;
+    async fn from_request(req: axum::extract::Request, state: &S) -> Result<Self, Self::Rejection> {
+        let bytes = Bytes::from_request(req, state).await.map_err(|e| {
+            if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+                return ClewdrError::RequestTooLarge;
+            }
+            ClewdrError::InvalidRequest(e.body_text())
+        })?;
+        let de = &mut serde_json::Deserializer::from_slice(&bytes);
+        let value = serde_path_to_error::deserialize(de).map_err(|e| {
+            let path = e.path().to_string();
+            let inner = e.into_inner();
+            let msg = if path == "." {
+                inner.to_string()
+            } else {
+                format!("{}: {}", path, inner)
+            };
+            warn!("Invalid request body: {}", msg);
+            ClewdrError::InvalidRequest(msg)
+        })?;
+        Ok(ValidJson(value))
+    }
+}
+
+/// Header keeping the conversation of the request for inspection
+pub const KEEP_HEADER: &str = "x-clewdr-keep";
+
+/// Whether the `x-clewdr-keep` header is set
+pub struct KeepChat(pub bool);
+
+impl FromRequestParts<AppState> for KeepChat {
+    type Rejection = StatusCode;
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        _: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let keep = parts
+            .headers
+            .get(KEEP_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| matches!(v.trim(), "1" | "true"));
+        Ok(KeepChat(keep))
+    }
+}
+
+/// Header forcing the proxy of a single request, admin only
+pub const PROXY_HEADER: &str = "x-clewdr-proxy";
+
+/// Proxy forced by the `x-clewdr-proxy` header, for debugging a proxy
+pub struct ProxyOverride(pub Option<Proxy>);
+
+impl FromRequestParts<AppState> for ProxyOverride {
+    type Rejection = ClewdrError;
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(proxy) = parts
+            .headers
+            .get(PROXY_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+        else {
+            return Ok(ProxyOverride(None));
+        };
+        if !state.live_config.load().admin_auth(api_key(&parts.headers)) {
+            warn!("Proxy override requires the admin password");
+            return Err(ClewdrError::InvalidRequest(format!(
+                "{} requires the admin password",
+                PROXY_HEADER
+            )));
+        }
+        let parsed = Proxy::all(proxy).map_err(|e| {
+            warn!("Invalid proxy override {}: {}", proxy, e);
+            ClewdrError::InvalidRequest(format!("Invalid proxy {}: {}", proxy, e))
+        })?;
+        info!("Proxy overridden for this request: {}", proxy.yellow());
+        Ok(ProxyOverride(Some(parsed)))
+    }
+}
+
+/// Timezone requested with the `x-clewdr-timezone` header
+pub struct TimezoneOverride(pub Option<String>);
+
+impl FromRequestParts<AppState> for TimezoneOverride {
+    type Rejection = StatusCode;
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        _: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let tz = parts
+            .headers
+            .get("x-clewdr-timezone")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .map(|v| v.to_string());
+        Ok(TimezoneOverride(tz))
+    }
+}
+
+impl TimezoneOverride {
+    /// Attach the requested timezone to the request
+    pub fn apply(self, p: &mut ClientRequestBody) {
+        p.timezone = self.0;
+    }
+}
+
+/// Model forced by the `x-clewdr-model` header
+pub struct ModelOverride(pub Option<String>);
+
+impl FromRequestParts<AppState> for ModelOverride {
+    type Rejection = StatusCode;
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(model) = parts
+            .headers
+            .get("x-clewdr-model")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+        else {
+            return Ok(ModelOverride(None));
+        };
+        if !state
+            .live_config
+            .load()
+            .override_models
+            .iter()
+            .any(|m| m == model)
+        {
+            warn!("Model override not allowed: {}", model);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        Ok(ModelOverride(Some(model.to_string())))
+    }
+}
+
+impl ModelOverride {
+    /// Replace the model of the request if an override is present
+    pub fn apply(self, p: &mut ClientRequestBody) {
+        if let Some(model) = self.0 {
+            info!(
+                "Model overridden: {} -> {}",
+                p.model.as_str().yellow(),
+                model.as_str().green()
+            );
+            p.model = model;
+        }
+    }
+}
+
 /// Axum handler for the API messages
 pub async fn api_messages(
-    Auth(_): Auth,
-    State(state): State<AppState>,
-    Json(p): Json<ClientRequestBody>,
+    Auth(key): Auth,
+    model_override: ModelOverride,
+    timezone: TimezoneOverride,
+    anthropic_headers: AnthropicHeaders,
+    ApiMode(api_mode): ApiMode,
+    SessionId(session): SessionId,
+    KeepChat(keep): KeepChat,
+    ProxyOverride(proxy): ProxyOverride,
+    State(mut state): State<AppState>,
+    ValidJson(mut p): ValidJson<ClientRequestBody>,
 ) -> Response {
-    // Check if the request is a test message
-    if !p.stream && p.messages == vec![TEST_MESSAGE.clone()] {
-        // respond with a test message
-        return Json(non_stream_message(
-            "Claude Reverse Proxy is working, please send a real message.".to_string(),
-        ))
-        .into_response();
+    state.set_key(&key);
+    state.keep_chat = keep;
+    state.proxy = proxy;
+    p.hash_user_id();
+    model_override.apply(&mut p);
+    timezone.apply(&mut p);
+    anthropic_headers.apply(&mut p);
+    // answer client handshakes without spending a cookie
+    if p.is_probe(&state.config.probe_messages) {
+        info!("Probe message received, sending canned response");
+        return probe_response(&state.config.probe_response, p.stream);
+    }
+    match state.config.model_chain(&p.model) {
+        Ok(chain) => p.set_model_chain(chain),
+        Err(e) => {
+            warn!("{}", e);
+            return e.error_response(p.stream);
+        }
     }
-
     let stream = p.stream;
+    if let Err(e) = p.render_variables(&state.config) {
+        warn!("{}", e);
+        return e.error_response(stream);
+    }
+    state.events.publish(RequestEvent::Received {
+        model: p.model.clone(),
+        stream,
+    });
     info!(
         "Request received, stream mode: {}, messages: {}, model: {}",
         stream.to_string().green(),
         p.messages.len().to_string().green(),
         p.model.as_str().green()
     );
-    for i in 0..state.config.max_retries {
-        if i > 0 {
-            info!("Retrying request, attempt: {}", (i + 1).to_string().green());
-        }
-        let mut state = state.clone();
-        let p = p.clone();
-        let stopwatch = chrono::Utc::now();
+    if state.use_api(api_mode) {
+        return state.forward_api(&p).await.unwrap_or_else(|e| {
+            error!("Failed to forward request: {}", e);
+            e.error_response(stream)
+        });
+    }
+    if let Some(id) = session {
+        return state.session_message(&id, p).await.unwrap_or_else(|e| {
+            warn!("Session {} error: {}", id, e);
+            e.error_response(stream)
+        });
+    }
+    state.message_with_retries(p).await
+}
 
-        if let Err(e) = state.request_cookie().await {
-            return Body::from_stream(e.error_stream()).into_response();
-        }
-        let mut state_clone = state.clone();
-        defer! {
-            // ensure the cookie is returned
-            spawn(async move {
+impl AppState {
+    /// Send a message on a fresh conversation, retrying with other cookies
+    pub(crate) async fn message_with_retries(&self, p: ClientRequestBody) -> Response {
+        let stream = p.stream;
+        self.retry_attempts(
+            &p,
+            |state, body, audit, _| state.try_message(body, audit).boxed(),
+            |state, body| state.forward_api(body).boxed(),
+        )
+        .await
+        .unwrap_or_else(|e| e.error_response(stream))
+    }
+
+    /// Run `attempt` on fresh conversations, retrying with other cookies
+    /// `fallback` answers from the official API when the exhausted pool policy asks for it
+    /// The error is returned for the caller to render in its own format
+    pub(crate) async fn retry_attempts<F, G>(
+        &self,
+        p: &ClientRequestBody,
+        mut attempt: F,
+        fallback: G,
+    ) -> Result<Response, ClewdrError>
+    where
+        F: for<'a> FnMut(
+            &'a mut AppState,
+            ClientRequestBody,
+            &'a mut Option<AuditRecord>,
+            bool,
+        ) -> BoxFuture<'a, Result<Response, ClewdrError>>,
+        G: for<'a> FnOnce(
+            &'a AppState,
+            &'a ClientRequestBody,
+        ) -> BoxFuture<'a, Result<Response, ClewdrError>>,
+    {
+        let mut pro_missing = false;
+        let mut trim_limit = None;
+        let mut json_retry = self.config.json_mode_retry && p.json_mode();
+        let mut empty_retry = self.config.empty_response_policy == EmptyResponsePolicy::Retry;
+        for i in 0..self.config.max_retries {
+            if i > 0 {
+                info!("Retrying request, attempt: {}", (i + 1).to_string().green());
+            }
+            let mut state = self.clone();
+            let mut body = p.clone();
+            body.trim_limit = trim_limit;
+            state.model = Some(p.model.clone());
+            let stopwatch = chrono::Utc::now();
+
+            if let Err(e) = state.request_cookie().await {
+                if state.falls_back_to_api(&e) {
+                    warn!("No cookie available, falling back to API key");
+                    return fallback(&state, &body).await;
+                }
+                return Err(e);
+            }
+            let mut audit = AuditRecord::new(&state, &body);
+            defer! {
                 let dur = chrono::Utc::now().signed_duration_since(stopwatch);
                 info!(
                     "Request finished, elapsed time: {} seconds",
                     dur.num_seconds().to_string().green()
                 );
-                state_clone.return_cookie(None).await;
+            }
+            // return the cookie if the attempt is dropped before returning it itself
+            let unreturned = guard(state.clone(), |mut state| {
+                spawn(async move {
+                    state.return_cookie(None).await;
+                });
             });
-        }
-        // check if request is successful
-        match state.bootstrap().await.and(state.try_message(p).await) {
-            Ok(b) => {
-                if let Err(e) = state.delete_chat().await {
-                    warn!("Failed to delete chat: {}", e);
+            let abandoned = state.begin_chat();
+            // check if request is successful
+            let res = async {
+                state.bootstrap().await?;
+                state.select_model(&mut body)?;
+                attempt(&mut state, body, &mut audit, json_retry).await
+            }
+            .await;
+            ScopeGuard::into_inner(abandoned);
+            state.record_stats(&res, stopwatch);
+            // delete chat, also after an error
+            if let Err(e) = state.delete_chat().await {
+                warn!("Failed to delete chat: {}", e);
+            }
+            let e = match res {
+                Ok(b) => {
+                    // return the cookie carrying what bootstrap learned
+                    state.return_cookie(None).await;
+                    ScopeGuard::into_inner(unreturned);
+                    return Ok(b);
                 }
-                return b.into_response();
+                Err(e) => e,
+            };
+            warn!("Error: {}", e);
+            if let Some(a) = audit.as_mut() {
+                a.fail(&e);
             }
-            Err(e) => {
-                // delete chat after an error
-                if let Err(e) = state.delete_chat().await {
-                    warn!("Failed to delete chat: {}", e);
+            let reason = match e {
+                // rest the slow cookie and switch to another one
+                ClewdrError::FirstTokenTimeout(_) => Some(state.config.first_token_cooldown()),
+                ClewdrError::ModelUnavailable(_) => {
+                    // the account lacks the model, try a cookie that may have it
+                    state.mark_unavailable(&p.model);
+                    None
+                }
+                _ => e.cookie_reason(),
+            };
+            state.return_cookie(reason).await;
+            ScopeGuard::into_inner(unreturned);
+            match e {
+                ClewdrError::InvalidCookie(_)
+                | ClewdrError::AccountSuspended(_)
+                | ClewdrError::ModelUnavailable(_) => {}
+                ClewdrError::InvalidJson(_) if json_retry => {
+                    // re-request once, accepting whatever comes back
+                    json_retry = false;
                 }
-                warn!("Error: {}", e);
-                // 429 error
-                match e {
-                    ClewdrError::InvalidCookie(ref r) => {
-                        state.return_cookie(Some(r.clone())).await;
-                        continue;
-                    }
-                    ClewdrError::OtherHttpError(c, e) => {
-                        state.return_cookie(None).await;
-                        return (c, Json(e)).into_response();
-                    }
-                    _ => {
-                        state.return_cookie(None).await;
-                    }
+                ClewdrError::FirstTokenTimeout(_) => {
+                    warn!("First token deadline missed, switching cookies");
                 }
-                if stream {
-                    // stream the error as a response
-                    return Body::from_stream(e.error_stream()).into_response();
-                } else {
-                    // return the error as a response
-                    return Json(e.error_body()).into_response();
+                ClewdrError::EmptyResponse if empty_retry => {
+                    // retry once on a fresh conversation
+                    empty_retry = false;
                 }
+                ClewdrError::PromptTooLong(ref msg)
+                    if trim_limit.is_none() && state.config.context_limit(&p.model).is_some() =>
+                {
+                    // trimming is enabled, trim harder and retry once on a fresh conversation
+                    let limit = state.retry_trim_limit(p, msg);
+                    warn!(
+                        "Prompt too long, retrying with a budget of {} tokens",
+                        limit
+                    );
+                    trim_limit = Some(limit);
+                }
+                ClewdrError::PromptTooLong(ref msg) => {
+                    return Err(state.prompt_too_long(p, msg));
+                }
+                ClewdrError::ModelRequiresPro(_) => {
+                    // try another cookie, hopefully a Pro one
+                    pro_missing = true;
+                }
+                _ => return Err(e),
             }
         }
+        if pro_missing {
+            let e = ClewdrError::ModelRequiresPro(p.model.clone());
+            error!("{}", e);
+            return Err(e);
+        }
+        error!("Max retries exceeded");
+        Err(ClewdrError::TooManyRetries)
     }
-    error!("Max retries exceeded");
-    (
-        StatusCode::TOO_MANY_REQUESTS,
-        Json(ClewdrError::TooManyRetries.error_body()),
-    )
-        .into_response()
 }
 
 impl AppState {
     /// Try to send a message to the Claude API
-    async fn try_message(&mut self, p: ClientRequestBody) -> Result<Response, ClewdrError> {
+    pub(crate) async fn try_message(
+        &mut self,
+        p: ClientRequestBody,
+        audit: &mut Option<AuditRecord>,
+    ) -> Result<Response, ClewdrError> {
         print_out_json(&p, "0.req.json");
-        let stream = p.stream;
-        let proxy = self.config.rquest_proxy.clone();
         let Some(org_uuid) = self.org_uuid.clone() else {
             return Ok(Json(non_stream_message(
                 "No organization found, please check your cookie.".to_string(),
@@ -229,33 +854,28 @@ impl AppState {
         };
 
         // Create a new conversation
-        let new_uuid = uuid::Uuid::new_v4().to_string();
-        self.conv_uuid = Some(new_uuid.to_string());
-        let endpoint = format!(
-            "{}/api/organizations/{}/chat_conversations",
-            self.config.endpoint(),
-            org_uuid
-        );
-        let mut body = json!({
-            "uuid": new_uuid,
-            "name":""
-        });
-
-        // enable thinking mode
-        if p.thinking.is_some() && self.is_pro() {
-            body["paprika_mode"] = "extended".into();
-            body["model"] = p.model.clone().into();
-        }
-        let api_res = SUPER_CLIENT
-            .post(endpoint)
-            .json(&body)
-            .setup_request("", self.header_cookie(), proxy.clone())
-            .send()
+        let thinking = p.thinking.is_some() && self.is_pro();
+        let new_uuid = self
+            .create_conversation(&org_uuid, &p.model, thinking)
             .await?;
-        self.update_cookie_from_res(&api_res);
-        debug!("New conversation created: {}", new_uuid);
+        self.send_message(p, audit, &org_uuid, &new_uuid).await
+    }
 
-        check_res_err(api_res).await?;
+    /// Send a message to an existing conversation
+    pub(crate) async fn send_message(
+        &mut self,
+        p: ClientRequestBody,
+        audit: &mut Option<AuditRecord>,
+        org_uuid: &str,
+        new_uuid: &str,
+    ) -> Result<Response, ClewdrError> {
+        let stream = p.stream;
+        let model = p.model.clone();
+        let fallback = p.fallback();
+        let has_tools = !p.tools.is_empty();
+        let stop_sequences = p.stop_sequences.clone();
+        let prefill = self.config.prefill_trimmer(p.prefill().as_deref());
+        let proxy = self.proxy();
 
         // generate the request body
         // check if the request is empty
@@ -270,39 +890,141 @@ impl AppState {
         let images = mem::take(&mut body.images);
 
         // upload images
-        let files = self.upload_images(images).await;
-        body.files = files;
+        let mut uploaded = self.upload_images(images).await?;
+        body.files = mem::take(&mut uploaded.files);
 
         // send the request
         print_out_json(&body, "4.req.json");
-        let endpoint = format!(
-            "{}/api/organizations/{}/chat_conversations/{}/completion",
-            self.config.endpoint(),
-            org_uuid,
-            new_uuid
-        );
-
-        let api_res = SUPER_CLIENT
-            .post(endpoint)
-            .json(&body)
-            .setup_request(new_uuid, self.header_cookie(), proxy)
-            .header_append(ACCEPT, "text/event-stream")
-            .send()
+        let cookie = self.header_cookie();
+        let api_res = self
+            .send_upstream(|client, endpoint| {
+                client
+                    .post(format!(
+                        "{}/api/organizations/{}/chat_conversations/{}/completion",
+                        endpoint, org_uuid, new_uuid
+                    ))
+                    .json(&body)
+                    .setup_request(new_uuid, &cookie, proxy.clone())
+                    .header_append(ACCEPT, "text/event-stream")
+            })
             .await?;
+        self.events.publish(RequestEvent::UpstreamSent {
+            conversation: new_uuid.to_string(),
+        });
         self.update_cookie_from_res(&api_res);
         let api_res = check_res_err(api_res).await?;
+        let limits = self.rate_limit_headers(api_res.headers());
 
         // if not streaming, return the response
-        if !stream {
+        let mut res = if !stream {
             let stream = api_res.bytes_stream().eventsource();
-            let text = merge_sse(stream).await;
+            let mut blocks = merge_sse_blocks(stream).await;
+            let text = blocks_text(&blocks);
             print_out_text(&text, "non_stream.txt");
-            return Ok(Json(non_stream_message(text)).into_response());
-        }
-
-        // stream the response
-        let input_stream = api_res.bytes_stream();
-        Ok(Body::from_stream(input_stream).into_response())
+            if let Some(a) = audit.as_mut() {
+                a.push(&text);
+            }
+            if let Some(ContentBlock::Text { text }) = blocks
+                .iter_mut()
+                .find(|b| matches!(b, ContentBlock::Text { .. }))
+            {
+                if let Some(p) = prefill {
+                    *text = p.strip(text);
+                }
+            }
+            if self.config.trim_stop_sequences {
+                blocks = cut_blocks_at_stops(blocks, &stop_sequences);
+            }
+            for b in blocks.iter_mut() {
+                if let (ContentBlock::Text { text }, Some(m)) =
+                    (b, self.config.markdown_stripper(has_tools))
+                {
+                    *text = m.strip(text);
+                }
+            }
+            let text = blocks_text(&blocks);
+            if text.trim().is_empty() {
+                let text = self.config.check_empty(text)?;
+                if !text.is_empty() {
+                    blocks = vec![ContentBlock::Text { text }];
+                }
+            }
+            if has_tools {
+                // tool calls are written as tagged text, split them out of each text block
+                blocks = blocks
+                    .into_iter()
+                    .flat_map(|b| match b {
+                        ContentBlock::Text { text } => parse_tool_calls(&text),
+                        b => vec![b],
+                    })
+                    .collect();
+            }
+            Json(MessageResponse::new(
+                model,
+                Message::new_blocks(Role::Assistant, blocks),
+            ))
+            .into_response()
+        } else {
+            // stream the response
+            let mut audit = audit.take();
+            let mut progress = StreamProgress::new(self.config.stream_progress_secs);
+            // switch cookies while nothing has been sent if the first token is late
+            let events = await_first_event(
+                Box::pin(api_res.bytes_stream().eventsource()),
+                self.config.first_token_timeout_secs,
+            )
+            .await?;
+            let mut first = Some(self.events.clone());
+            let input_stream = events.inspect(move |e| {
+                let Ok(e) = e else {
+                    return;
+                };
+                if let Some(bus) = first.take() {
+                    bus.publish(RequestEvent::FirstToken);
+                }
+                if let Some(a) = audit.as_mut() {
+                    a.push_event(&e.data);
+                }
+                if let Some(p) = progress.as_mut() {
+                    p.push(&e.data);
+                }
+            });
+            let input_stream = coalesce_events(
+                input_stream,
+                self.config.coalesce_ms,
+                self.config.coalesce_chars,
+            );
+            let trans = ClaudeTransformer::new(model)
+                .with_stop_sequences(stop_sequences)
+                .with_stop_trimming(self.config.trim_stop_sequences)
+                .with_turn_stops(self.config.default_stop_sequences())
+                .with_leading(self.config.leading_trimmer())
+                .with_prefill(prefill)
+                .with_markdown(self.config.markdown_stripper(has_tools))
+                .with_empty_response(
+                    self.config.empty_response_policy,
+                    &self.config.empty_response_placeholder,
+                )
+                .with_thinking_tags(
+                    self.config.thinking_tags,
+                    &self.config.thinking_open_tag,
+                    &self.config.thinking_close_tag,
+                );
+            let sse = Sse::new(with_event_ids(
+                trans.transform_stream(input_stream),
+                self.config.sse_event_ids,
+                self.config.sse_retry_ms,
+            ));
+            match self.config.keep_alive() {
+                Some(k) => sse.keep_alive(k).into_response(),
+                None => sse.into_response(),
+            }
+        };
+        uploaded.apply_header(&mut res);
+        apply_fallback_header(&mut res, fallback);
+        res.headers_mut().extend(limits);
+        self.apply_cookie_headers(&mut res);
+        Ok(res)
     }
 }
 
@@ -310,3 +1032,95 @@ impl AppState {
 pub fn non_stream_message(str: String) -> Message {
     Message::new_blocks(Role::Assistant, vec![ContentBlock::Text { text: str }])
 }
+
+/// Transform a string to the Claude API events of a complete message
+pub fn stream_message(text: String) -> impl Stream<Item = Result<Bytes, Infallible>> {
+    let events = [
+        StreamEvent::MessageStart {
+            message: MessageStartContent::default(),
+        },
+        StreamEvent::ContentBlockStart {
+            index: 0,
+            content_block: ContentBlock::Text {
+                text: String::new(),
+            },
+        },
+        StreamEvent::ContentBlockDelta {
+            index: 0,
+            delta: ContentBlockDelta::TextDelta { text },
+        },
+        StreamEvent::ContentBlockStop { index: 0 },
+        StreamEvent::MessageDelta {
+            delta: MessageDeltaContent::default(),
+            usage: None,
+        },
+        StreamEvent::MessageStop,
+    ];
+    let events = events.into_iter().map(|e| {
+        let e = serde_json::to_value(&e).unwrap_or_default();
+        let name = e["type"].as_str().unwrap_or("message").to_string();
+        // SSE format, named like the Anthropic events
+        Ok(Bytes::from(format!("event: {name}\ndata: {e}\n\n")))
+    });
+    futures::stream::iter(events)
+}
+
+/// Canned response to a client probe, in the requested mode
+fn probe_response(text: &str, stream: bool) -> Response {
+    if !stream {
+        return Json(non_stream_message(text.to_string())).into_response();
+    }
+    (
+        [(CONTENT_TYPE, "text/event-stream")],
+        axum::body::Body::from_stream(stream_message(text.to_string())),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn api_key_falls_back_to_bearer_token() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(api_key(&headers), "");
+        headers.insert(AUTHORIZATION, "Bearer  secret ".parse().unwrap());
+        assert_eq!(api_key(&headers), "secret");
+        headers.insert("x-api-key", "key".parse().unwrap());
+        assert_eq!(api_key(&headers), "key");
+    }
+
+    #[test]
+    fn legacy_functions_become_tools() {
+        let request = |tools: Value| {
+            serde_json::from_value::<ClientRequestBody>(json!({
+                "model": "claude-test",
+                "messages": [{ "role": "user", "content": "Weather?" }],
+                "tools": tools,
+                "functions": [{
+                    "name": "get_weather",
+                    "parameters": { "type": "object" },
+                }],
+                "function_call": { "name": "get_weather" },
+            }))
+            .unwrap()
+        };
+        let mut body = request(json!([]));
+        body.convert_functions();
+        assert!(body.legacy_functions);
+        assert_eq!(body.tools[0].name, "get_weather");
+        assert_eq!(body.tools[0].input_schema, json!({ "type": "object" }));
+        assert_eq!(
+            body.tool_choice,
+            Some(json!({ "type": "tool", "name": "get_weather" }))
+        );
+        // explicit tools win over the deprecated functions
+        let tool = json!([{ "name": "search", "input_schema": {} }]);
+        let mut body = request(tool);
+        body.convert_functions();
+        assert!(!body.legacy_functions);
+        assert_eq!(body.tools.len(), 1);
+        assert_eq!(body.tools[0].name, "search");
+    }
+}
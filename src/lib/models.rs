@@ -0,0 +1,146 @@
+use std::{
+    sync::{LazyLock, RwLock},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    Json,
+    extract::State,
+    response::{IntoResponse, Response},
+};
+use serde_json::{Value, json};
+use tracing::{info, warn};
+
+use crate::{
+    admin::AdminAuth,
+    client::SetupRequest,
+    config::Config,
+    error::{ClewdrError, check_res_err},
+    state::AppState,
+    utils::print_out_json,
+};
+
+/// Models of the account from the last successful refresh
+struct ModelCache {
+    models: Vec<String>,
+    fetched_at: Instant,
+}
+
+static MODEL_CACHE: LazyLock<RwLock<Option<ModelCache>>> = LazyLock::new(Default::default);
+
+impl Config {
+    /// Known models, the refreshed upstream list while it is fresh,
+    /// `known_models` from the config otherwise
+    pub fn current_models(&self) -> Vec<String> {
+        let ttl = Duration::from_secs(self.model_cache_ttl_secs);
+        MODEL_CACHE
+            .read()
+            .ok()
+            .and_then(|c| {
+                c.as_ref()
+                    .filter(|c| c.fetched_at.elapsed() <= ttl)
+                    .map(|c| c.models.clone())
+            })
+            .unwrap_or_else(|| self.known_models.clone())
+    }
+}
+
+/// Collect model names from every `models` array of the response
+fn collect_models(value: &Value, models: &mut Vec<String>) {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map {
+                match v.as_array() {
+                    Some(list) if k == "models" => models.extend(
+                        list.iter()
+                            .filter_map(|m| m["model"].as_str().or(m.as_str()))
+                            .map(|m| m.to_string()),
+                    ),
+                    _ => collect_models(v, models),
+                }
+            }
+        }
+        Value::Array(list) => list.iter().for_each(|v| collect_models(v, models)),
+        _ => {}
+    }
+}
+
+impl AppState {
+    /// Fetch the models available to the account of the bootstrapped cookie
+    pub(crate) async fn account_models(&mut self) -> Result<Vec<String>, ClewdrError> {
+        let org_uuid = self.org_uuid.clone().ok_or(ClewdrError::UnexpectedNone)?;
+        let cookie = self.header_cookie();
+        let proxy = self.proxy();
+        let res = self
+            .send_upstream(|client, endpoint| {
+                client
+                    .get(format!("{}/api/bootstrap/{}/app_start", endpoint, org_uuid))
+                    .setup_request("", &cookie, proxy.clone())
+            })
+            .await?;
+        self.update_cookie_from_res(&res);
+        let res = check_res_err(res).await?;
+        let json = res.json::<Value>().await?;
+        print_out_json(&json, "app_start.json");
+        let mut models = vec![];
+        collect_models(&json, &mut models);
+        models.sort();
+        models.dedup();
+        if models.is_empty() {
+            return Err(ClewdrError::UnexpectedNone);
+        }
+        Ok(models)
+    }
+
+    /// Borrow a cookie and fetch the models available to its account
+    async fn fetch_models(&mut self) -> Result<Vec<String>, ClewdrError> {
+        self.request_cookie().await?;
+        let res = async {
+            self.bootstrap().await?;
+            self.account_models().await
+        }
+        .await;
+        let reason = res.as_ref().err().and_then(ClewdrError::cookie_reason);
+        self.return_cookie(reason).await;
+        res
+    }
+
+    /// Refresh the cached model list, keeping the previous list on failure
+    pub async fn refresh_models(&self) -> Result<Vec<String>, ClewdrError> {
+        let models = self.clone().fetch_models().await?;
+        info!("Refreshed model list, {} models available", models.len());
+        if let Ok(mut cache) = MODEL_CACHE.write() {
+            *cache = Some(ModelCache {
+                models: models.clone(),
+                fetched_at: Instant::now(),
+            });
+        }
+        Ok(models)
+    }
+}
+
+/// Periodically refresh the model list from the upstream account
+pub async fn run_model_refresh(state: AppState) {
+    let secs = state.config.model_refresh_secs;
+    if secs == 0 {
+        return;
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(secs));
+    loop {
+        interval.tick().await;
+        if let Err(e) = state.refresh_models().await {
+            warn!("Failed to refresh model list: {}", e);
+        }
+    }
+}
+
+/// Admin handler refreshing the model list now
+pub async fn api_refresh_models(AdminAuth: AdminAuth, State(s): State<AppState>) -> Response {
+    match s.refresh_models().await {
+        Ok(models) => Json(json!({ "models": models })).into_response(),
+        Err(e) => {
+            warn!("Failed to refresh model list: {}", e);
+            e.into_response()
+        }
+    }
+}
@@ -8,35 +8,85 @@ use axum::{
 use axum_auth::AuthBearer;
 use colored::Colorize;
 use eventsource_stream::Eventsource;
+use futures::{FutureExt, StreamExt};
 use rquest::{StatusCode, header::ACCEPT};
-use scopeguard::defer;
-use serde_json::json;
-use tokio::spawn;
-use tracing::{debug, error, info, warn};
+use serde_json::{Value, json};
+use tokio::task::JoinSet;
+use tracing::{error, info, warn};
 
 use crate::{
-    client::{SUPER_CLIENT, SetupRequest},
+    audit::AuditRecord,
+    client::SetupRequest,
+    config::LogprobsPolicy,
     error::{ClewdrError, check_res_err},
-    messages::ClientRequestBody,
-    openai::stream::{ClewdrTransformer, NonStreamEventData},
+    events::RequestEvent,
+    json_mode::parse_json_output,
+    messages::{
+        ClientRequestBody, KeepChat, ModelOverride, ProxyOverride, TimezoneOverride, ValidJson,
+        apply_fallback_header,
+    },
+    openai::stream::{ClewdrTransformer, NonStreamEventData, text_events},
     state::AppState,
-    text::merge_sse,
+    stream::{StreamProgress, await_first_event, coalesce_events, with_event_ids},
+    text::{merge_sse, merge_sse_blocks},
+    tools::parse_tool_calls,
     utils::{print_out_json, print_out_text},
 };
 
 /// Axum handler for the API messages
 pub async fn api_completion(
     AuthBearer(token): AuthBearer,
+    model_override: ModelOverride,
+    timezone: TimezoneOverride,
+    KeepChat(keep): KeepChat,
+    ProxyOverride(proxy): ProxyOverride,
     State(mut state): State<AppState>,
-    Json(p): Json<ClientRequestBody>,
+    ValidJson(mut p): ValidJson<ClientRequestBody>,
 ) -> Response {
     if !state.config.auth(&token) {
         return (StatusCode::UNAUTHORIZED, Json("Unauthorized".to_string())).into_response();
     }
-    // TODO: Check if the request is a test message
-
+    state.set_key(&token);
+    state.keep_chat = keep;
+    state.proxy = proxy;
+    p.hash_user_id();
+    model_override.apply(&mut p);
+    timezone.apply(&mut p);
+    if state.config.legacy_functions {
+        p.convert_functions();
+    }
+    // answer client handshakes without spending a cookie
+    if p.is_probe(&state.config.probe_messages) {
+        info!("Probe message received, sending canned response");
+        let text = state.config.probe_response.clone();
+        if p.stream {
+            return Sse::new(text_events(text)).into_response();
+        }
+        return Json(NonStreamEventData::new(text)).into_response();
+    }
+    if p.wants_logprobs() && state.config.logprobs_policy == LogprobsPolicy::Reject {
+        let e = ClewdrError::InvalidRequest(
+            "logprobs are not supported, Claude.ai does not expose token probabilities".to_string(),
+        );
+        warn!("{}", e);
+        return error_response(&e);
+    }
+    match state.config.model_chain(&p.model) {
+        Ok(chain) => p.set_model_chain(chain),
+        Err(e) => {
+            warn!("{}", e);
+            return error_response(&e);
+        }
+    }
     let stream = p.stream;
-    let stopwatch = chrono::Utc::now();
+    if let Err(e) = p.render_variables(&state.config) {
+        warn!("{}", e);
+        return error_response(&e);
+    }
+    state.events.publish(RequestEvent::Received {
+        model: p.model.clone(),
+        stream,
+    });
     info!(
         "Request received, stream mode: {}, messages: {}, model: {}",
         stream.to_string().green(),
@@ -44,103 +94,153 @@ pub async fn api_completion(
         p.model.to_string().green()
     );
 
-    for i in 0..state.config.max_retries {
-        let p = p.clone();
-        if i > 0 {
-            info!("Retrying request, attempt: {}", (i + 1).to_string().green());
+    let n = p.n.unwrap_or(1).clamp(1, state.config.max_n.max(1));
+    if n > 1 {
+        if !stream {
+            return fan_out(state, p, n).await;
         }
-        if let Err(e) = state.request_cookie().await {
-            return Json(json!(
-                {
-                    "error": {
-                        "message": e.to_string(),
-                        "type": "invalid_request_error",
-                        "param": null,
-                        "code": 500
-                    }
+        warn!("Multiple choices are not supported when streaming, generating one");
+    }
+    complete(state, p)
+        .await
+        .unwrap_or_else(|e| error_response(&e))
+}
+
+/// OpenAI style error response, with the status and retry headers of the error
+fn error_response(e: &ClewdrError) -> Response {
+    let status = e.status();
+    let param = matches!(
+        e,
+        ClewdrError::ModelRequiresPro(_) | ClewdrError::InvalidModel(_)
+    )
+    .then_some("model");
+    let mut res = (
+        status,
+        Json(json!(
+            {
+                "error": {
+                    "message": e.to_string(),
+                    "type": e.error_type(),
+                    "param": param,
+                    "code": status.as_u16()
                 }
-            ))
-            .into_response();
-        }
-        let mut state_clone = state.clone();
-        defer! {
-            // ensure the cookie is returned
-            spawn(async move {
-                let dur = chrono::Utc::now().signed_duration_since(stopwatch);
-                info!(
-                    "Request finished, elapsed time: {} seconds",
-                    dur.num_seconds().to_string().green()
-                );
-                state_clone.return_cookie(None).await;
-            });
+            }
+        )),
+    )
+        .into_response();
+    e.apply_reset_headers(&mut res);
+    res
+}
+
+/// Generate `n` completions in separate conversations and merge the choices
+/// Branches run in parallel, falling back to sequential when cookies run out
+async fn fan_out(state: AppState, p: ClientRequestBody, n: usize) -> Response {
+    info!("Generating {} choices", n.to_string().green());
+    let mut set = JoinSet::new();
+    let mut queued = n;
+    let mut parallel = n;
+    let mut choices = vec![];
+    let mut model = Value::Null;
+    let mut failed = None;
+    while queued > 0 || !set.is_empty() {
+        while queued > 0 && set.len() < parallel {
+            set.spawn(complete(state.clone(), p.clone()));
+            queued -= 1;
         }
-        // check if request is successful
-        match state.bootstrap().await.and(state.try_completion(p).await) {
-            Ok(b) => {
-                if let Err(e) = state.delete_chat().await {
-                    warn!("Failed to delete chat: {}", e);
-                }
-                return b.into_response();
+        let Some(res) = set.join_next().await else {
+            break;
+        };
+        let res = match res {
+            Ok(Ok(res)) => res,
+            Ok(Err(e)) if e.is_no_cookie() && !set.is_empty() => {
+                // wait for a running branch to free its cookie
+                queued += 1;
+                parallel = set.len();
+                continue;
+            }
+            Ok(Err(e)) => {
+                warn!("Failed to generate choice: {}", e);
+                queued = 0;
+                failed.get_or_insert(error_response(&e));
+                continue;
             }
             Err(e) => {
-                // delete chat after an error
-                if let Err(e) = state.delete_chat().await {
-                    warn!("Failed to delete chat: {}", e);
-                }
-                warn!("Error: {}", e);
-                // 429 error
-                match e {
-                    ClewdrError::InvalidCookie(ref r) => {
-                        state.return_cookie(Some(r.clone())).await;
-                        continue;
-                    }
-                    ClewdrError::OtherHttpError(c, e) => {
-                        state.return_cookie(None).await;
-                        return (c, Json(e)).into_response();
-                    }
-                    _ => {
-                        state.return_cookie(None).await;
-                    }
-                }
-
-                // return the error as a response
-                return Json(json! {
-                    {
-                        "error": {
-                            "message": e.to_string(),
-                            "type": "invalid_request_error",
-                            "param": null,
-                            "code": 500
-                        }
-                    }
-                })
-                .into_response();
+                error!("Choice task failed: {}", e);
+                continue;
             }
+        };
+        if !res.status().is_success() {
+            failed.get_or_insert(res);
+            continue;
+        }
+        let Ok(bytes) = axum::body::to_bytes(res.into_body(), usize::MAX).await else {
+            continue;
+        };
+        let Ok(mut data) = serde_json::from_slice::<Value>(&bytes) else {
+            continue;
+        };
+        let Some(mut choice) = data.pointer_mut("/choices/0").map(Value::take) else {
+            failed.get_or_insert(Json(data).into_response());
+            continue;
+        };
+        choice["index"] = choices.len().into();
+        choices.push(choice);
+        if model.is_null() {
+            model = data["model"].take();
         }
     }
-    error!("Max retries exceeded");
-    (
-        StatusCode::INTERNAL_SERVER_ERROR,
-        Json(json! {
-            {
-                "error": {
-                    "message": "Max retries exceeded",
-                    "type": "invalid_request_error",
-                    "param": null,
-                    "code": 500
-                }
-            }
-        }),
-    )
-        .into_response()
+    if choices.is_empty() {
+        return failed.unwrap_or_else(|| error_response(&ClewdrError::TooManyRetries));
+    }
+    Json(json!({ "model": model, "choices": choices })).into_response()
+}
+
+/// Generate a single completion with retries
+/// Errors are returned for the caller to render, fan out waits on them when no cookie is free
+async fn complete(state: AppState, p: ClientRequestBody) -> Result<Response, ClewdrError> {
+    state
+        .retry_attempts(
+            &p,
+            |state, body, audit, json_retry| state.try_completion(body, audit, json_retry).boxed(),
+            |state, body| state.forward_completion(body).boxed(),
+        )
+        .await
 }
 
 impl AppState {
+    /// Answer from the official API in the OpenAI format, used when cookies are exhausted
+    async fn forward_completion(&self, p: &ClientRequestBody) -> Result<Response, ClewdrError> {
+        let mut body = p.clone();
+        body.stream = true;
+        let res = check_res_err(self.send_api(&body).await?).await?;
+        let events = res.bytes_stream().eventsource();
+        if !p.stream {
+            let data = NonStreamEventData::from_blocks(merge_sse_blocks(events).await)
+                .with_model(p.model.clone());
+            return Ok(Json(data).into_response());
+        }
+        Ok(Sse::new(ClewdrTransformer::new().transform_stream(events)).into_response())
+    }
+
     /// Try to send a message to the Claude API
-    async fn try_completion(&mut self, mut p: ClientRequestBody) -> Result<Response, ClewdrError> {
+    async fn try_completion(
+        &mut self,
+        mut p: ClientRequestBody,
+        audit: &mut Option<AuditRecord>,
+        validate_json: bool,
+    ) -> Result<Response, ClewdrError> {
         print_out_json(&p, "0.req.json");
+        let fallback = p.fallback();
+        let model = p.model.clone();
         let stream = p.stream;
-        let proxy = self.config.rquest_proxy.clone();
+        let has_tools = !p.tools.is_empty();
+        let legacy_functions = p.legacy_functions;
+        let json_mode = p.json_mode();
+        let prefill = self.config.prefill_trimmer(p.prefill().as_deref());
+        let reasoning = p.wants_reasoning();
+        // only reached with logprobs when the policy answers them with null
+        let null_logprobs = p.wants_logprobs();
+        let proxy = self.proxy();
         let Some(org_uuid) = self.org_uuid.clone() else {
             return Ok(Json(json!(
                 {
@@ -156,33 +256,11 @@ impl AppState {
         };
 
         // Create a new conversation
-        let new_uuid = uuid::Uuid::new_v4().to_string();
-        self.conv_uuid = Some(new_uuid.to_string());
-        let endpoint = format!(
-            "{}/api/organizations/{}/chat_conversations",
-            self.config.endpoint(),
-            org_uuid
-        );
-        let mut body = json!({
-            "uuid": new_uuid,
-            "name":""
-        });
-        // enable thinking mode
-        if p.model.contains("-thinking") && self.is_pro() {
-            body["paprika_mode"] = "extended".into();
-            body["model"] = p.model.clone().into();
-        }
-        p.model = p.model.trim_end_matches("-thinking").to_string();
-
-        let api_res = SUPER_CLIENT
-            .post(endpoint)
-            .json(&body)
-            .setup_request("", self.header_cookie(), proxy.clone())
-            .send()
+        let thinking = p.model.contains("-thinking") && self.is_pro();
+        let new_uuid = self
+            .create_conversation(&org_uuid, &p.model, thinking)
             .await?;
-        debug!("New conversation created: {}", new_uuid);
-        self.update_cookie_from_res(&api_res);
-        check_res_err(api_res).await?;
+        p.model = p.model.trim_end_matches("-thinking").to_string();
 
         // generate the request body
         // check if the request is empty
@@ -204,39 +282,145 @@ impl AppState {
         let images = mem::take(&mut body.images);
 
         // upload images
-        let files = self.upload_images(images).await;
-        body.files = files;
+        let mut uploaded = self.upload_images(images).await?;
+        body.files = mem::take(&mut uploaded.files);
 
         // send the request
         print_out_json(&body, "4.req.json");
-        let endpoint = format!(
-            "{}/api/organizations/{}/chat_conversations/{}/completion",
-            self.config.endpoint(),
-            org_uuid,
-            new_uuid
-        );
-
-        let api_res = SUPER_CLIENT
-            .post(endpoint)
-            .json(&body)
-            .setup_request("", self.header_cookie(), proxy)
-            .header_append(ACCEPT, "text/event-stream")
-            .send()
+        let cookie = self.header_cookie();
+        let api_res = self
+            .send_upstream(|client, endpoint| {
+                client
+                    .post(format!(
+                        "{}/api/organizations/{}/chat_conversations/{}/completion",
+                        endpoint, org_uuid, new_uuid
+                    ))
+                    .json(&body)
+                    .setup_request("", &cookie, proxy.clone())
+                    .header_append(ACCEPT, "text/event-stream")
+            })
             .await?;
+        self.events.publish(RequestEvent::UpstreamSent {
+            conversation: new_uuid.to_string(),
+        });
         self.update_cookie_from_res(&api_res);
         let api_res = check_res_err(api_res).await?;
+        let limits = self.rate_limit_headers(api_res.headers());
 
-        if !stream {
+        let mut res = if !stream {
             let stream = api_res.bytes_stream().eventsource();
             let text = merge_sse(stream).await;
             print_out_text(&text, "non_stream.txt");
-            return Ok(Json(NonStreamEventData::new(text)).into_response());
-        }
-        // stream the response
-        let input_stream = api_res.bytes_stream().eventsource();
-        let trans = ClewdrTransformer::new();
-        let output = trans.transform_stream(input_stream);
+            if let Some(a) = audit.as_mut() {
+                a.push(&text);
+            }
+            let text = match prefill {
+                Some(p) => p.strip(&text),
+                None => text,
+            };
+            let text = match self.config.tag_stripper() {
+                Some(s) => s.strip(&text),
+                None => text,
+            };
+            let text = match self.config.markdown_stripper(has_tools) {
+                Some(m) => m.strip(&text),
+                None => text,
+            };
+            let text = self.config.check_empty(text)?;
+            if json_mode && parse_json_output(&text).is_none() {
+                if validate_json {
+                    return Err(ClewdrError::InvalidJson(text));
+                }
+                warn!("Output is not valid JSON");
+            }
+            let data = if has_tools {
+                NonStreamEventData::from_blocks(parse_tool_calls(&text))
+            } else {
+                NonStreamEventData::new(text)
+            };
+            let data = if reasoning {
+                data.split_reasoning()
+            } else {
+                data
+            };
+            let data = data
+                .with_legacy_functions(legacy_functions)
+                .with_null_logprobs(null_logprobs)
+                .with_model(model);
+            Json(data).into_response()
+        } else {
+            // stream the response
+            let mut audit = audit.take();
+            let mut progress = StreamProgress::new(self.config.stream_progress_secs);
+            // switch cookies while nothing has been sent if the first token is late
+            let events = await_first_event(
+                Box::pin(api_res.bytes_stream().eventsource()),
+                self.config.first_token_timeout_secs,
+            )
+            .await?;
+            let mut first = Some(self.events.clone());
+            let input_stream = events.inspect(move |e| {
+                let Ok(e) = e else {
+                    return;
+                };
+                if let Some(bus) = first.take() {
+                    bus.publish(RequestEvent::FirstToken);
+                }
+                if let Some(a) = audit.as_mut() {
+                    a.push_event(&e.data);
+                }
+                if let Some(p) = progress.as_mut() {
+                    p.push(&e.data);
+                }
+            });
+            let input_stream = coalesce_events(
+                input_stream,
+                self.config.coalesce_ms,
+                self.config.coalesce_chars,
+            );
+            let trans = ClewdrTransformer::new()
+                .with_tools(has_tools)
+                .with_legacy_functions(legacy_functions)
+                .with_reasoning(reasoning)
+                .with_null_logprobs(null_logprobs)
+                .with_strip(self.config.tag_stripper())
+                .with_markdown(self.config.markdown_stripper(has_tools))
+                .with_leading(self.config.leading_trimmer())
+                .with_prefill(prefill)
+                .with_json(json_mode);
+            let output = with_event_ids(
+                trans.transform_stream(input_stream),
+                self.config.sse_event_ids,
+                self.config.sse_retry_ms,
+            );
+            let sse = Sse::new(output);
+            match self.config.keep_alive() {
+                Some(k) => sse.keep_alive(k).into_response(),
+                None => sse.into_response(),
+            }
+        };
+        uploaded.apply_header(&mut res);
+        apply_fallback_header(&mut res, fallback);
+        res.headers_mut().extend(limits);
+        self.apply_cookie_headers(&mut res);
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rquest::header::RETRY_AFTER;
+
+    use super::*;
 
-        Ok(Sse::new(output).into_response())
+    #[test]
+    fn errors_keep_their_status() {
+        let res = error_response(&ClewdrError::RateLimited(5));
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()[RETRY_AFTER], "5");
+        let res = error_response(&ClewdrError::NoCookieAvailable);
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let res = error_response(&ClewdrError::ModelRequiresPro("claude-opus-4".to_string()));
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
     }
 }
@@ -1,4 +1,7 @@
 mod completion;
+mod models;
 mod stream;
 
 pub use completion::api_completion;
+pub use models::api_models;
+pub use stream::{LeadingTrimmer, PrefillTrimmer, TagStripper};
@@ -0,0 +1,22 @@
+use axum::{Json, extract::State};
+use serde_json::{Value, json};
+
+use crate::{messages::Auth, state::AppState};
+
+/// List the models exposed to clients
+pub async fn api_models(_: Auth, State(state): State<AppState>) -> Json<Value> {
+    let data = state
+        .config
+        .listed_models()
+        .into_iter()
+        .map(|id| {
+            json!({
+                "id": id,
+                "object": "model",
+                "created": 0,
+                "owned_by": "anthropic",
+            })
+        })
+        .collect::<Vec<_>>();
+    Json(json!({ "object": "list", "data": data }))
+}
@@ -1,17 +1,224 @@
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::{
+    convert::Infallible,
+    mem,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use axum::response::sse::Event;
 use eventsource_stream::EventStreamError;
 use futures::pin_mut;
-use serde_json::Value;
+use regex::Regex;
+use serde_json::{Value, json};
 use tokio_stream::{Stream, StreamExt};
+use tracing::warn;
 use transform_stream::{AsyncTryStream, Yielder};
 
-use crate::error::ClewdrError;
+use crate::{
+    error::ClewdrError,
+    json_mode::parse_json_output,
+    markdown::MarkdownStripper,
+    tools::{TOOL_USE_END, TOOL_USE_START, parse_tool_calls},
+    types::message::ContentBlock,
+};
 
 #[derive(Debug)]
 pub struct ClewdrTransformer {
     in_thinking: AtomicBool,
+    tools: bool,
+    reasoning: bool,
+    pending: String,
+    tool_index: usize,
+    legacy_functions: bool,
+    strip: Option<TagStripper>,
+    markdown: Option<MarkdownStripper>,
+    leading: Option<LeadingTrimmer>,
+    prefill: Option<PrefillTrimmer>,
+    json: Option<String>,
+    null_logprobs: bool,
+}
+
+/// Remove text between an open and a close tag from a chunked stream
+/// Text that may be the start of a tag is held back until it can be decided
+#[derive(Debug)]
+pub struct TagStripper {
+    open: String,
+    close: String,
+    buf: String,
+    inside: bool,
+}
+
+impl TagStripper {
+    pub fn new(open: &str, close: &str) -> Self {
+        Self {
+            open: open.to_string(),
+            close: close.to_string(),
+            buf: String::new(),
+            inside: false,
+        }
+    }
+
+    /// Length of the longest suffix of `buf` that is a proper prefix of `tag`
+    pub(crate) fn partial(buf: &str, tag: &str) -> usize {
+        (1..tag.len())
+            .rev()
+            .filter(|k| tag.is_char_boundary(*k))
+            .find(|k| buf.ends_with(&tag[..*k]))
+            .unwrap_or_default()
+    }
+
+    /// Feed a chunk, returning the text that is safe to emit
+    pub fn push(&mut self, text: &str) -> String {
+        self.buf.push_str(text);
+        let mut out = String::new();
+        loop {
+            let tag = if self.inside { &self.close } else { &self.open };
+            if let Some(i) = self.buf.find(tag.as_str()) {
+                let end = i + tag.len();
+                if !self.inside {
+                    out.push_str(&self.buf[..i]);
+                }
+                self.buf.drain(..end);
+                self.inside = !self.inside;
+                continue;
+            }
+            let split = self.buf.len() - Self::partial(&self.buf, tag);
+            let head = self.buf.drain(..split).collect::<String>();
+            if !self.inside {
+                out.push_str(&head);
+            }
+            return out;
+        }
+    }
+
+    /// Flush held back text at the end of the stream, an unclosed region is dropped
+    pub fn finish(&mut self) -> String {
+        if self.inside {
+            self.buf.clear();
+            return String::new();
+        }
+        mem::take(&mut self.buf)
+    }
+
+    /// Strip all tagged regions from a complete text
+    pub fn strip(mut self, text: &str) -> String {
+        let mut out = self.push(text);
+        out.push_str(&self.finish());
+        out
+    }
+}
+
+/// Length of the response held back before the prefix is matched
+const LEADING_HOLD: usize = 32;
+
+/// Remove a pattern from the start of a chunked response
+/// The first chunks are held back until enough text arrived to match the prefix
+#[derive(Debug)]
+pub struct LeadingTrimmer {
+    re: Regex,
+    buf: String,
+    done: bool,
+}
+
+impl LeadingTrimmer {
+    pub fn new(re: Regex) -> Self {
+        Self {
+            re,
+            buf: String::new(),
+            done: false,
+        }
+    }
+
+    /// Feed a chunk, returning the text that is safe to emit
+    pub fn push(&mut self, text: &str) -> String {
+        if self.done {
+            return text.to_string();
+        }
+        self.buf.push_str(text);
+        if self.buf.trim_start().len() < LEADING_HOLD {
+            return String::new();
+        }
+        self.finish()
+    }
+
+    /// Strip the prefix from the held text and pass everything after it through
+    pub fn finish(&mut self) -> String {
+        if self.done {
+            return String::new();
+        }
+        self.done = true;
+        let buf = mem::take(&mut self.buf);
+        match self.re.find(&buf) {
+            Some(m) if m.start() == 0 => buf[m.end()..].to_string(),
+            _ => buf,
+        }
+    }
+}
+
+/// Longest tail of the prefill compared against the start of the response
+const PREFILL_HOLD: usize = 256;
+
+/// Remove the start of a chunked response that repeats the end of the prefill
+/// The first chunks are held back until as much text arrived as the compared tail
+#[derive(Debug)]
+pub struct PrefillTrimmer {
+    tail: String,
+    min_overlap: usize,
+    buf: String,
+    done: bool,
+}
+
+impl PrefillTrimmer {
+    pub fn new(prefill: &str, min_overlap: usize) -> Self {
+        let prefill = prefill.trim_end();
+        let mut start = prefill.len().saturating_sub(PREFILL_HOLD);
+        while !prefill.is_char_boundary(start) {
+            start += 1;
+        }
+        Self {
+            tail: prefill[start..].to_string(),
+            min_overlap: min_overlap.max(1),
+            buf: String::new(),
+            done: false,
+        }
+    }
+
+    /// Feed a chunk, returning the text that is safe to emit
+    pub fn push(&mut self, text: &str) -> String {
+        if self.done {
+            return text.to_string();
+        }
+        self.buf.push_str(text);
+        if self.buf.trim_start().len() < self.tail.len() {
+            return String::new();
+        }
+        self.finish()
+    }
+
+    /// Strip the longest overlap with the prefill from the held text and pass everything after it through
+    pub fn finish(&mut self) -> String {
+        if self.done {
+            return String::new();
+        }
+        self.done = true;
+        let buf = mem::take(&mut self.buf);
+        let start = buf.len() - buf.trim_start().len();
+        let text = &buf[start..];
+        let overlap = (self.min_overlap..=text.len().min(self.tail.len()))
+            .rev()
+            .filter(|&n| text.is_char_boundary(n))
+            .find(|&n| self.tail.ends_with(&text[..n]));
+        match overlap {
+            Some(n) => text[n..].to_string(),
+            None => buf,
+        }
+    }
+
+    /// Strip the overlap from a complete response
+    pub fn strip(mut self, text: &str) -> String {
+        let mut out = self.push(text);
+        out.push_str(&self.finish());
+        out
+    }
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
@@ -23,55 +230,342 @@ impl StreamEventData {
     fn new(content: String) -> Self {
         Self {
             choices: vec![StreamEventDelta {
-                delta: EventContent { content },
+                delta: EventContent {
+                    content,
+                    ..Default::default()
+                },
+                logprobs: None,
             }],
         }
     }
+
+    /// Report `logprobs: null` in every choice
+    fn with_null_logprobs(mut self, on: bool) -> Self {
+        if on {
+            for choice in self.choices.iter_mut() {
+                choice.logprobs = Some(Value::Null);
+            }
+        }
+        self
+    }
+}
+
+/// Events of a complete streamed response with the given content
+pub fn text_events(content: String) -> impl Stream<Item = Result<Event, Infallible>> {
+    let data = StreamEventData::new(content);
+    tokio_stream::iter([
+        Ok(Event::default().json_data(data).unwrap()),
+        Ok(Event::default().data("[DONE]")),
+    ])
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct NonStreamEventData {
+    /// Model that served the request, after aliases and fallbacks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
     choices: Vec<NonStreamEventMessage>,
 }
 
 impl NonStreamEventData {
     pub fn new(content: String) -> Self {
         Self {
+            model: None,
             choices: vec![NonStreamEventMessage {
-                message: EventContent { content },
+                message: EventContent {
+                    content,
+                    ..Default::default()
+                },
+                logprobs: None,
             }],
         }
     }
+
+    /// Build the response from parsed content blocks
+    pub fn from_blocks(blocks: Vec<ContentBlock>) -> Self {
+        let mut content = String::new();
+        let mut tool_calls = vec![];
+        for block in blocks {
+            match block {
+                ContentBlock::Text { text } => content.push_str(&text),
+                ContentBlock::ToolUse { id, name, input } => {
+                    tool_calls.push(ToolCall::new(tool_calls.len(), id, name, input));
+                }
+                _ => {}
+            }
+        }
+        Self {
+            model: None,
+            choices: vec![NonStreamEventMessage {
+                message: EventContent {
+                    content,
+                    tool_calls,
+                    ..Default::default()
+                },
+                logprobs: None,
+            }],
+        }
+    }
+
+    /// Report `logprobs: null` in every choice
+    pub fn with_null_logprobs(mut self, on: bool) -> Self {
+        if on {
+            for choice in self.choices.iter_mut() {
+                choice.logprobs = Some(Value::Null);
+            }
+        }
+        self
+    }
+
+    /// Report the model that served the request
+    pub fn with_model(mut self, model: String) -> Self {
+        self.model = Some(model);
+        self
+    }
+}
+
+impl NonStreamEventData {
+    /// Answer with the legacy `function_call`, which holds only the first call
+    pub fn with_legacy_functions(mut self, on: bool) -> Self {
+        if !on {
+            return self;
+        }
+        for choice in self.choices.iter_mut() {
+            let msg = &mut choice.message;
+            let mut calls = mem::take(&mut msg.tool_calls).into_iter();
+            msg.function_call = calls.next().map(|c| c.function);
+            if calls.next().is_some() {
+                warn!("Legacy functions allow one call, dropping the rest");
+            }
+        }
+        self
+    }
+
+    /// Move the leading `<thinking>` section of the content to `reasoning_content`
+    pub fn split_reasoning(mut self) -> Self {
+        for choice in self.choices.iter_mut() {
+            let msg = &mut choice.message;
+            let trimmed = msg.content.trim_start();
+            let Some(rest) = trimmed.strip_prefix("<thinking>") else {
+                continue;
+            };
+            let Some((thinking, content)) = rest.split_once("</thinking>") else {
+                continue;
+            };
+            msg.reasoning_content = Some(thinking.trim().to_string());
+            msg.content = content.trim_start().to_string();
+        }
+        self
+    }
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 struct StreamEventDelta {
     delta: EventContent,
+    /// `null` when the client asked for logprobs, omitted otherwise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    logprobs: Option<Value>,
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 struct NonStreamEventMessage {
     message: EventContent,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    logprobs: Option<Value>,
 }
 
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
 struct EventContent {
     content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reasoning_content: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    function_call: Option<FunctionCall>,
+}
+
+/// Tool call in OpenAI format
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct ToolCall {
+    index: usize,
+    id: String,
+    r#type: String,
+    function: FunctionCall,
+}
+
+impl ToolCall {
+    fn new(index: usize, id: String, name: String, input: Value) -> Self {
+        Self {
+            index,
+            id,
+            r#type: "function".to_string(),
+            function: FunctionCall {
+                name,
+                arguments: input.to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct FunctionCall {
+    name: String,
+    arguments: String,
 }
 
 impl ClewdrTransformer {
     pub fn new() -> Self {
         Self {
             in_thinking: AtomicBool::new(false),
+            tools: false,
+            reasoning: false,
+            pending: String::new(),
+            tool_index: 0,
+            legacy_functions: false,
+            strip: None,
+            markdown: None,
+            leading: None,
+            prefill: None,
+            json: None,
+            null_logprobs: false,
         }
     }
 
+    /// Strip a prefix from the start of the output
+    pub fn with_leading(mut self, leading: Option<LeadingTrimmer>) -> Self {
+        self.leading = leading;
+        self
+    }
+
+    /// Strip the repeated end of the prefill from the start of the output
+    pub fn with_prefill(mut self, prefill: Option<PrefillTrimmer>) -> Self {
+        self.prefill = prefill;
+        self
+    }
+
+    /// Report `logprobs: null` in text events
+    pub fn with_null_logprobs(mut self, null_logprobs: bool) -> Self {
+        self.null_logprobs = null_logprobs;
+        self
+    }
+
+    /// Validate the accumulated output as JSON at the end of the stream
+    pub fn with_json(mut self, json: bool) -> Self {
+        self.json = json.then(String::new);
+        self
+    }
+
+    /// Strip text between the tags from the output
+    pub fn with_strip(mut self, strip: Option<TagStripper>) -> Self {
+        self.strip = strip;
+        self
+    }
+
+    /// Strip markdown formatting from the output
+    pub fn with_markdown(mut self, markdown: Option<MarkdownStripper>) -> Self {
+        self.markdown = markdown;
+        self
+    }
+
+    /// Parse tool calls out of the completion
+    pub fn with_tools(mut self, tools: bool) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    /// Send tool calls in the legacy `function_call` shape
+    pub fn with_legacy_functions(mut self, legacy: bool) -> Self {
+        self.legacy_functions = legacy;
+        self
+    }
+
+    /// Send thinking as `reasoning_content` instead of inline tags
+    pub fn with_reasoning(mut self, reasoning: bool) -> Self {
+        self.reasoning = reasoning;
+        self
+    }
+
+    fn build_reasoning(&self, thinking: &str) -> Event {
+        let data = json!({ "choices": [{ "delta": { "reasoning_content": thinking } }] });
+        Event::default().json_data(data).unwrap()
+    }
+
     fn build(&self, selection: &str) -> Event {
         let event = Event::default();
-        let data = StreamEventData::new(selection.to_string());
+        let data =
+            StreamEventData::new(selection.to_string()).with_null_logprobs(self.null_logprobs);
         event.json_data(data).unwrap()
     }
 
+    fn build_tool_call(&mut self, id: String, name: String, input: Value) -> Event {
+        let call = ToolCall::new(self.tool_index, id, name, input);
+        self.tool_index += 1;
+        let data = if self.legacy_functions {
+            json!({ "choices": [{ "delta": { "function_call": call.function } }] })
+        } else {
+            json!({ "choices": [{ "delta": { "tool_calls": [call] } }] })
+        };
+        Event::default().json_data(data).unwrap()
+    }
+
+    /// Emit completion text, holding back tool calls until they are complete
+    async fn emit_text(&mut self, text: &str, y: &mut Yielder<Result<Event, ClewdrError>>) {
+        if let Some(json) = self.json.as_mut() {
+            json.push_str(text);
+        }
+        if !self.tools {
+            let event = self.build(text);
+            y.yield_ok(event).await;
+            return;
+        }
+        self.pending.push_str(text);
+        loop {
+            let Some(start) = self.pending.find(TOOL_USE_START) else {
+                // keep a possible partial tag at the end
+                let keep = (1..TOOL_USE_START.len())
+                    .rev()
+                    .find(|k| self.pending.ends_with(&TOOL_USE_START[..*k]))
+                    .unwrap_or_default();
+                let split = self.pending.len() - keep;
+                let out = self.pending.drain(..split).collect::<String>();
+                if !out.is_empty() {
+                    let event = self.build(&out);
+                    y.yield_ok(event).await;
+                }
+                return;
+            };
+            if start > 0 {
+                let out = self.pending.drain(..start).collect::<String>();
+                let event = self.build(&out);
+                y.yield_ok(event).await;
+            }
+            let Some(end) = self.pending.find(TOOL_USE_END) else {
+                return;
+            };
+            let block = self
+                .pending
+                .drain(..end + TOOL_USE_END.len())
+                .collect::<String>();
+            for b in parse_tool_calls(&block) {
+                let event = match b {
+                    ContentBlock::ToolUse { .. }
+                        if self.legacy_functions && self.tool_index > 0 =>
+                    {
+                        warn!("Legacy functions allow one call, dropping the rest");
+                        continue;
+                    }
+                    ContentBlock::ToolUse { id, name, input } => {
+                        self.build_tool_call(id, name, input)
+                    }
+                    ContentBlock::Text { text } => self.build(&text),
+                    _ => continue,
+                };
+                y.yield_ok(event).await;
+            }
+        }
+    }
+
     async fn parse_buf(&mut self, buf: &str, y: &mut Yielder<Result<Event, ClewdrError>>) {
         if buf.is_empty() {
             return;
@@ -81,13 +575,19 @@ impl ClewdrTransformer {
         };
         if let Some("thinking") = parsed["content_block"]["type"].as_str() {
             self.in_thinking.store(true, Ordering::SeqCst);
-            let event = self.build("<thinking>");
-            y.yield_ok(event).await;
+            if !self.reasoning {
+                let event = self.build("<thinking>");
+                y.yield_ok(event).await;
+            }
             return;
         }
         if self.in_thinking.load(Ordering::SeqCst) {
             if let Some(thinking) = parsed["delta"]["thinking"].as_str() {
-                let event = self.build(thinking);
+                let event = if self.reasoning {
+                    self.build_reasoning(thinking)
+                } else {
+                    self.build(thinking)
+                };
                 y.yield_ok(event).await;
                 return;
             }
@@ -103,11 +603,43 @@ impl ClewdrTransformer {
         };
         if self.in_thinking.load(Ordering::SeqCst) {
             self.in_thinking.store(false, Ordering::SeqCst);
-            let event = self.build("</thinking>");
-            y.yield_ok(event).await;
+            if !self.reasoning {
+                let event = self.build("</thinking>");
+                y.yield_ok(event).await;
+            }
+        }
+        let completion = match self.leading.as_mut() {
+            Some(l) => l.push(completion),
+            None => completion.to_string(),
+        };
+        let completion = match self.prefill.as_mut() {
+            Some(p) => p.push(&completion),
+            None => completion,
+        };
+        self.emit_stripped(&completion, y).await;
+    }
+
+    /// Emit completion text after removing stripped regions and markdown
+    async fn emit_stripped(&mut self, text: &str, y: &mut Yielder<Result<Event, ClewdrError>>) {
+        if text.is_empty() {
+            return;
+        }
+        let text = match self.strip.as_mut() {
+            Some(s) => s.push(text),
+            None => text.to_string(),
+        };
+        self.emit_markdown(&text, y).await;
+    }
+
+    /// Emit completion text after removing markdown
+    async fn emit_markdown(&mut self, text: &str, y: &mut Yielder<Result<Event, ClewdrError>>) {
+        let text = match self.markdown.as_mut() {
+            Some(m) => m.push(text),
+            None => text.to_string(),
+        };
+        if !text.is_empty() {
+            self.emit_text(&text, y).await;
         }
-        let event = self.build(completion);
-        y.yield_ok(event).await;
     }
 
     async fn transform(
@@ -121,6 +653,43 @@ impl ClewdrTransformer {
 
     async fn flush(&mut self, y: &mut Yielder<Result<Event, ClewdrError>>) {
         // Flush logic
+        let mut rest = self
+            .leading
+            .as_mut()
+            .map(|l| l.finish())
+            .unwrap_or_default();
+        if let Some(p) = self.prefill.as_mut() {
+            rest = p.push(&rest);
+            rest.push_str(&p.finish());
+        }
+        self.emit_stripped(&rest, y).await;
+        if let Some(text) = self
+            .strip
+            .as_mut()
+            .map(|s| s.finish())
+            .filter(|t| !t.is_empty())
+        {
+            self.emit_markdown(&text, y).await;
+        }
+        if let Some(text) = self
+            .markdown
+            .as_mut()
+            .map(|m| m.finish())
+            .filter(|t| !t.is_empty())
+        {
+            self.emit_text(&text, y).await;
+        }
+        if !self.pending.is_empty() {
+            let event = self.build(&mem::take(&mut self.pending));
+            y.yield_ok(event).await;
+        }
+        if self
+            .json
+            .take()
+            .is_some_and(|j| parse_json_output(&j).is_none())
+        {
+            warn!("Streamed output is not valid JSON");
+        }
         let event = Event::default();
         y.yield_ok(event.data("[DONE]")).await;
     }
@@ -147,7 +716,15 @@ impl ClewdrTransformer {
                         self.transform(event, &mut y).await;
                     }
                     Err(e) => {
-                        y.yield_err(e.into()).await;
+                        // end the stream cleanly instead of dropping the connection
+                        let e = ClewdrError::from(e);
+                        warn!("Stream error: {}", e);
+                        let data = json!({
+                            "error": { "message": e.to_string(), "type": e.error_type() },
+                        });
+                        y.yield_ok(Event::default().json_data(data).unwrap()).await;
+                        y.yield_ok(Event::default().data("[DONE]")).await;
+                        return Ok(());
                     }
                 }
             }
@@ -156,3 +733,37 @@ impl ClewdrTransformer {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_prefill_is_stripped() {
+        let trimmer = || PrefillTrimmer::new("Once upon a time", 3);
+        assert_eq!(trimmer().strip("a time, there was"), ", there was");
+        assert_eq!(trimmer().strip("Hello"), "Hello");
+        // overlaps shorter than the minimum are kept
+        assert_eq!(PrefillTrimmer::new("abc de", 3).strip("de fg"), "de fg");
+    }
+
+    #[test]
+    fn chunks_are_held_until_the_overlap_is_known() {
+        let mut trimmer = PrefillTrimmer::new("Once upon a time", 3);
+        assert_eq!(trimmer.push("a ti"), "");
+        assert_eq!(trimmer.push("me, there was a king"), ", there was a king");
+        assert_eq!(trimmer.push("!"), "!");
+        assert_eq!(trimmer.finish(), "");
+    }
+
+    #[test]
+    fn completion_reports_the_served_model() {
+        let data = NonStreamEventData::new("Hi".to_string());
+        assert!(serde_json::to_value(&data).unwrap().get("model").is_none());
+        let data = data.with_model("claude-served".to_string());
+        assert_eq!(
+            serde_json::to_value(&data).unwrap()["model"],
+            "claude-served"
+        );
+    }
+}
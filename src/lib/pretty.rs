@@ -0,0 +1,52 @@
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::header::{CONTENT_LENGTH, CONTENT_TYPE},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use rquest::StatusCode;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::state::AppState;
+
+/// Check if the query string asks for pretty-printed output
+fn pretty_query(query: Option<&str>) -> bool {
+    query
+        .into_iter()
+        .flat_map(|q| q.split('&'))
+        .any(|p| matches!(p, "pretty" | "pretty=true" | "pretty=1"))
+}
+
+/// Middleware re-serializing JSON responses with indentation
+/// Enabled by `pretty_json` or `?pretty=true`, otherwise bodies pass through untouched
+pub async fn pretty_json_layer(State(s): State<AppState>, req: Request, next: Next) -> Response {
+    let pretty = s.config.pretty_json || pretty_query(req.uri().query());
+    let res = next.run(req).await;
+    let is_json = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    if !pretty || !is_json {
+        return res;
+    }
+    let (mut parts, body) = res.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, usize::MAX).await else {
+        return StatusCode::BAD_GATEWAY.into_response();
+    };
+    let pretty =
+        serde_json::from_slice::<Value>(&bytes).and_then(|v| serde_json::to_vec_pretty(&v));
+    let body = match pretty {
+        Ok(pretty) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            Body::from(pretty)
+        }
+        Err(e) => {
+            warn!("Response is not valid JSON, sending it as is: {}", e);
+            Body::from(bytes)
+        }
+    };
+    Response::from_parts(parts, body)
+}
@@ -0,0 +1,95 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use colored::Colorize;
+use futures::StreamExt;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+use crate::{config::Config, error::ClewdrError, state::AppState};
+
+/// Bounded FIFO queue of requests waiting for a free slot
+#[derive(Clone, Debug)]
+pub struct RequestQueue {
+    semaphore: Arc<Semaphore>,
+    /// Slots of the semaphore, follows `max_concurrent_requests` across reloads
+    slots: Arc<AtomicUsize>,
+    waiting: Arc<AtomicUsize>,
+}
+
+impl RequestQueue {
+    /// Create a new queue, unlimited when `max_concurrent_requests` is 0
+    pub fn new(config: &Config) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(config.max_concurrent_requests)),
+            slots: Arc::new(AtomicUsize::new(config.max_concurrent_requests)),
+            waiting: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Wait for a free slot without a timeout, `None` when unlimited
+    pub async fn acquire(&self, config: &Config) -> Option<OwnedSemaphorePermit> {
+        if config.max_concurrent_requests == 0 {
+            return None;
+        }
+        self.semaphore.clone().acquire_owned().await.ok()
+    }
+
+    /// Resize to a reloaded `max_concurrent_requests`
+    /// Slots taken away are retired as the requests holding them end
+    pub fn resize(&self, slots: usize) {
+        let old = self.slots.swap(slots, Ordering::SeqCst);
+        if slots > old {
+            self.semaphore.add_permits(slots - old);
+        } else if slots < old {
+            let semaphore = self.semaphore.clone();
+            let retired = (old - slots) as u32;
+            tokio::spawn(async move {
+                if let Ok(p) = semaphore.acquire_many_owned(retired).await {
+                    p.forget();
+                }
+            });
+        }
+    }
+}
+
+/// Middleware limiting concurrent requests
+/// Requests beyond the limit wait for a slot until the queue timeout
+pub async fn queue_layer(State(s): State<AppState>, req: Request, next: Next) -> Response {
+    if s.config.max_concurrent_requests == 0 {
+        return next.run(req).await;
+    }
+    let semaphore = s.queue.semaphore.clone();
+    let retry_after = s.config.queue_timeout_secs.max(1);
+    let waiting = s.queue.waiting.fetch_add(1, Ordering::SeqCst);
+    if waiting >= s.config.max_queue_len && semaphore.available_permits() == 0 {
+        s.queue.waiting.fetch_sub(1, Ordering::SeqCst);
+        warn!("Request queue is full: {}", waiting.to_string().red());
+        return ClewdrError::ServerBusy(retry_after).into_response();
+    }
+    let timeout = Duration::from_secs(s.config.queue_timeout_secs);
+    let permit = tokio::time::timeout(timeout, semaphore.acquire_owned()).await;
+    s.queue.waiting.fetch_sub(1, Ordering::SeqCst);
+    let Ok(Ok(permit)) = permit else {
+        warn!("Timed out waiting for a free request slot");
+        return ClewdrError::ServerBusy(retry_after).into_response();
+    };
+    let (parts, body) = next.run(req).await.into_parts();
+    // the slot is held until the body ends, a stream keeps Claude.ai busy after the head
+    let body = body.into_data_stream().map(move |chunk| {
+        let _ = &permit;
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
@@ -0,0 +1,160 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{Extensions, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use colored::Colorize;
+use tracing::warn;
+
+use crate::{config::Config, error::ClewdrError, messages::api_key, state::AppState};
+
+/// Header with the time the current rate limit resets
+pub const RESET_HEADER: &str = "anthropic-ratelimit-requests-reset";
+
+/// Buckets kept before idle full ones are pruned
+const MAX_BUCKETS: usize = 4096;
+
+/// Token bucket of a single client
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket rate limiter keyed by API key and optionally client IP
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+    /// Tokens refilled per second, disabled when 0
+    rate: f64,
+    capacity: f64,
+}
+
+impl RateLimiter {
+    /// Create a new limiter, disabled when `rate_limit_rpm` is 0
+    pub fn new(config: &Config) -> Self {
+        Self {
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            rate: config.rate_limit_rpm as f64 / 60.0,
+            capacity: config.rate_limit_burst.max(1) as f64,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.rate > 0.0
+    }
+
+    /// Take a token for the key, returns seconds to wait when over limit
+    fn acquire(&self, key: &str) -> Result<(), u64> {
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if buckets.len() > MAX_BUCKETS {
+            let (rate, capacity) = (self.rate, self.capacity);
+            buckets.retain(|_, b| {
+                b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < capacity
+            });
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(((1.0 - bucket.tokens) / self.rate).ceil() as u64)
+    }
+}
+
+impl AppState {
+    /// Take a rate limit token for the API key, and the client IP if configured
+    pub fn charge_rate_limit(&self, key: &str, extensions: &Extensions) -> Result<(), ClewdrError> {
+        if !self.rate_limiter.enabled() {
+            return Ok(());
+        }
+        let mut key = key.to_string();
+        if self.config.rate_limit_by_ip {
+            if let Some(ConnectInfo(addr)) = extensions.get::<ConnectInfo<SocketAddr>>() {
+                key = format!("{}@{}", key, addr.ip());
+            }
+        }
+        self.rate_limiter.acquire(&key).map_err(|retry_after| {
+            warn!(
+                "Rate limit exceeded, retry after {}s",
+                retry_after.to_string().red()
+            );
+            ClewdrError::RateLimited(retry_after)
+        })
+    }
+}
+
+/// Middleware rejecting requests over the rate limit with 429
+pub async fn rate_limit_layer(State(s): State<AppState>, req: Request, next: Next) -> Response {
+    if let Err(e) = s.charge_rate_limit(api_key(req.headers()), req.extensions()) {
+        return e.into_response();
+    }
+    next.run(req).await
+}
+
+impl AppState {
+    /// Rate limit headers reported to the client, empty unless enabled
+    /// Upstream headers are passed through, the reset time of the cookie fills in the rest
+    pub fn rate_limit_headers(&self, upstream: &HeaderMap) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if !self.config.forward_rate_limit_headers {
+            return headers;
+        }
+        for (name, value) in upstream {
+            let n = name.as_str();
+            if n.starts_with("anthropic-ratelimit-") || n.starts_with("x-ratelimit-") {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+        if let Some(reset) = self
+            .cookie
+            .as_ref()
+            .and_then(|c| c.reset_time)
+            .and_then(|t| chrono::DateTime::from_timestamp(t, 0))
+            .and_then(|t| HeaderValue::from_str(&t.to_rfc3339()).ok())
+        {
+            headers.entry(RESET_HEADER).or_insert(reset);
+        }
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_the_burst_then_rejects() {
+        let mut config = Config::default();
+        config.rate_limit_rpm = 60;
+        config.rate_limit_burst = 2;
+        let limiter = RateLimiter::new(&config);
+        assert!(limiter.acquire("a").is_ok());
+        assert!(limiter.acquire("a").is_ok());
+        assert_eq!(limiter.acquire("a"), Err(1));
+        // every key has its own bucket
+        assert!(limiter.acquire("b").is_ok());
+    }
+
+    #[test]
+    fn limiter_is_disabled_without_a_rate() {
+        assert!(!RateLimiter::new(&Config::default()).enabled());
+    }
+}
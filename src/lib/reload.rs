@@ -0,0 +1,65 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::State,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::{admin::AdminAuth, config::Config, error::ClewdrError, state::AppState};
+
+impl AppState {
+    /// Re-read the config file and swap it in, leaving the cookie pool and running requests alone
+    /// Returns the changed fields that only take effect after a restart
+    pub fn reload_config(&self) -> Result<Vec<&'static str>, ClewdrError> {
+        let new = Config::reload()?;
+        let restart = self.live_config.load().restart_required(&new);
+        self.queue.resize(new.max_concurrent_requests);
+        self.live_config.store(Arc::new(new));
+        info!("Config reloaded");
+        if !restart.is_empty() {
+            warn!(
+                "Changes need a restart to take effect: {}",
+                restart.join(", ")
+            );
+        }
+        Ok(restart)
+    }
+}
+
+/// Reload the config on `SIGHUP`
+#[cfg(unix)]
+pub async fn run_reload_signal(state: AppState) {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        if let Err(e) = state.reload_config() {
+            warn!("Failed to reload config: {}", e);
+        }
+    }
+}
+
+/// Reload the config on `SIGHUP`, not available on this platform
+#[cfg(not(unix))]
+pub async fn run_reload_signal(_: AppState) {}
+
+/// Admin handler reloading the config file
+pub async fn api_reload_config(AdminAuth: AdminAuth, State(s): State<AppState>) -> Response {
+    match s.reload_config() {
+        Ok(restart) => {
+            Json(json!({ "reloaded": true, "restart_required": restart })).into_response()
+        }
+        Err(e) => {
+            warn!("Failed to reload config: {}", e);
+            e.into_response()
+        }
+    }
+}
@@ -1,14 +1,36 @@
 use axum::{
     Router,
-    extract::Request,
-    http::HeaderMap,
-    response::Html,
-    routing::{options, post},
+    extract::{DefaultBodyLimit, Request},
+    http::{HeaderMap, StatusCode, header::CONTENT_TYPE},
+    middleware::{from_fn_with_state, map_response},
+    response::{Html, IntoResponse, Response},
+    routing::{delete, get, options, post},
 };
 use const_format::{concatc, formatc};
+use tower_http::{compression::CompressionLayer, limit::RequestBodyLimitLayer};
 use tracing::error;
 
-use crate::{messages::api_messages, openai::api_completion, state::AppState, submit::api_submit};
+use crate::{
+    admin::{api_delete_conversation, api_list_conversations},
+    batch::api_batch,
+    debug::api_replay,
+    dedup::dedup_layer,
+    error::ClewdrError,
+    health::{api_healthz, api_readyz},
+    legacy::api_legacy_complete,
+    loopguard::loop_guard_layer,
+    messages::api_messages,
+    models::api_refresh_models,
+    openai::{api_completion, api_models},
+    pretty::pretty_json_layer,
+    queue::queue_layer,
+    ratelimit::rate_limit_layer,
+    reload::api_reload_config,
+    state::AppState,
+    stats::api_cookie_stats,
+    streams::stream_limit_layer,
+    submit::{api_submit, api_test_cookie},
+};
 
 /// RouterBuilder for the application
 pub struct RouterBuilder {
@@ -18,15 +40,54 @@ pub struct RouterBuilder {
 impl RouterBuilder {
     /// Create a new RouterBuilder instance
     pub fn new(state: AppState) -> Self {
+        let mut router = Router::new()
+            .route("/v1/chat/completions", post(api_completion))
+            .route("/v1/messages", post(api_messages))
+            .route("/v1/complete", post(api_legacy_complete))
+            .route_layer(from_fn_with_state(state.clone(), pretty_json_layer))
+            .route_layer(from_fn_with_state(state.clone(), queue_layer))
+            // streams over the cap of the key are rejected before queueing
+            .route_layer(from_fn_with_state(state.clone(), stream_limit_layer))
+            // rate limit runs first, so rejected requests never wait in the queue
+            .route_layer(from_fn_with_state(state.clone(), rate_limit_layer))
+            // duplicates attach before taking rate limit tokens or queue slots
+            .route_layer(from_fn_with_state(state.clone(), dedup_layer))
+            // looping clients are rejected before they can attach or spend anything
+            .route_layer(from_fn_with_state(state.clone(), loop_guard_layer))
+            .route("/", options(api_options))
+            .route("/v1", options(api_options))
+            .route("/v1/submit", post(api_submit))
+            // every request of a batch takes its own rate limit token and queue slot
+            .route(
+                "/v1/batch",
+                post(api_batch).layer(from_fn_with_state(state.clone(), loop_guard_layer)),
+            )
+            .route("/v1/models", get(api_models))
+            .route("/healthz", get(api_healthz))
+            .route("/readyz", get(api_readyz))
+            .route("/admin/conversations", get(api_list_conversations))
+            .route("/admin/cookies/stats", get(api_cookie_stats))
+            .route("/admin/cookies/test", post(api_test_cookie))
+            .route("/admin/models/refresh", post(api_refresh_models))
+            .route("/admin/config/reload", post(api_reload_config))
+            .route(
+                "/admin/conversations/{uuid}",
+                delete(api_delete_conversation),
+            );
+        if state.config.debug_endpoints {
+            router = router.route("/debug/replay", post(api_replay));
+        }
+        // one limit for every body, axum's default would cut image requests short
+        router = router
+            .layer(DefaultBodyLimit::disable())
+            .layer(RequestBodyLimitLayer::new(state.config.max_body_size))
+            .layer(map_response(body_limit_response));
+        if state.config.compression {
+            // the default predicate skips `text/event-stream`, so streams are never buffered
+            router = router.layer(CompressionLayer::new());
+        }
         Self {
-            inner: Router::new()
-                .route("/", options(api_options))
-                .route("/v1", options(api_options))
-                .route("/v1/chat/completions", post(api_completion))
-                .route("/v1/messages", post(api_messages))
-                .route("/v1/submit", post(api_submit))
-                .fallback(api_fallback)
-                .with_state(state),
+            inner: router.fallback(api_fallback).with_state(state),
         }
     }
 
@@ -83,6 +144,18 @@ Clewdr "#,
     ))
 }
 
+/// Explain rejections of the body size limit, which come without a JSON body
+async fn body_limit_response(res: Response) -> Response {
+    let is_json = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/json"));
+    if res.status() == StatusCode::PAYLOAD_TOO_LARGE && !is_json {
+        return ClewdrError::RequestTooLarge.into_response();
+    }
+    res
+}
+
 /// Handle the CORS preflight request
 async fn api_options() -> HeaderMap {
     let mut headers = HeaderMap::new();
@@ -0,0 +1,124 @@
+use std::collections::VecDeque;
+
+use rand::{Rng, rng, seq::IndexedRandom};
+
+use crate::config::{CookieStatus, CookieStrategy};
+
+/// Picks the next cookie to dispatch among the eligible ones
+pub trait Selector: Send + Sync {
+    /// Position in `pool` of the picked cookie, `candidates` are positions of eligible cookies in pool order
+    fn pick(&self, pool: &VecDeque<CookieStatus>, candidates: &[usize]) -> Option<usize>;
+}
+
+/// First eligible cookie, returned cookies go to the back of the pool
+pub struct RoundRobin;
+
+impl Selector for RoundRobin {
+    fn pick(&self, _: &VecDeque<CookieStatus>, candidates: &[usize]) -> Option<usize> {
+        candidates.first().copied()
+    }
+}
+
+/// Cookie dispatched the longest time ago, never used cookies first
+pub struct LeastRecentlyUsed;
+
+impl Selector for LeastRecentlyUsed {
+    fn pick(&self, pool: &VecDeque<CookieStatus>, candidates: &[usize]) -> Option<usize> {
+        // `min_by_key` keeps the first of equal keys, so ties fall back to pool order
+        candidates
+            .iter()
+            .copied()
+            .min_by_key(|&i| pool[i].last_used.unwrap_or(i64::MIN))
+    }
+}
+
+/// Uniformly random eligible cookie
+pub struct Random;
+
+impl Selector for Random {
+    fn pick(&self, _: &VecDeque<CookieStatus>, candidates: &[usize]) -> Option<usize> {
+        candidates.choose(&mut rng()).copied()
+    }
+}
+
+/// Random eligible cookie, proportional to its `weight`
+pub struct Weighted;
+
+impl Selector for Weighted {
+    fn pick(&self, pool: &VecDeque<CookieStatus>, candidates: &[usize]) -> Option<usize> {
+        let total = candidates
+            .iter()
+            .map(|&i| pool[i].weight() as u64)
+            .sum::<u64>();
+        if total == 0 {
+            return None;
+        }
+        let mut roll = rng().random_range(0..total);
+        candidates.iter().copied().find(|&i| {
+            let w = pool[i].weight() as u64;
+            if roll < w {
+                return true;
+            }
+            roll -= w;
+            false
+        })
+    }
+}
+
+impl CookieStrategy {
+    /// Selector implementing the strategy
+    pub fn selector(self) -> &'static dyn Selector {
+        match self {
+            CookieStrategy::RoundRobin => &RoundRobin,
+            CookieStrategy::LeastRecentlyUsed => &LeastRecentlyUsed,
+            CookieStrategy::Random => &Random,
+            CookieStrategy::Weighted => &Weighted,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cookie(last_used: Option<i64>) -> CookieStatus {
+        CookieStatus {
+            last_used,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn round_robin_takes_the_first_candidate() {
+        let pool = VecDeque::from([cookie(None), cookie(None), cookie(None)]);
+        assert_eq!(RoundRobin.pick(&pool, &[1, 2]), Some(1));
+        assert_eq!(RoundRobin.pick(&pool, &[]), None);
+    }
+
+    #[test]
+    fn least_recently_used_prefers_unused_cookies() {
+        let pool = VecDeque::from([cookie(Some(10)), cookie(None), cookie(Some(5))]);
+        assert_eq!(LeastRecentlyUsed.pick(&pool, &[0, 2]), Some(2));
+        assert_eq!(LeastRecentlyUsed.pick(&pool, &[0, 1, 2]), Some(1));
+    }
+
+    #[test]
+    fn random_strategies_pick_a_candidate() {
+        let pool = VecDeque::from([cookie(None), cookie(None), cookie(None)]);
+        for _ in 0..32 {
+            assert!(matches!(Random.pick(&pool, &[0, 2]), Some(0 | 2)));
+            assert!(matches!(Weighted.pick(&pool, &[0, 2]), Some(0 | 2)));
+        }
+        assert_eq!(Weighted.pick(&pool, &[]), None);
+    }
+
+    #[test]
+    fn weighted_skips_outweighed_cookies() {
+        let mut pool = VecDeque::from([cookie(None), cookie(None)]);
+        pool[1].weight = u32::MAX;
+        let picks = (0..64)
+            .filter(|_| Weighted.pick(&pool, &[0, 1]) == Some(1))
+            .count();
+        assert!(picks >= 60);
+    }
+}
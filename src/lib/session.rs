@@ -0,0 +1,236 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::FromRequestParts,
+    http::{StatusCode, request::Parts},
+    response::Response,
+};
+use colored::Colorize;
+use scopeguard::{ScopeGuard, guard};
+use tokio::spawn;
+use tracing::{info, warn};
+
+use crate::{audit::AuditRecord, error::ClewdrError, messages::ClientRequestBody, state::AppState};
+
+/// Header naming the session of a request
+pub const SESSION_HEADER: &str = "x-clewdr-session";
+
+/// Conversation and cookie pinned to a client session
+struct Session {
+    state: AppState,
+    last_used: Instant,
+    /// Turns sent in the current conversation
+    depth: usize,
+}
+
+/// Slot of a session, locked while a request of the session is running
+type Slot = Arc<tokio::sync::Mutex<Option<Session>>>;
+
+/// Sessions by the id sent in the session header
+#[derive(Clone, Default)]
+pub struct SessionTable {
+    sessions: Arc<Mutex<HashMap<String, Slot>>>,
+}
+
+impl SessionTable {
+    /// Get the slot of a session, creating an empty one if needed
+    fn slot(&self, id: &str) -> Option<Slot> {
+        let mut sessions = self.sessions.lock().ok()?;
+        Some(sessions.entry(id.to_string()).or_default().clone())
+    }
+
+    /// Remove a session from the table
+    fn remove(&self, id: &str) {
+        if let Ok(mut sessions) = self.sessions.lock() {
+            sessions.remove(id);
+        }
+    }
+
+    /// Take every idle session unused for longer than the ttl
+    fn take_expired(&self, ttl: Duration) -> Vec<(String, AppState)> {
+        let Ok(mut sessions) = self.sessions.lock() else {
+            return vec![];
+        };
+        let mut expired = vec![];
+        sessions.retain(|id, slot| {
+            // sessions in use are never expired
+            let Ok(mut s) = slot.try_lock() else {
+                return true;
+            };
+            match s.as_ref() {
+                Some(session) if session.last_used.elapsed() <= ttl => true,
+                Some(_) => {
+                    if let Some(session) = s.take() {
+                        expired.push((id.clone(), session.state));
+                    }
+                    false
+                }
+                None => false,
+            }
+        });
+        expired
+    }
+}
+
+/// Session id from the `x-clewdr-session` header
+pub struct SessionId(pub Option<String>);
+
+impl FromRequestParts<AppState> for SessionId {
+    type Rejection = StatusCode;
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        if state.live_config.load().session_ttl_secs == 0 {
+            return Ok(SessionId(None));
+        }
+        let id = parts
+            .headers
+            .get(SESSION_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .map(|v| v.to_string());
+        Ok(SessionId(id))
+    }
+}
+
+impl AppState {
+    /// Send a message in a pinned session
+    /// The first request creates the conversation and keeps its cookie,
+    /// later requests only append their newest turn
+    pub async fn session_message(
+        &self,
+        id: &str,
+        mut p: ClientRequestBody,
+    ) -> Result<Response, ClewdrError> {
+        let slot = self.sessions.slot(id).ok_or(ClewdrError::UnexpectedNone)?;
+        let mut slot = slot.lock().await;
+        match slot.as_mut() {
+            Some(session) => {
+                session.state.select_model(&mut p)?;
+                let max = self.config.max_session_depth;
+                if max > 0 && session.depth >= max {
+                    info!(
+                        "Session {} reached depth {}, renewing conversation",
+                        id, session.depth
+                    );
+                    // the new conversation gets the whole history
+                    session.state.renew_conversation(&p).await?;
+                    session.depth = 0;
+                } else {
+                    // the conversation already holds the earlier turns
+                    let start = p.messages.len().saturating_sub(1);
+                    p.messages.drain(..start);
+                }
+                session.last_used = Instant::now();
+            }
+            None => {
+                info!("Starting session {}", id.green());
+                *slot = Some(Session {
+                    state: self.start_session(&mut p).await?,
+                    last_used: Instant::now(),
+                    depth: 0,
+                });
+            }
+        }
+        // the session stays in its slot, so a dropped request keeps the cookie pinned
+        let session = slot.as_mut().ok_or(ClewdrError::UnexpectedNone)?;
+        let state = &mut session.state;
+        let org_uuid = state.org_uuid.clone().ok_or(ClewdrError::UnexpectedNone)?;
+        let conv_uuid = state.conv_uuid.clone().ok_or(ClewdrError::UnexpectedNone)?;
+        let mut audit = AuditRecord::new(state, &p);
+        let res = state
+            .send_message(p, &mut audit, &org_uuid, &conv_uuid)
+            .await;
+        session.last_used = Instant::now();
+        session.depth += 1;
+        if let Some(r) = res.as_ref().err().and_then(ClewdrError::cookie_reason) {
+            // the cookie is gone, so is the session
+            warn!("Session {} ended, cookie is invalid", id);
+            self.sessions.remove(id);
+            if let Some(mut session) = slot.take() {
+                if let Err(e) = session.state.delete_chat().await {
+                    warn!("Failed to delete chat: {}", e);
+                }
+                session.state.return_cookie(Some(r)).await;
+            }
+        }
+        res
+    }
+
+    /// Borrow a cookie and create the conversation of a new session
+    async fn start_session(&self, p: &mut ClientRequestBody) -> Result<AppState, ClewdrError> {
+        let mut state = self.clone();
+        state.request_cookie().await?;
+        // give the cookie back if the session is never stored
+        let mut state = guard(state, |mut s| {
+            spawn(async move {
+                if let Err(e) = s.delete_chat().await {
+                    warn!("Failed to delete chat: {}", e);
+                }
+                s.return_cookie(None).await;
+            });
+        });
+        let res = async {
+            state.bootstrap().await?;
+            state.select_model(p)?;
+            let org_uuid = state.org_uuid.clone().ok_or(ClewdrError::UnexpectedNone)?;
+            let thinking = p.thinking.is_some() && state.is_pro();
+            state
+                .create_conversation(&org_uuid, &p.model, thinking)
+                .await
+        }
+        .await;
+        if let Err(e) = res {
+            if let Some(r) = e.cookie_reason() {
+                let mut state = ScopeGuard::into_inner(state);
+                state.return_cookie(Some(r)).await;
+            }
+            return Err(e);
+        }
+        Ok(ScopeGuard::into_inner(state))
+    }
+
+    /// Replace the conversation of a session with a fresh one
+    async fn renew_conversation(&mut self, p: &ClientRequestBody) -> Result<(), ClewdrError> {
+        if let Err(e) = self.delete_chat().await {
+            warn!("Failed to delete chat: {}", e);
+        }
+        self.conv_uuid = None;
+        let org_uuid = self.org_uuid.clone().ok_or(ClewdrError::UnexpectedNone)?;
+        let thinking = p.thinking.is_some() && self.is_pro();
+        self.create_conversation(&org_uuid, &p.model, thinking)
+            .await?;
+        Ok(())
+    }
+
+    /// End every idle session, deleting its chat and releasing its cookie
+    async fn expire_sessions(&self) {
+        let ttl = Duration::from_secs(self.config.session_ttl_secs);
+        for (id, mut state) in self.sessions.take_expired(ttl) {
+            info!("Session {} expired", id);
+            if let Err(e) = state.delete_chat().await {
+                warn!("Failed to delete chat: {}", e);
+            }
+            state.return_cookie(None).await;
+        }
+    }
+}
+
+/// Periodically expire idle sessions
+pub async fn run_session_expiry(state: AppState) {
+    let secs = state.config.session_ttl_secs;
+    if secs == 0 {
+        return;
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(secs.min(60)));
+    loop {
+        interval.tick().await;
+        state.expire_sessions().await;
+    }
+}
@@ -0,0 +1,67 @@
+use colored::Colorize;
+use futures::future::join_all;
+use tracing::{info, warn};
+
+use crate::state::AppState;
+
+/// Wait for Ctrl+C or SIGTERM
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut s) => {
+                s.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!(
+        "{}",
+        "Shutdown signal received, draining requests...".yellow()
+    );
+}
+
+impl AppState {
+    /// Best-effort delete conversations that were not cleaned up
+    pub async fn delete_pending_chats(&self) {
+        let chats = self
+            .pending_chats
+            .lock()
+            .map(|mut c| c.drain().collect::<Vec<_>>())
+            .unwrap_or_default();
+        if chats.is_empty() {
+            return;
+        }
+        info!(
+            "Deleting {} pending chats",
+            chats.len().to_string().yellow()
+        );
+        let fut = chats.into_iter().map(|c| async move {
+            if let Err(e) = self
+                .delete_conversation(&c.org_uuid, &c.conv_uuid, c.cookie)
+                .await
+            {
+                warn!("Failed to delete chat {}: {}", c.conv_uuid, e);
+            }
+        });
+        join_all(fut).await;
+    }
+}
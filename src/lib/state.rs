@@ -1,46 +1,193 @@
 use colored::Colorize;
 use regex::Regex;
 use regex::RegexBuilder;
+use rquest::Proxy;
 use rquest::Response;
+use rquest::StatusCode;
 use rquest::header::SET_COOKIE;
+use scopeguard::{ScopeGuard, guard};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::spawn;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::error::SendTimeoutError;
 use tokio::sync::oneshot;
+use tokio::time::{sleep, timeout};
 use tracing::debug;
 use tracing::error;
+use tracing::info;
+use tracing::warn;
 
-use std::collections::HashMap;
-use std::sync::Arc;
+use arc_swap::ArcSwap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use crate::client::SUPER_CLIENT;
 use crate::client::SetupRequest;
 use crate::config::Config;
 use crate::config::CookieStatus;
+use crate::config::ExhaustedPolicy;
 use crate::config::Reason;
-use crate::error::ClewdrError;
+use crate::config::UselessCookie;
+use crate::config::WEB_ORIGIN;
+use crate::cookie::CookieRequest;
+use crate::dedup::Deduplicator;
+use crate::error::{ClewdrError, check_res_err};
+use crate::events::{EventBus, RequestEvent};
+use crate::loopguard::LoopGuard;
+use crate::messages::ClientRequestBody;
+use crate::queue::RequestQueue;
+use crate::ratelimit::RateLimiter;
+use crate::session::SessionTable;
+use crate::stats::StatsTable;
+use crate::streams::StreamCounter;
+use crate::utils::config_dir;
+
+/// Attempts to return a cookie before persisting it to disk
+const RETURN_RETRIES: u32 = 3;
+
+/// Write a cookie back to the config file when the cookie manager is gone,
+/// so it is recovered on restart
+fn persist_cookie(mut cookie: CookieStatus, reason: Option<Reason>) -> Result<(), ClewdrError> {
+    let mut config = Config::load()?;
+    config.cookie_array.retain(|c| c.cookie != cookie.cookie);
+    match reason {
+        None => config.cookie_array.push(cookie),
+        Some(Reason::TooManyRequest(i)) | Some(Reason::Restricted(i)) => {
+            cookie.reset_time = Some(i);
+            config.cookie_array.push(cookie);
+        }
+        Some(r) => config
+            .wasted_cookie
+            .push(UselessCookie::new(cookie.cookie, r)),
+    }
+    config.save()
+}
+
+/// Attempts to delete a chat before queueing it for a later retry
+const DELETE_RETRIES: u32 = 3;
+
+/// File keeping chats that failed to delete across restarts
+const FAILED_CHATS_FILE: &str = "failed_chats.json";
+
+/// Load chats that failed to delete in a previous run
+fn load_failed_chats() -> HashSet<PendingChat> {
+    config_dir()
+        .ok()
+        .and_then(|dir| std::fs::read_to_string(dir.join(FAILED_CHATS_FILE)).ok())
+        .and_then(|s| serde_json::from_str::<Vec<PendingChat>>(&s).ok())
+        .map(HashSet::from_iter)
+        .unwrap_or_default()
+}
+
+/// Conversation created on Claude.ai that has not been deleted yet
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PendingChat {
+    pub org_uuid: String,
+    pub conv_uuid: String,
+    pub cookie: String,
+    pub created_at: i64,
+}
 
 /// State of current connection
-#[derive(Clone)]
 pub struct AppState {
-    pub req_tx: Sender<oneshot::Sender<Result<CookieStatus, ClewdrError>>>,
+    pub req_tx: Sender<CookieRequest>,
     pub ret_tx: Sender<(CookieStatus, Option<Reason>)>,
     pub submit_tx: Sender<CookieStatus>,
     pub cookie: Option<CookieStatus>,
     pub config: Arc<Config>,
+    /// Latest config, swapped on reload
+    pub live_config: Arc<ArcSwap<Config>>,
     pub org_uuid: Option<String>,
     pub conv_uuid: Option<String>,
     cookies: HashMap<String, String>,
     pub capabilities: Vec<String>,
+    /// Cookie tags allowed for the API key of the request
+    pub tags: Vec<String>,
+    /// Model the borrowed cookie must be able to serve
+    pub model: Option<String>,
+    /// Keep the conversation of the request on Claude.ai
+    pub keep_chat: bool,
+    /// Proxy forced for the request by an admin, replacing `rquest_proxy`
+    pub proxy: Option<Proxy>,
+    /// Conversation created by the current attempt, shared with its disconnect guard
+    current_chat: Arc<Mutex<Option<PendingChat>>>,
+    pub pending_chats: Arc<Mutex<HashSet<PendingChat>>>,
+    pub failed_chats: Arc<Mutex<HashSet<PendingChat>>>,
+    pub queue: RequestQueue,
+    pub rate_limiter: RateLimiter,
+    pub loop_guard: LoopGuard,
+    pub streams: StreamCounter,
+    pub dedup: Deduplicator,
+    pub sessions: SessionTable,
+    /// Set once bootstrap has validated a cookie
+    pub ready: Arc<AtomicBool>,
+    pub stats: StatsTable,
+    pub events: EventBus,
+}
+
+/// Check if the capabilities belong to a paid account
+pub fn has_pro(capabilities: &[String]) -> bool {
+    capabilities.iter().any(|c| {
+        c.contains("pro") || c.contains("enterprise") || c.contains("raven") || c.contains("max")
+    })
+}
+
+/// Clones pick up the latest reloaded config,
+/// a running request keeps the config it started with
+impl Clone for AppState {
+    fn clone(&self) -> Self {
+        AppState {
+            req_tx: self.req_tx.clone(),
+            ret_tx: self.ret_tx.clone(),
+            submit_tx: self.submit_tx.clone(),
+            cookie: self.cookie.clone(),
+            config: self.live_config.load_full(),
+            live_config: self.live_config.clone(),
+            org_uuid: self.org_uuid.clone(),
+            conv_uuid: self.conv_uuid.clone(),
+            cookies: self.cookies.clone(),
+            capabilities: self.capabilities.clone(),
+            tags: self.tags.clone(),
+            model: self.model.clone(),
+            keep_chat: self.keep_chat,
+            proxy: self.proxy.clone(),
+            current_chat: self.current_chat.clone(),
+            pending_chats: self.pending_chats.clone(),
+            failed_chats: self.failed_chats.clone(),
+            queue: self.queue.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            loop_guard: self.loop_guard.clone(),
+            streams: self.streams.clone(),
+            dedup: self.dedup.clone(),
+            sessions: self.sessions.clone(),
+            ready: self.ready.clone(),
+            stats: self.stats.clone(),
+            events: self.events.clone(),
+        }
+    }
 }
 
 impl AppState {
     /// Create a new AppState instance
     pub fn new(
         config: Config,
-        req_tx: Sender<oneshot::Sender<Result<CookieStatus, ClewdrError>>>,
+        req_tx: Sender<CookieRequest>,
         ret_tx: Sender<(CookieStatus, Option<Reason>)>,
         submit_tx: Sender<CookieStatus>,
     ) -> Self {
         AppState {
+            queue: RequestQueue::new(&config),
+            rate_limiter: RateLimiter::new(&config),
+            loop_guard: LoopGuard::new(&config),
+            streams: StreamCounter::default(),
+            dedup: Deduplicator::default(),
+            sessions: SessionTable::default(),
+            ready: Arc::new(AtomicBool::new(false)),
+            stats: StatsTable::default(),
+            events: EventBus::default(),
+            live_config: Arc::new(ArcSwap::from_pointee(config.clone())),
             config: Arc::new(config),
             req_tx,
             ret_tx,
@@ -50,16 +197,58 @@ impl AppState {
             conv_uuid: None,
             cookies: HashMap::new(),
             capabilities: Vec::new(),
+            tags: Vec::new(),
+            model: None,
+            keep_chat: false,
+            proxy: None,
+            current_chat: Arc::default(),
+            pending_chats: Arc::new(Mutex::new(HashSet::new())),
+            failed_chats: Arc::new(Mutex::new(load_failed_chats())),
         }
     }
 
+    /// Proxy for upstream requests, the override of the request takes precedence
+    pub fn proxy(&self) -> Option<Proxy> {
+        self.proxy
+            .clone()
+            .or_else(|| self.config.rquest_proxy.clone())
+    }
+
     pub fn is_pro(&self) -> bool {
-        self.capabilities.iter().any(|c| {
-            c.contains("pro")
-                || c.contains("enterprise")
-                || c.contains("raven")
-                || c.contains("max")
-        })
+        has_pro(&self.capabilities)
+    }
+
+    /// Remember that the account of the current cookie cannot use the model
+    pub fn mark_unavailable(&mut self, model: &str) {
+        if let Some(c) = self.cookie.as_mut() {
+            c.unavailable_models.insert(model.to_string());
+        }
+    }
+
+    /// Check if the current account can use the model
+    pub fn check_model(&self, model: &str) -> Result<(), ClewdrError> {
+        if self.config.requires_pro(model) && !self.is_pro() {
+            return Err(ClewdrError::ModelRequiresPro(model.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Check the model against the current account, switching to the first usable fallback
+    pub fn select_model(&self, p: &mut ClientRequestBody) -> Result<(), ClewdrError> {
+        let Err(e) = self.check_model(&p.model) else {
+            return Ok(());
+        };
+        let Some(model) = p
+            .fallback_models
+            .iter()
+            .find(|m| self.check_model(m).is_ok())
+        else {
+            return Err(e);
+        };
+        info!("Model {} unavailable, falling back to {}", p.model, model);
+        p.model = model.clone();
+        p.substituted = true;
+        Ok(())
     }
 
     /// Update cookie from the server response
@@ -105,28 +294,186 @@ impl AppState {
             .to_string()
     }
 
+    /// Limit cookies to the tags allowed for the API key
+    pub fn set_key(&mut self, key: &str) {
+        self.tags = self.config.key_tags.get(key).cloned().unwrap_or_default();
+    }
+
     /// request a new cookie from cookie manager
     pub async fn request_cookie(&mut self) -> Result<(), ClewdrError> {
         // real client to avoid mixed use of cookies
         let (one_tx, one_rx) = oneshot::channel();
-        self.req_tx.send(one_tx).await?;
-        let res = one_rx.await??;
-        self.cookie = Some(res.clone());
-        self.update_cookies(res.cookie.to_string().as_str());
+        let req = CookieRequest {
+            tx: one_tx,
+            tags: self.tags.clone(),
+            model: self.model.clone(),
+        };
+        self.req_tx.send(req).await?;
+        let res = if self.config.exhausted_policy == ExhaustedPolicy::Wait {
+            // the manager holds the request until a cookie frees up
+            let wait = Duration::from_secs(self.config.cookie_wait_secs);
+            timeout(wait, one_rx)
+                .await
+                .map_err(|_| ClewdrError::NoCookieAvailable)???
+        } else {
+            one_rx.await??
+        };
         println!("Cookie: {}", res.cookie.to_string().green());
+        self.events.publish(RequestEvent::CookieSelected {
+            cookie: res.cookie.digest(),
+        });
+        self.set_cookie(res);
         Ok(())
     }
 
+    /// Use the given cookie for following requests
+    pub fn set_cookie(&mut self, cookie: CookieStatus) {
+        self.update_cookies(cookie.cookie.to_string().as_str());
+        self.cookie = Some(cookie);
+    }
+
     /// return the cookie to the cookie manager
     pub async fn return_cookie(&mut self, reason: Option<Reason>) {
         // return the cookie to the cookie manager
-        if let Some(cookie) = self.cookie.take() {
-            self.ret_tx
-                .send((cookie, reason))
-                .await
-                .unwrap_or_else(|e| {
-                    error!("Failed to send cookie: {}", e);
-                });
+        let Some(cookie) = self.cookie.take() else {
+            return;
+        };
+        let mut item = (cookie, reason);
+        for i in 0..RETURN_RETRIES {
+            let timeout = Duration::from_secs(1 << i);
+            match self.ret_tx.send_timeout(item, timeout).await {
+                Ok(_) => return,
+                Err(SendTimeoutError::Timeout(v)) => {
+                    warn!("Cookie manager busy, retrying cookie return");
+                    item = v;
+                }
+                Err(SendTimeoutError::Closed(v)) => {
+                    item = v;
+                    break;
+                }
+            }
+        }
+        let (cookie, reason) = item;
+        error!(
+            "{}",
+            format!(
+                "Cookie manager unreachable, persisting cookie to disk: {}",
+                cookie.cookie
+            )
+            .red()
+        );
+        if let Err(e) = persist_cookie(cookie, reason) {
+            error!("Failed to persist cookie: {}", e);
+        }
+    }
+
+    /// Assign a fresh chat conversation to the current attempt
+    /// The returned guard deletes it if the attempt is dropped before finishing,
+    /// e.g. when the client disconnects, so the upstream work is not kept around
+    /// The organization is only known after bootstrap, so the guard reads the created conversation
+    pub fn begin_chat(&mut self) -> ScopeGuard<AppState, impl FnOnce(AppState)> {
+        self.conv_uuid = Some(uuid::Uuid::new_v4().to_string());
+        self.current_chat = Arc::default();
+        guard(self.clone(), |mut state| {
+            warn!("Client disconnected, aborting upstream request");
+            let Some(chat) = state.current_chat.lock().ok().and_then(|c| c.clone()) else {
+                // nothing was created on Claude.ai yet
+                return;
+            };
+            state.org_uuid = Some(chat.org_uuid);
+            state.conv_uuid = Some(chat.conv_uuid);
+            spawn(async move {
+                if let Err(e) = state.delete_chat().await {
+                    warn!("Failed to delete chat: {}", e);
+                }
+            });
+        })
+    }
+
+    /// Create the chat conversation of the current attempt on Claude.ai
+    /// A rejected UUID is regenerated and retried a few times
+    pub async fn create_conversation(
+        &mut self,
+        org_uuid: &str,
+        model: &str,
+        thinking: bool,
+    ) -> Result<String, ClewdrError> {
+        let mut attempt = 0;
+        loop {
+            let new_uuid = self
+                .conv_uuid
+                .get_or_insert_with(|| uuid::Uuid::new_v4().to_string())
+                .clone();
+            let mut body = json!({
+                "uuid": new_uuid,
+                "name": self.config.conversation_name(model, &new_uuid),
+            });
+            // enable thinking mode
+            if thinking {
+                body["paprika_mode"] = "extended".into();
+                body["model"] = model.into();
+            }
+            let cookie = self.header_cookie();
+            let api_res = self
+                .send_upstream(|client, endpoint| {
+                    client
+                        .post(format!(
+                            "{}/api/organizations/{}/chat_conversations",
+                            endpoint, org_uuid
+                        ))
+                        .json(&body)
+                        .setup_request("", &cookie, self.proxy())
+                })
+                .await?;
+            self.update_cookie_from_res(&api_res);
+            match check_res_err(api_res).await {
+                Ok(_) => {
+                    debug!("New conversation created: {}", new_uuid);
+                    self.track_chat();
+                    return Ok(new_uuid);
+                }
+                Err(e)
+                    if e.is_conversation_conflict()
+                        && attempt < self.config.conversation_retries =>
+                {
+                    attempt += 1;
+                    warn!(
+                        "Conversation {} rejected, retrying with a new UUID: {}",
+                        new_uuid, e
+                    );
+                    self.conv_uuid = Some(uuid::Uuid::new_v4().to_string());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Whether conversations are kept on Claude.ai, by `preserve_chats` or the keep header
+    pub fn keeps_chat(&self) -> bool {
+        self.config.preserve_chats || self.keep_chat
+    }
+
+    /// Remember current chat conversation so it can be deleted on shutdown
+    pub fn track_chat(&self) {
+        // kept chats are never deleted
+        if self.keeps_chat() {
+            return;
+        }
+        let (Some(org_uuid), Some(conv_uuid)) = (self.org_uuid.clone(), self.conv_uuid.clone())
+        else {
+            return;
+        };
+        let chat = PendingChat {
+            org_uuid,
+            conv_uuid,
+            cookie: self.header_cookie(),
+            created_at: chrono::Utc::now().timestamp(),
+        };
+        if let Ok(mut current) = self.current_chat.lock() {
+            *current = Some(chat.clone());
+        }
+        if let Ok(mut chats) = self.pending_chats.lock() {
+            chats.insert(chat);
         }
     }
 
@@ -138,23 +485,86 @@ impl AppState {
         let Some(ref conv_uuid) = self.conv_uuid else {
             return Ok(());
         };
-        // if preserve_chats is true, do not delete chat
-        if self.config.preserve_chats {
+        if self.keeps_chat() {
+            info!(
+                "Keeping conversation {}: {}/chat/{}",
+                conv_uuid, WEB_ORIGIN, conv_uuid
+            );
             return Ok(());
         }
+        let cookie = self.header_cookie();
+        let mut res = Ok(());
+        for i in 0..DELETE_RETRIES {
+            if i > 0 {
+                sleep(Duration::from_secs(1 << i)).await;
+            }
+            res = self
+                .delete_conversation(org_uuid, conv_uuid, cookie.clone())
+                .await;
+            if res.is_ok() {
+                return res;
+            }
+        }
+        // keep it on disk so the retry task deletes it later
+        self.queue_failed_chat(PendingChat {
+            org_uuid: org_uuid.clone(),
+            conv_uuid: conv_uuid.clone(),
+            cookie,
+            created_at: chrono::Utc::now().timestamp(),
+        });
+        res
+    }
+
+    /// Queue a chat that failed to delete and persist the queue
+    pub fn queue_failed_chat(&self, chat: PendingChat) {
+        if let Ok(mut chats) = self.failed_chats.lock() {
+            chats.insert(chat);
+        }
+        self.save_failed_chats();
+    }
+
+    /// Write chats that failed to delete to disk
+    pub fn save_failed_chats(&self) {
+        let Ok(chats) = self.failed_chats.lock() else {
+            return;
+        };
+        let res = config_dir().and_then(|dir| {
+            let chats = chats.iter().collect::<Vec<_>>();
+            std::fs::write(dir.join(FAILED_CHATS_FILE), serde_json::to_string(&chats)?)?;
+            Ok(())
+        });
+        if let Err(e) = res {
+            error!("Failed to save failed chats: {}", e);
+        }
+    }
+
+    /// Delete a chat conversation with the given cookie
+    pub async fn delete_conversation(
+        &self,
+        org_uuid: &str,
+        conv_uuid: &str,
+        cookie: String,
+    ) -> Result<(), ClewdrError> {
         debug!("Deleting chat: {}", conv_uuid);
-        let endpoint = format!(
-            "{}/api/organizations/{}/chat_conversations/{}",
-            self.config.endpoint(),
-            org_uuid,
-            conv_uuid
-        );
-        let proxy = self.config.rquest_proxy.clone();
-        let _ = SUPER_CLIENT
-            .delete(endpoint)
-            .setup_request("", self.header_cookie(), proxy)
-            .send()
+        let proxy = self.proxy();
+        let res = self
+            .send_upstream(|client, endpoint| {
+                client
+                    .delete(format!(
+                        "{}/api/organizations/{}/chat_conversations/{}",
+                        endpoint, org_uuid, conv_uuid
+                    ))
+                    .setup_request("", &cookie, proxy.clone())
+            })
             .await?;
+        // already deleted chats count as deleted
+        if res.status() != StatusCode::NOT_FOUND {
+            check_res_err(res).await?;
+        }
+        // chats failed to delete stay tracked for the sweeper
+        if let Ok(mut chats) = self.pending_chats.lock() {
+            chats.retain(|c| c.conv_uuid != conv_uuid);
+        }
         Ok(())
     }
 }
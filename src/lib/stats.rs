@@ -0,0 +1,120 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{Json, extract::State};
+use serde::Serialize;
+
+use crate::{
+    admin::AdminAuth,
+    error::ClewdrError,
+    events::{Failure, RequestEvent, next_event},
+    state::AppState,
+};
+
+/// Request counters of a single cookie
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct CookieStats {
+    pub requests: u64,
+    pub successes: u64,
+    pub rate_limited: u64,
+    pub invalid: u64,
+    pub errors: u64,
+    pub avg_latency_ms: u64,
+    /// Unix timestamp of the last request
+    pub last_used: Option<i64>,
+    /// Last reason the cookie was reported unusable
+    pub reason: Option<String>,
+    /// Upstream status code behind the last reason
+    pub status: Option<u16>,
+    #[serde(skip)]
+    total_latency_ms: u64,
+}
+
+/// Per-cookie statistics, keyed by cookie digest so the raw cookie is never exposed
+#[derive(Clone, Default)]
+pub struct StatsTable {
+    cookies: Arc<Mutex<HashMap<String, CookieStats>>>,
+}
+
+impl StatsTable {
+    /// Record the outcome of a request made with the cookie
+    fn record(&self, cookie: String, failure: Option<&Failure>, latency_ms: u64) {
+        let Ok(mut cookies) = self.cookies.lock() else {
+            return;
+        };
+        let s = cookies.entry(cookie).or_default();
+        s.requests += 1;
+        s.total_latency_ms += latency_ms;
+        s.avg_latency_ms = s.total_latency_ms / s.requests;
+        s.last_used = Some(chrono::Utc::now().timestamp());
+        match failure {
+            None => s.successes += 1,
+            Some(Failure::RateLimited(r)) => {
+                s.rate_limited += 1;
+                s.reason = Some(r.clone());
+                s.status = Some(429);
+            }
+            Some(Failure::InvalidCookie { reason, status }) => {
+                s.invalid += 1;
+                s.reason = Some(reason.clone());
+                s.status = *status;
+            }
+            Some(Failure::Other(_)) => s.errors += 1,
+        }
+    }
+
+    fn snapshot(&self) -> HashMap<String, CookieStats> {
+        self.cookies.lock().map(|c| c.clone()).unwrap_or_default()
+    }
+}
+
+impl AppState {
+    /// Publish the outcome of a request made with the current cookie
+    pub fn record_stats<T>(
+        &self,
+        res: &Result<T, ClewdrError>,
+        started: chrono::DateTime<chrono::Utc>,
+    ) {
+        let Some(cookie) = self.cookie.as_ref() else {
+            return;
+        };
+        let cookie = cookie.cookie.digest();
+        let latency_ms = (chrono::Utc::now() - started).num_milliseconds().max(0) as u64;
+        self.events.publish(match res {
+            Ok(_) => RequestEvent::Completed { cookie, latency_ms },
+            Err(e) => RequestEvent::Errored {
+                cookie,
+                failure: e.into(),
+                latency_ms,
+            },
+        });
+    }
+}
+
+/// Subscriber keeping the per-cookie statistics up to date
+pub async fn run_stats(state: AppState) {
+    let mut rx = state.events.subscribe();
+    while let Some(event) = next_event(&mut rx).await {
+        match event {
+            RequestEvent::Completed { cookie, latency_ms } => {
+                state.stats.record(cookie, None, latency_ms)
+            }
+            RequestEvent::Errored {
+                cookie,
+                failure,
+                latency_ms,
+            } => state.stats.record(cookie, Some(&failure), latency_ms),
+            _ => {}
+        }
+    }
+}
+
+/// Per-cookie request statistics
+pub async fn api_cookie_stats(
+    AdminAuth: AdminAuth,
+    State(s): State<AppState>,
+) -> Json<HashMap<String, CookieStats>> {
+    Json(s.stats.snapshot())
+}
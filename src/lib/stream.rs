@@ -0,0 +1,976 @@
+use axum::response::sse::Event;
+use eventsource_stream::EventStreamError;
+use futures::pin_mut;
+use serde_json::Value;
+use std::{
+    mem,
+    time::{Duration, Instant},
+};
+use tokio_stream::{Stream, StreamExt};
+use tracing::{debug, warn};
+use transform_stream::{AsyncTryStream, Yielder};
+
+use crate::{
+    config::{EmptyResponsePolicy, ThinkingTags},
+    error::ClewdrError,
+    markdown::MarkdownStripper,
+    openai::{LeadingTrimmer, PrefillTrimmer, TagStripper},
+    types::message::{
+        ContentBlock, ContentBlockDelta, MessageDeltaContent, MessageStartContent, Role,
+        StopReason, StreamEvent, StreamUsage, Usage,
+    },
+};
+
+/// Service tier reported for Claude.ai, which has no tiers
+const WEB_SERVICE_TIER: &str = "standard";
+
+/// Largest number of upstream events merged into one batch
+const COALESCE_BATCH: usize = 1024;
+
+/// Merge runs of text events arriving within `window_ms` into one event of up to `max_chars`
+/// Disabled when `window_ms` is 0, other events pass through and flush the run immediately
+pub fn coalesce_events<S, E>(
+    input: S,
+    window_ms: u64,
+    max_chars: usize,
+) -> impl Stream<Item = Result<eventsource_stream::Event, E>>
+where
+    S: Stream<Item = Result<eventsource_stream::Event, E>>,
+{
+    if window_ms == 0 {
+        return futures::StreamExt::right_stream(input);
+    }
+    let batches = input.chunks_timeout(COALESCE_BATCH, Duration::from_millis(window_ms));
+    let merged = futures::StreamExt::flat_map(batches, move |batch| {
+        tokio_stream::iter(merge_text_events(batch, max_chars.max(1)))
+    });
+    futures::StreamExt::left_stream(merged)
+}
+
+/// Text of an event carrying nothing but text, with its parsed body
+fn text_only(event: &eventsource_stream::Event) -> Option<(Value, String)> {
+    let json = serde_json::from_str::<Value>(&event.data).ok()?;
+    let stop = json
+        .get("stop_reason")
+        .or(json.pointer("/delta/stop_reason"))
+        .is_some_and(|r| !r.is_null());
+    if stop {
+        return None;
+    }
+    let text = json
+        .get("completion")
+        .or(json.pointer("/delta/text"))?
+        .as_str()?
+        .to_string();
+    Some((json, text))
+}
+
+/// Merge consecutive text events of a batch
+fn merge_text_events<E>(
+    batch: Vec<Result<eventsource_stream::Event, E>>,
+    max_chars: usize,
+) -> Vec<Result<eventsource_stream::Event, E>> {
+    /// First event of a run, its body and the text merged so far
+    type Run = (eventsource_stream::Event, Value, String);
+    fn flush<E>(run: Option<Run>, out: &mut Vec<Result<eventsource_stream::Event, E>>) {
+        let Some((mut event, mut json, text)) = run else {
+            return;
+        };
+        if json.get("completion").is_some() {
+            json["completion"] = text.into();
+        } else {
+            json["delta"]["text"] = text.into();
+        }
+        event.data = json.to_string();
+        out.push(Ok(event));
+    }
+    let mut out = Vec::with_capacity(batch.len());
+    let mut run: Option<Run> = None;
+    for item in batch {
+        let Some((json, text)) = item.as_ref().ok().and_then(text_only) else {
+            flush(run.take(), &mut out);
+            out.push(item);
+            continue;
+        };
+        match run.as_mut() {
+            Some((_, _, merged)) => merged.push_str(&text),
+            None => {
+                if let Ok(event) = item {
+                    run = Some((event, json, text));
+                }
+            }
+        }
+        if run
+            .as_ref()
+            .is_some_and(|(_, _, merged)| merged.chars().count() >= max_chars)
+        {
+            flush(run.take(), &mut out);
+        }
+    }
+    flush(run, &mut out);
+    out
+}
+
+/// Periodic debug log of the progress of a long stream
+#[derive(Debug)]
+pub struct StreamProgress {
+    every: Duration,
+    start: Instant,
+    last: Instant,
+    events: usize,
+    bytes: usize,
+}
+
+impl StreamProgress {
+    /// Log every `secs` seconds, disabled when 0
+    pub fn new(secs: u64) -> Option<Self> {
+        let now = Instant::now();
+        (secs > 0).then(|| Self {
+            every: Duration::from_secs(secs),
+            start: now,
+            last: now,
+            events: 0,
+            bytes: 0,
+        })
+    }
+
+    /// Count an upstream event, logging when the interval has passed
+    pub fn push(&mut self, data: &str) {
+        self.events += 1;
+        self.bytes += data.len();
+        if self.last.elapsed() < self.every {
+            return;
+        }
+        self.last = Instant::now();
+        debug!(
+            "Stream progress: {} events, {} bytes, elapsed {} seconds",
+            self.events,
+            self.bytes,
+            self.start.elapsed().as_secs()
+        );
+    }
+}
+
+/// Kind of the content block currently open
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockKind {
+    Text,
+    Thinking,
+    ToolUse,
+}
+
+/// Thinking wrapped in tags inside the text
+#[derive(Debug)]
+struct InlineThinking {
+    open: String,
+    close: String,
+    /// Keep native thinking blocks, the tagged text follows them
+    both: bool,
+    buf: String,
+    inside: bool,
+}
+
+/// Wait up to `secs` for the first upstream event, disabled when 0
+/// The event is put back in front of the stream, nothing is sent to the client before it
+pub async fn await_first_event<S>(
+    mut events: S,
+    secs: u64,
+) -> Result<impl Stream<Item = S::Item>, ClewdrError>
+where
+    S: Stream + Unpin,
+{
+    let first = if secs == 0 {
+        None
+    } else {
+        tokio::time::timeout(Duration::from_secs(secs), events.next())
+            .await
+            .map_err(|_| ClewdrError::FirstTokenTimeout(secs))?
+    };
+    Ok(tokio_stream::iter(first).chain(events))
+}
+
+/// Add incrementing `id:` fields to the events and a `retry:` hint to the first one
+/// Both are optional, the stream passes through untouched when disabled
+pub fn with_event_ids<S, E>(
+    events: S,
+    ids: bool,
+    retry_ms: u64,
+) -> impl Stream<Item = Result<Event, E>>
+where
+    S: Stream<Item = Result<Event, E>>,
+{
+    let mut next_id = 0u64;
+    events.map(move |e| {
+        e.map(|mut e| {
+            if next_id == 0 && retry_ms > 0 {
+                e = e.retry(Duration::from_millis(retry_ms));
+            }
+            if ids {
+                e = e.id(next_id.to_string());
+            }
+            next_id += 1;
+            e
+        })
+    })
+}
+
+/// Cut a complete response at the first client stop sequence
+pub fn cut_at_stops(mut text: String, stops: &[String]) -> String {
+    if let Some(i) = stops.iter().filter_map(|s| text.find(s.as_str())).min() {
+        text.truncate(i);
+    }
+    text
+}
+
+/// Cut complete content blocks at the first client stop sequence, dropping the blocks after it
+pub fn cut_blocks_at_stops(blocks: Vec<ContentBlock>, stops: &[String]) -> Vec<ContentBlock> {
+    let mut out = Vec::with_capacity(blocks.len());
+    for block in blocks {
+        let ContentBlock::Text { text } = block else {
+            out.push(block);
+            continue;
+        };
+        let len = text.len();
+        let text = cut_at_stops(text, stops);
+        let cut = text.len() < len;
+        out.push(ContentBlock::Text { text });
+        if cut {
+            break;
+        }
+    }
+    out
+}
+
+/// Transform Claude.ai completion events into Claude API streaming events
+/// Synthesizes the full `message_start` .. `message_stop` lifecycle
+#[derive(Debug)]
+pub struct ClaudeTransformer {
+    model: String,
+    started: bool,
+    block: Option<BlockKind>,
+    index: usize,
+    stop_reason: Option<StopReason>,
+    stop_sequence: Option<String>,
+    stop_sequences: Vec<String>,
+    /// Cut the output at client stop sequences, so they never reach the client
+    trim_stops: bool,
+    thinking_tags: Option<InlineThinking>,
+    /// An `error` event was sent, the stream ends without `message_stop`
+    failed: bool,
+    /// Turn separators ending the output when the model writes them
+    turn_stops: Vec<String>,
+    /// Text held back while it may be the start of a turn separator
+    held: String,
+    /// Prefix removed from the start of the response
+    leading: Option<LeadingTrimmer>,
+    /// Repeated end of the prefill removed from the start of the response
+    prefill: Option<PrefillTrimmer>,
+    /// Markdown formatting removed from the response
+    markdown: Option<MarkdownStripper>,
+    /// A turn separator was reached, the rest of the output is dropped
+    cut: bool,
+    /// Non-blank text was emitted
+    has_text: bool,
+    empty_policy: EmptyResponsePolicy,
+    empty_placeholder: String,
+}
+
+impl ClaudeTransformer {
+    pub fn new(model: String) -> Self {
+        Self {
+            model,
+            started: false,
+            block: None,
+            index: 0,
+            stop_reason: None,
+            stop_sequence: None,
+            stop_sequences: Vec::new(),
+            trim_stops: false,
+            thinking_tags: None,
+            failed: false,
+            turn_stops: Vec::new(),
+            held: String::new(),
+            leading: None,
+            prefill: None,
+            markdown: None,
+            cut: false,
+            has_text: false,
+            empty_policy: EmptyResponsePolicy::Pass,
+            empty_placeholder: String::new(),
+        }
+    }
+
+    /// Handle a stream that ends without any text
+    /// Retrying is impossible once the stream started, so `retry` fails like `error`
+    pub fn with_empty_response(mut self, policy: EmptyResponsePolicy, placeholder: &str) -> Self {
+        self.empty_policy = policy;
+        self.empty_placeholder = placeholder.to_string();
+        self
+    }
+
+    /// Strip a prefix from the start of the response
+    pub fn with_leading(mut self, leading: Option<LeadingTrimmer>) -> Self {
+        self.leading = leading;
+        self
+    }
+
+    /// Strip the repeated end of the prefill from the start of the response
+    pub fn with_prefill(mut self, prefill: Option<PrefillTrimmer>) -> Self {
+        self.prefill = prefill;
+        self
+    }
+
+    /// Strip markdown formatting from the response
+    pub fn with_markdown(mut self, markdown: Option<MarkdownStripper>) -> Self {
+        self.markdown = markdown;
+        self
+    }
+
+    /// End the output where the model starts a turn on its own
+    pub fn with_turn_stops(mut self, turn_stops: Vec<String>) -> Self {
+        self.turn_stops = turn_stops;
+        self
+    }
+
+    /// Strip client stop sequences from the output, holding back text that may start one
+    pub fn with_stop_trimming(mut self, enabled: bool) -> Self {
+        self.trim_stops = enabled;
+        self
+    }
+
+    /// Client stop sequences trimmed from the output
+    fn trimmed_stops(&self) -> &[String] {
+        if self.trim_stops {
+            &self.stop_sequences
+        } else {
+            &[]
+        }
+    }
+
+    /// Text that is safe to emit, cutting the output at the first turn separator or stop sequence
+    fn cut_turns(&mut self, text: &str) -> String {
+        if self.turn_stops.is_empty() && self.trimmed_stops().is_empty() {
+            return text.to_string();
+        }
+        self.held.push_str(text);
+        let turns = self
+            .turn_stops
+            .iter()
+            .filter_map(|s| Some((self.held.find(s.as_str())?, None)));
+        let stops = self
+            .trimmed_stops()
+            .iter()
+            .filter_map(|s| Some((self.held.find(s.as_str())?, Some(s.clone()))));
+        if let Some((i, stop)) = turns.chain(stops).min_by_key(|(i, _)| *i) {
+            match stop {
+                Some(s) => {
+                    debug!("Stop sequence reached, cutting the output");
+                    self.stop_reason = Some(StopReason::StopSequence);
+                    self.stop_sequence = Some(s);
+                }
+                None => {
+                    debug!("Model started a new turn, cutting the output");
+                    self.stop_reason = Some(StopReason::EndTurn);
+                }
+            }
+            self.cut = true;
+            self.held.truncate(i);
+            return mem::take(&mut self.held);
+        }
+        let keep = self
+            .turn_stops
+            .iter()
+            .chain(self.trimmed_stops())
+            .map(|s| TagStripper::partial(&self.held, s))
+            .max()
+            .unwrap_or_default();
+        let split = self.held.len() - keep;
+        self.held.drain(..split).collect()
+    }
+
+    /// Wrap thinking in tags inside the text
+    pub fn with_thinking_tags(mut self, mode: ThinkingTags, open: &str, close: &str) -> Self {
+        self.thinking_tags = (mode != ThinkingTags::Off).then(|| InlineThinking {
+            open: open.to_string(),
+            close: close.to_string(),
+            both: mode == ThinkingTags::Both,
+            buf: String::new(),
+            inside: false,
+        });
+        self
+    }
+
+    /// Stop sequences supplied by the client, reported back when matched
+    pub fn with_stop_sequences(mut self, stop_sequences: Vec<String>) -> Self {
+        self.stop_sequences = stop_sequences;
+        self
+    }
+
+    /// Record the upstream stop reason, keeping only client stop sequences
+    fn set_stop(&mut self, reason: &str, stop: Option<&str>) {
+        let reason = StopReason::normalize(reason);
+        let matched = stop.filter(|s| self.stop_sequences.iter().any(|c| c == s));
+        match (reason, matched) {
+            (StopReason::StopSequence, Some(s)) => {
+                self.stop_reason = Some(StopReason::StopSequence);
+                self.stop_sequence = Some(s.to_string());
+            }
+            // upstream stopped on its own turn delimiter, not a client one
+            (StopReason::StopSequence, None) => self.stop_reason = Some(StopReason::EndTurn),
+            (r, _) => self.stop_reason = Some(r),
+        }
+    }
+
+    fn build(event: &StreamEvent) -> Event {
+        let data = serde_json::to_value(event).unwrap_or_default();
+        let name = data["type"].as_str().unwrap_or("message").to_string();
+        Event::default().event(name).json_data(data).unwrap()
+    }
+
+    async fn emit(event: StreamEvent, y: &mut Yielder<Result<Event, ClewdrError>>) {
+        y.yield_ok(Self::build(&event)).await;
+    }
+
+    /// Emit `message_start` once
+    async fn start(&mut self, y: &mut Yielder<Result<Event, ClewdrError>>) {
+        if self.started {
+            return;
+        }
+        self.started = true;
+        let message = MessageStartContent {
+            id: format!("msg_{}", uuid::Uuid::new_v4().simple()),
+            type_: "message".to_string(),
+            role: Role::Assistant,
+            model: self.model.clone(),
+            usage: Usage {
+                service_tier: Some(WEB_SERVICE_TIER.to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        Self::emit(StreamEvent::MessageStart { message }, y).await;
+    }
+
+    /// Open a content block of the given kind, closing the previous one
+    async fn open_block(&mut self, kind: BlockKind, y: &mut Yielder<Result<Event, ClewdrError>>) {
+        if self.block == Some(kind) {
+            return;
+        }
+        self.close_block(y).await;
+        let content_block = match kind {
+            BlockKind::Text => ContentBlock::Text {
+                text: String::new(),
+            },
+            BlockKind::Thinking => ContentBlock::Thinking {
+                thinking: String::new(),
+            },
+            // tool use blocks carry their id and name, see `open_tool_use`
+            BlockKind::ToolUse => return,
+        };
+        let index = self.index;
+        Self::emit(
+            StreamEvent::ContentBlockStart {
+                index,
+                content_block,
+            },
+            y,
+        )
+        .await;
+        self.block = Some(kind);
+    }
+
+    /// Open a tool use block, each call gets its own block
+    async fn open_tool_use(
+        &mut self,
+        content_block: ContentBlock,
+        y: &mut Yielder<Result<Event, ClewdrError>>,
+    ) {
+        self.close_block(y).await;
+        // a tool call is content, the response is not empty
+        self.has_text = true;
+        let index = self.index;
+        Self::emit(
+            StreamEvent::ContentBlockStart {
+                index,
+                content_block,
+            },
+            y,
+        )
+        .await;
+        self.block = Some(BlockKind::ToolUse);
+    }
+
+    /// Close the current content block if any
+    async fn close_block(&mut self, y: &mut Yielder<Result<Event, ClewdrError>>) {
+        if self.block.take().is_none() {
+            return;
+        }
+        let index = self.index;
+        Self::emit(StreamEvent::ContentBlockStop { index }, y).await;
+        self.index += 1;
+    }
+
+    async fn parse_buf(&mut self, buf: &str, y: &mut Yielder<Result<Event, ClewdrError>>) {
+        let Ok(parsed) = serde_json::from_str::<Value>(buf) else {
+            return;
+        };
+        if parsed["type"].as_str() == Some("error") {
+            // pass upstream errors through as the terminal event
+            warn!("Upstream stream error: {}", parsed);
+            self.fail(parsed, y).await;
+            return;
+        }
+        self.start(y).await;
+        if self.cut {
+            return;
+        }
+        // keep upstream block boundaries, so distinct blocks keep distinct indices
+        match parsed["type"].as_str() {
+            Some("content_block_start") if parsed["content_block"]["type"] == "tool_use" => {
+                let Ok(block) = serde_json::from_value(parsed["content_block"].clone()) else {
+                    return;
+                };
+                self.end_thinking(y).await;
+                self.open_tool_use(block, y).await;
+                return;
+            }
+            // inline thinking shares the text block with the answer
+            Some("content_block_stop") if self.thinking_tags.is_none() => {
+                self.close_block(y).await;
+                return;
+            }
+            _ => {}
+        }
+        if let Some(partial_json) = parsed
+            .pointer("/delta/partial_json")
+            .and_then(|p| p.as_str())
+        {
+            if self.block == Some(BlockKind::ToolUse) {
+                let delta = ContentBlockDelta::InputJsonDelta {
+                    partial_json: partial_json.to_string(),
+                };
+                let index = self.index;
+                Self::emit(StreamEvent::ContentBlockDelta { index, delta }, y).await;
+            }
+            return;
+        }
+        if let Some(reason) = parsed
+            .get("stop_reason")
+            .or(parsed.pointer("/delta/stop_reason"))
+            .and_then(|r| r.as_str())
+        {
+            let stop = parsed
+                .get("stop")
+                .or(parsed.get("stop_sequence"))
+                .or(parsed.pointer("/delta/stop_sequence"))
+                .and_then(|s| s.as_str());
+            self.set_stop(reason, stop);
+        }
+        if let Some(thinking) = parsed.pointer("/delta/thinking").and_then(|t| t.as_str()) {
+            self.push_thinking(thinking, y).await;
+            return;
+        }
+        let Some(text) = parsed
+            .get("completion")
+            .or(parsed.pointer("/delta/text"))
+            .and_then(|c| c.as_str())
+        else {
+            return;
+        };
+        let text = match self.leading.as_mut() {
+            Some(l) => l.push(text),
+            None => text.to_string(),
+        };
+        let text = match self.prefill.as_mut() {
+            Some(p) => p.push(&text),
+            None => text,
+        };
+        let text = self.cut_turns(&text);
+        let text = match self.markdown.as_mut() {
+            Some(m) => m.push(&text),
+            None => text,
+        };
+        if text.is_empty() {
+            return;
+        }
+        self.end_thinking(y).await;
+        self.emit_text(&text, y).await;
+    }
+
+    /// Emit a text delta in a text block
+    async fn emit_text(&mut self, text: &str, y: &mut Yielder<Result<Event, ClewdrError>>) {
+        self.has_text |= !text.trim().is_empty();
+        self.open_block(BlockKind::Text, y).await;
+        let delta = ContentBlockDelta::TextDelta {
+            text: text.to_string(),
+        };
+        let index = self.index;
+        Self::emit(StreamEvent::ContentBlockDelta { index, delta }, y).await;
+    }
+
+    /// Emit thinking as a native block, as tagged text, or both
+    async fn push_thinking(&mut self, thinking: &str, y: &mut Yielder<Result<Event, ClewdrError>>) {
+        let inline = match self.thinking_tags.as_mut() {
+            Some(tags) if tags.both => {
+                tags.buf.push_str(thinking);
+                None
+            }
+            Some(tags) if tags.inside => Some(thinking.to_string()),
+            Some(tags) => {
+                tags.inside = true;
+                Some(format!("{}{}", tags.open, thinking))
+            }
+            None => None,
+        };
+        if let Some(text) = inline {
+            self.emit_text(&text, y).await;
+            return;
+        }
+        self.open_block(BlockKind::Thinking, y).await;
+        let delta = ContentBlockDelta::ThinkingDelta {
+            thinking: thinking.to_string(),
+        };
+        let index = self.index;
+        Self::emit(StreamEvent::ContentBlockDelta { index, delta }, y).await;
+    }
+
+    /// Close the inline thinking once the answer starts
+    async fn end_thinking(&mut self, y: &mut Yielder<Result<Event, ClewdrError>>) {
+        let text = match self.thinking_tags.as_mut() {
+            Some(tags) if tags.both && !tags.buf.is_empty() => {
+                format!("{}{}{}", tags.open, mem::take(&mut tags.buf), tags.close)
+            }
+            Some(tags) if !tags.both && tags.inside => {
+                tags.inside = false;
+                tags.close.clone()
+            }
+            _ => return,
+        };
+        self.emit_text(&text, y).await;
+    }
+
+    /// Emit an `error` event, the last event of the stream
+    async fn fail(&mut self, envelope: Value, y: &mut Yielder<Result<Event, ClewdrError>>) {
+        self.failed = true;
+        let event = Event::default().event("error").json_data(envelope).unwrap();
+        y.yield_ok(event).await;
+    }
+
+    /// Close the message with `message_delta` and `message_stop`
+    async fn flush(&mut self, y: &mut Yielder<Result<Event, ClewdrError>>) {
+        self.start(y).await;
+        let mut rest = match self.leading.as_mut() {
+            Some(l) if !self.cut => l.finish(),
+            _ => String::new(),
+        };
+        if !self.cut {
+            if let Some(p) = self.prefill.as_mut() {
+                rest = p.push(&rest);
+                rest.push_str(&p.finish());
+            }
+        }
+        let mut held = self.cut_turns(&rest);
+        held.push_str(&mem::take(&mut self.held));
+        if let Some(m) = self.markdown.as_mut() {
+            held = m.push(&held);
+            held.push_str(&m.finish());
+        }
+        if !held.is_empty() {
+            self.end_thinking(y).await;
+            self.emit_text(&held, y).await;
+        }
+        self.end_thinking(y).await;
+        if !self.has_text {
+            match self.empty_policy {
+                EmptyResponsePolicy::Pass => {}
+                EmptyResponsePolicy::Error | EmptyResponsePolicy::Retry => {
+                    warn!("Empty response from Claude.ai");
+                    self.close_block(y).await;
+                    self.fail(ClewdrError::EmptyResponse.error_envelope(), y)
+                        .await;
+                    return;
+                }
+                EmptyResponsePolicy::Placeholder => {
+                    warn!("Empty response from Claude.ai");
+                    let placeholder = mem::take(&mut self.empty_placeholder);
+                    self.emit_text(&placeholder, y).await;
+                }
+            }
+        }
+        self.close_block(y).await;
+        let delta = MessageDeltaContent {
+            stop_reason: Some(self.stop_reason.take().unwrap_or(StopReason::EndTurn)),
+            stop_sequence: self.stop_sequence.take(),
+        };
+        Self::emit(
+            StreamEvent::MessageDelta {
+                delta,
+                usage: Some(StreamUsage {
+                    service_tier: Some(WEB_SERVICE_TIER.to_string()),
+                    ..Default::default()
+                }),
+            },
+            y,
+        )
+        .await;
+        Self::emit(StreamEvent::MessageStop, y).await;
+    }
+
+    pub fn transform_stream<S>(
+        mut self,
+        input: S,
+    ) -> AsyncTryStream<
+        Event,
+        ClewdrError,
+        impl std::future::Future<Output = Result<(), ClewdrError>> + Send,
+    >
+    where
+        S: Stream<Item = Result<eventsource_stream::Event, EventStreamError<rquest::Error>>>
+            + Send
+            + 'static,
+    {
+        AsyncTryStream::new(move |mut y| async move {
+            pin_mut!(input);
+
+            while let Some(chunk) = input.next().await {
+                match chunk {
+                    Ok(event) => {
+                        self.parse_buf(&event.data, &mut y).await;
+                    }
+                    Err(e) => {
+                        // end the stream cleanly instead of dropping the connection
+                        let e = ClewdrError::from(e);
+                        warn!("Stream error: {}", e);
+                        self.fail(e.error_envelope(), &mut y).await;
+                    }
+                }
+                if self.failed {
+                    return Ok(());
+                }
+            }
+            self.flush(&mut y).await;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::{IntoResponse, Sse};
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::Config;
+
+    /// Run Claude.ai completion events through the transformer, returning the SSE events
+    async fn transform(trans: ClaudeTransformer, events: Vec<Value>) -> Vec<(String, Value)> {
+        let input = tokio_stream::iter(events.into_iter().map(|e| {
+            Ok::<_, EventStreamError<rquest::Error>>(eventsource_stream::Event {
+                data: e.to_string(),
+                ..Default::default()
+            })
+        }));
+        let body = Sse::new(trans.transform_stream(input))
+            .into_response()
+            .into_body();
+        let bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        let body = String::from_utf8(bytes.to_vec()).unwrap();
+        body.split("\n\n")
+            .filter(|e| !e.trim().is_empty())
+            .map(|e| {
+                let field = |name: &str| {
+                    e.lines()
+                        .find_map(|l| l.strip_prefix(name))
+                        .unwrap_or_default()
+                        .trim()
+                        .to_string()
+                };
+                let data = serde_json::from_str(&field("data:")).unwrap_or_default();
+                (field("event:"), data)
+            })
+            .collect()
+    }
+
+    fn completion(text: &str) -> Value {
+        json!({ "type": "completion", "completion": text, "stop_reason": null })
+    }
+
+    #[tokio::test]
+    async fn completion_events_get_message_lifecycle() {
+        let events = transform(
+            ClaudeTransformer::new("claude-test".to_string()),
+            vec![completion("Hello"), completion(", world")],
+        )
+        .await;
+        let names = events.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "message_start",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_delta",
+                "content_block_stop",
+                "message_delta",
+                "message_stop",
+            ]
+        );
+        assert_eq!(events[0].1["message"]["model"], "claude-test");
+        assert_eq!(events[2].1["delta"]["text"], "Hello");
+        assert_eq!(events[5].1["delta"]["stop_reason"], "end_turn");
+    }
+
+    #[tokio::test]
+    async fn tool_use_gets_its_own_block() {
+        let events = transform(
+            ClaudeTransformer::new("claude-test".to_string()),
+            vec![
+                completion("Let me check."),
+                json!({ "type": "content_block_stop", "index": 0 }),
+                json!({
+                    "type": "content_block_start",
+                    "index": 1,
+                    "content_block": { "type": "tool_use", "id": "toolu_1", "name": "search", "input": {} },
+                }),
+                json!({
+                    "type": "content_block_delta",
+                    "index": 1,
+                    "delta": { "type": "input_json_delta", "partial_json": "{}" },
+                }),
+                json!({ "type": "content_block_stop", "index": 1 }),
+            ],
+        )
+        .await;
+        let starts = events
+            .iter()
+            .filter(|(n, _)| n == "content_block_start")
+            .map(|(_, d)| (d["index"].clone(), d["content_block"]["type"].clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            starts,
+            [(json!(0), json!("text")), (json!(1), json!("tool_use"))]
+        );
+        let json_delta = events
+            .iter()
+            .find(|(_, d)| d["delta"]["type"] == "input_json_delta")
+            .unwrap();
+        assert_eq!(json_delta.1["index"], 1);
+    }
+
+    #[tokio::test]
+    async fn only_client_stop_sequences_are_reported() {
+        let stop =
+            |seq: &str| json!({ "completion": "", "stop_reason": "stop_sequence", "stop": seq });
+        let trans = || {
+            ClaudeTransformer::new("claude-test".to_string())
+                .with_stop_sequences(vec!["END".to_string()])
+        };
+        let events = transform(trans(), vec![completion("Hi"), stop("END")]).await;
+        let delta = &events[events.len() - 2].1["delta"];
+        assert_eq!(delta["stop_reason"], "stop_sequence");
+        assert_eq!(delta["stop_sequence"], "END");
+        // Claude.ai stopping on its own turn delimiter is a normal end of turn
+        let events = transform(trans(), vec![completion("Hi"), stop("\n\nHuman:")]).await;
+        let delta = &events[events.len() - 2].1["delta"];
+        assert_eq!(delta["stop_reason"], "end_turn");
+        assert!(delta["stop_sequence"].is_null());
+    }
+
+    #[tokio::test]
+    async fn upstream_error_ends_the_stream() {
+        let error = json!({
+            "type": "error",
+            "error": { "type": "overloaded_error", "message": "Overloaded" },
+        });
+        let events = transform(
+            ClaudeTransformer::new("claude-test".to_string()),
+            vec![completion("Hi"), error, completion("lost")],
+        )
+        .await;
+        let (name, data) = events.last().unwrap();
+        assert_eq!(name, "error");
+        assert_eq!(data["error"]["type"], "overloaded_error");
+        assert!(events.iter().all(|(n, _)| n != "message_stop"));
+        assert!(!events.iter().any(|(_, d)| d["delta"]["text"] == "lost"));
+    }
+
+    #[tokio::test]
+    async fn output_is_cut_at_a_turn_split_across_events() {
+        let trans = ClaudeTransformer::new("claude-test".to_string())
+            .with_turn_stops(Config::default().default_stop_sequences());
+        let events = transform(
+            trans,
+            vec![completion("Hello\n\nHu"), completion("man: next turn")],
+        )
+        .await;
+        let text = events
+            .iter()
+            .filter_map(|(_, d)| d["delta"]["text"].as_str())
+            .collect::<String>();
+        assert_eq!(text, "Hello");
+    }
+
+    #[tokio::test]
+    async fn empty_responses_follow_the_policy() {
+        let trans = |policy| {
+            ClaudeTransformer::new("claude-test".to_string()).with_empty_response(policy, "[empty]")
+        };
+        let events = transform(
+            trans(EmptyResponsePolicy::Placeholder),
+            vec![completion(" ")],
+        )
+        .await;
+        assert!(events.iter().any(|(_, d)| d["delta"]["text"] == "[empty]"));
+        assert_eq!(events.last().unwrap().0, "message_stop");
+        let events = transform(trans(EmptyResponsePolicy::Error), vec![completion(" ")]).await;
+        let (name, data) = events.last().unwrap();
+        assert_eq!(name, "error");
+        assert_eq!(
+            data["error"]["message"],
+            ClewdrError::EmptyResponse.to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn client_stop_sequences_are_trimmed() {
+        let trans = ClaudeTransformer::new("claude-test".to_string())
+            .with_stop_sequences(vec!["STOP".to_string()])
+            .with_stop_trimming(true);
+        let events = transform(trans, vec![completion("Done ST"), completion("OP more")]).await;
+        let text = events
+            .iter()
+            .filter_map(|(_, d)| d["delta"]["text"].as_str())
+            .collect::<String>();
+        assert_eq!(text, "Done ");
+        let delta = &events[events.len() - 2].1["delta"];
+        assert_eq!(delta["stop_reason"], "stop_sequence");
+        assert_eq!(delta["stop_sequence"], "STOP");
+    }
+
+    #[test]
+    fn complete_responses_are_cut_at_the_first_stop() {
+        let stops = ["B".to_string(), "A".to_string()];
+        assert_eq!(cut_at_stops("xAyB".to_string(), &stops), "x");
+        let blocks = vec![
+            ContentBlock::Text {
+                text: "oneAtwo".to_string(),
+            },
+            ContentBlock::Text {
+                text: "dropped".to_string(),
+            },
+        ];
+        let cut = cut_blocks_at_stops(blocks, &stops);
+        assert_eq!(cut.len(), 1);
+        assert!(matches!(&cut[0], ContentBlock::Text { text } if text == "one"));
+    }
+
+    #[test]
+    fn turn_stops_follow_the_separator() {
+        let mut config = Config::default();
+        config.turn_separator = "\n".to_string();
+        config.custom_h = Some("User".to_string());
+        assert_eq!(config.default_stop_sequences(), ["\nUser:", "\nAssistant:"]);
+        config.turn_stops = false;
+        assert!(config.default_stop_sequences().is_empty());
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use colored::Colorize;
+use futures::StreamExt;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{error::ClewdrError, messages::api_key, state::AppState};
+
+/// Number of open streams per API key
+#[derive(Clone, Debug, Default)]
+pub struct StreamCounter {
+    active: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl StreamCounter {
+    /// Count a new stream of the key, `None` when the key is at its cap
+    fn acquire(&self, key: &str, limit: usize) -> Option<StreamGuard> {
+        let Ok(mut active) = self.active.lock() else {
+            return Some(StreamGuard {
+                active: self.active.clone(),
+                key: key.to_string(),
+            });
+        };
+        let count = active.entry(key.to_string()).or_default();
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(StreamGuard {
+            active: self.active.clone(),
+            key: key.to_string(),
+        })
+    }
+}
+
+/// An open stream, released when the body ends or the client disconnects
+struct StreamGuard {
+    active: Arc<Mutex<HashMap<String, usize>>>,
+    key: String,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let Ok(mut active) = self.active.lock() else {
+            return;
+        };
+        if let Some(count) = active.get_mut(&self.key) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                active.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Only the `stream` flag of the request body
+#[derive(Deserialize)]
+struct StreamFlag {
+    #[serde(default)]
+    stream: bool,
+}
+
+/// Middleware capping the concurrent streams of an API key
+/// Non-stream requests pass through, they are governed by the queue
+pub async fn stream_limit_layer(State(s): State<AppState>, req: Request, next: Next) -> Response {
+    let key = api_key(req.headers()).to_string();
+    let limit = s.config.stream_limit(&key);
+    if limit == 0 {
+        return next.run(req).await;
+    }
+    let (parts, body) = req.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, s.config.max_body_size).await else {
+        return ClewdrError::RequestTooLarge.into_response();
+    };
+    let stream = serde_json::from_slice::<StreamFlag>(&bytes).is_ok_and(|f| f.stream);
+    let req = Request::from_parts(parts, Body::from(bytes));
+    if !stream {
+        return next.run(req).await;
+    }
+    let Some(guard) = s.streams.acquire(&key, limit) else {
+        warn!(
+            "Too many concurrent streams for a key, limit: {}",
+            limit.to_string().red()
+        );
+        return ClewdrError::TooManyStreams(limit).into_response();
+    };
+    let (parts, body) = next.run(req).await.into_parts();
+    // the guard lives as long as the body stream
+    let body = body.into_data_stream().map(move |chunk| {
+        let _ = &guard;
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
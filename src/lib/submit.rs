@@ -1,8 +1,18 @@
+use std::sync::{Arc, atomic::AtomicBool};
+
 use axum::{Json, extract::State};
 use rquest::StatusCode;
+use serde::{Deserialize, Serialize};
 use tracing::{error, info, warn};
 
-use crate::{config::CookieStatus, messages::Auth, state::AppState};
+use crate::{
+    admin::AdminAuth,
+    config::{CookieStatus, PROBE_MESSAGE},
+    error::ClewdrError,
+    messages::{Auth, ClientRequestBody},
+    state::AppState,
+    types::message::{Message, Role},
+};
 
 pub async fn api_submit(
     State(s): State<AppState>,
@@ -32,3 +42,111 @@ pub async fn api_submit(
         }
     }
 }
+
+/// Body of the cookie test endpoint
+#[derive(Deserialize)]
+pub struct CookieTest {
+    pub cookie: String,
+    /// Model of the test message, the first available model if unset
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Submit the cookie to the pool if it passes the test
+    #[serde(default)]
+    pub add: bool,
+}
+
+/// Result of a cookie test
+#[derive(Serialize, Default)]
+pub struct CookieTestResult {
+    pub valid: bool,
+    pub org_uuid: Option<String>,
+    pub is_pro: bool,
+    pub available_models: Vec<String>,
+    pub error: Option<String>,
+    /// Whether the cookie was submitted to the pool
+    pub added: bool,
+}
+
+impl AppState {
+    /// Bootstrap the cookie and send the first probe message, the cookie never enters the pool
+    async fn test_cookie(
+        &mut self,
+        model: Option<String>,
+        result: &mut CookieTestResult,
+    ) -> Result<(), ClewdrError> {
+        self.bootstrap().await?;
+        result.org_uuid = self.org_uuid.clone();
+        result.is_pro = self.is_pro();
+        result.available_models = self.account_models().await.unwrap_or_else(|e| {
+            warn!("Failed to fetch models of the tested cookie: {}", e);
+            vec![]
+        });
+        let model = model
+            .or_else(|| result.available_models.first().cloned())
+            .ok_or(ClewdrError::InvalidRequest(
+                "no model to send the test message with".to_string(),
+            ))?;
+        let text = self
+            .config
+            .probe_messages
+            .first()
+            .map_or(PROBE_MESSAGE, String::as_str);
+        let p = ClientRequestBody {
+            model,
+            messages: vec![Message::new_text(Role::User, text)],
+            max_tokens: Some(16),
+            ..Default::default()
+        };
+        self.conv_uuid = Some(uuid::Uuid::new_v4().to_string());
+        let res = self.try_message(p, &mut None).await;
+        if let Err(e) = self.delete_chat().await {
+            warn!("Failed to delete chat: {}", e);
+        }
+        let res = res?;
+        if !res.status().is_success() {
+            return Err(ClewdrError::InvalidRequest(format!(
+                "test message failed with status {}",
+                res.status()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Validate a cookie with a throwaway state
+/// The cookie is submitted to the pool only when it passes and `add` is set
+pub async fn api_test_cookie(
+    AdminAuth: AdminAuth,
+    State(s): State<AppState>,
+    Json(req): Json<CookieTest>,
+) -> Json<CookieTestResult> {
+    let mut result = CookieTestResult::default();
+    let cookie = CookieStatus::new(&req.cookie, None, None, None);
+    if !cookie.cookie.validate() {
+        result.error = Some(format!("Invalid cookie format: {}", cookie.cookie));
+        return Json(result);
+    }
+    let mut state = s.clone();
+    // the test must not mark the app ready or reuse the cookies of another request
+    state.ready = Arc::new(AtomicBool::new(false));
+    state.cookie = None;
+    state.keep_chat = false;
+    state.set_cookie(cookie.clone());
+    match state.test_cookie(req.model, &mut result).await {
+        Ok(()) => result.valid = true,
+        Err(e) => {
+            info!("Cookie test failed: {}", e);
+            result.error = Some(e.to_string());
+        }
+    }
+    if result.valid && req.add {
+        match s.submit_tx.send(cookie).await {
+            Ok(_) => {
+                info!("Tested cookie submitted to the pool");
+                result.added = true;
+            }
+            Err(e) => error!("Failed to submit tested cookie: {}", e),
+        }
+    }
+    Json(result)
+}
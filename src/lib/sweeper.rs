@@ -0,0 +1,180 @@
+use std::time::Duration;
+
+use colored::Colorize;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::{
+    client::SetupRequest,
+    error::{ClewdrError, check_res_err},
+    state::AppState,
+};
+
+impl AppState {
+    /// Retry deleting known leaked chats older than the threshold
+    async fn sweep_known(&self) {
+        let now = chrono::Utc::now().timestamp();
+        let age = self.config.sweep_age_secs as i64;
+        let chats = self
+            .pending_chats
+            .lock()
+            .map(|c| {
+                c.iter()
+                    .filter(|c| now - c.created_at > age)
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        for c in chats {
+            if let Err(e) = self
+                .delete_conversation(&c.org_uuid, &c.conv_uuid, c.cookie)
+                .await
+            {
+                warn!("Failed to delete leaked chat {}: {}", c.conv_uuid, e);
+            }
+        }
+    }
+
+    /// List conversations on the current account
+    pub(crate) async fn list_conversations(&self) -> Result<Vec<Value>, ClewdrError> {
+        let Some(ref org_uuid) = self.org_uuid else {
+            return Ok(vec![]);
+        };
+        let cookie = self.header_cookie();
+        let proxy = self.proxy();
+        let res = self
+            .send_upstream(|client, endpoint| {
+                client
+                    .get(format!(
+                        "{}/api/organizations/{}/chat_conversations",
+                        endpoint, org_uuid
+                    ))
+                    .setup_request("", &cookie, proxy.clone())
+            })
+            .await?;
+        let res = check_res_err(res).await?;
+        let convs = res.json::<Value>().await?;
+        Ok(convs.as_array().cloned().unwrap_or_default())
+    }
+
+    /// Delete chats named by clewdr older than the threshold on the current account
+    async fn sweep_account(&self) -> Result<usize, ClewdrError> {
+        let Some(prefix) = self.config.conversation_name_prefix() else {
+            return Ok(0);
+        };
+        let Some(ref org_uuid) = self.org_uuid else {
+            return Ok(0);
+        };
+        let convs = self.list_conversations().await?;
+        let now = chrono::Utc::now();
+        let age = chrono::Duration::seconds(self.config.sweep_age_secs as i64);
+        let leaked = convs
+            .iter()
+            .filter(|c| c["name"].as_str().is_some_and(|n| n.starts_with(prefix)))
+            .filter(|c| {
+                c["created_at"]
+                    .as_str()
+                    .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+                    .is_some_and(|t| now - t.to_utc() > age)
+            })
+            .filter_map(|c| c["uuid"].as_str())
+            .collect::<Vec<_>>();
+        for uuid in leaked.iter() {
+            self.delete_conversation(org_uuid, uuid, self.header_cookie())
+                .await?;
+        }
+        Ok(leaked.len())
+    }
+
+    /// Sweep leaked chats once, borrowing a cookie from the pool
+    async fn sweep(&mut self) -> Result<(), ClewdrError> {
+        self.sweep_known().await;
+        if self.config.conversation_name_prefix().is_none() {
+            return Ok(());
+        }
+        self.request_cookie().await?;
+        let res = async {
+            self.bootstrap().await?;
+            self.sweep_account().await
+        }
+        .await;
+        let reason = match res {
+            Ok(n) => {
+                if n > 0 {
+                    info!("Swept {} leaked chats", n.to_string().green());
+                }
+                None
+            }
+            Err(ClewdrError::InvalidCookie(r)) => Some(r),
+            Err(e) => {
+                warn!("Failed to sweep chats: {}", e);
+                None
+            }
+        };
+        self.return_cookie(reason).await;
+        Ok(())
+    }
+}
+
+/// Interval between retries of chats that failed to delete
+const DELETE_RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+impl AppState {
+    /// Retry deleting chats queued after failed deletes
+    async fn retry_failed_chats(&self) {
+        let chats = self
+            .failed_chats
+            .lock()
+            .map(|c| c.iter().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        if chats.is_empty() {
+            return;
+        }
+        let mut deleted = 0;
+        for c in chats {
+            match self
+                .delete_conversation(&c.org_uuid, &c.conv_uuid, c.cookie.clone())
+                .await
+            {
+                Ok(_) => {
+                    deleted += 1;
+                    if let Ok(mut chats) = self.failed_chats.lock() {
+                        chats.remove(&c);
+                    }
+                }
+                Err(e) => warn!("Failed to delete chat {}: {}", c.conv_uuid, e),
+            }
+        }
+        if deleted > 0 {
+            info!("Deleted {} queued chats", deleted.to_string().green());
+            self.save_failed_chats();
+        }
+    }
+}
+
+/// Periodically retry chats that failed to delete
+pub async fn run_delete_retry(state: AppState) {
+    let mut interval = tokio::time::interval(DELETE_RETRY_INTERVAL);
+    loop {
+        interval.tick().await;
+        state.retry_failed_chats().await;
+    }
+}
+
+/// Periodically delete chats that were not cleaned up
+pub async fn run_sweeper(state: AppState) {
+    let secs = state.config.sweep_interval_secs;
+    if secs == 0 {
+        return;
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(secs));
+    // skip the immediate first tick
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let mut state = state.clone();
+        if let Err(e) = state.sweep().await {
+            warn!("Sweeper error: {}", e);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::{collections::HashMap, sync::LazyLock};
+
+use chrono::Local;
+use regex::{Captures, Regex};
+use serde_json::Value;
+
+use crate::{
+    config::Config,
+    error::ClewdrError,
+    messages::ClientRequestBody,
+    types::message::{ContentBlock, MessageContent},
+};
+
+/// `{{name}}` token in message content
+static VARIABLE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{\{\s*([A-Za-z0-9_]+)\s*\}\}").unwrap());
+
+/// Value of a built-in variable
+fn builtin(name: &str, model: &str) -> Option<String> {
+    let now = Local::now();
+    let value = match name {
+        "date" => now.format("%Y-%m-%d").to_string(),
+        "time" => now.format("%H:%M:%S").to_string(),
+        "datetime" => now.format("%Y-%m-%d %H:%M:%S").to_string(),
+        "weekday" => now.format("%A").to_string(),
+        "model" => model.to_string(),
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Variables of a single request, request values override configured ones
+struct Variables<'a> {
+    request: &'a HashMap<String, String>,
+    config: &'a HashMap<String, String>,
+    model: &'a str,
+    strict: bool,
+    unknown: Option<String>,
+}
+
+impl Variables<'_> {
+    fn get(&self, name: &str) -> Option<String> {
+        self.request
+            .get(name)
+            .or_else(|| self.config.get(name))
+            .cloned()
+            .or_else(|| builtin(name, self.model))
+    }
+
+    /// Replace every known token, unknown ones are kept as is
+    fn render(&mut self, text: &mut String) {
+        if !text.contains("{{") {
+            return;
+        }
+        let rendered = VARIABLE_RE.replace_all(text, |c: &Captures| match self.get(&c[1]) {
+            Some(v) => v,
+            None => {
+                if self.strict && self.unknown.is_none() {
+                    self.unknown = Some(c[1].to_string());
+                }
+                c[0].to_string()
+            }
+        });
+        *text = rendered.into_owned();
+    }
+}
+
+impl ClientRequestBody {
+    /// Substitute `{{variables}}` in the system prompt and messages
+    /// Fails on unknown variables when `strict_variables` is set
+    pub fn render_variables(&mut self, config: &Config) -> Result<(), ClewdrError> {
+        if !config.templating {
+            return Ok(());
+        }
+        let mut vars = Variables {
+            request: &self.variables,
+            config: &config.template_variables,
+            model: &self.model,
+            strict: config.strict_variables,
+            unknown: None,
+        };
+        match &mut self.system {
+            Value::String(s) => vars.render(s),
+            Value::Array(blocks) => {
+                for b in blocks {
+                    if let Some(Value::String(s)) = b.get_mut("text") {
+                        vars.render(s);
+                    }
+                }
+            }
+            _ => {}
+        }
+        for m in self.messages.iter_mut() {
+            match &mut m.content {
+                MessageContent::Text { content } => vars.render(content),
+                MessageContent::Blocks { content } => {
+                    for b in content {
+                        if let ContentBlock::Text { text } = b {
+                            vars.render(text);
+                        }
+                    }
+                }
+            }
+        }
+        match vars.unknown {
+            Some(name) => Err(ClewdrError::InvalidRequest(format!(
+                "unknown template variable `{}`",
+                name
+            ))),
+            None => Ok(()),
+        }
+    }
+}
@@ -7,14 +7,21 @@ use itertools::Itertools;
 use rand::{Rng, rng};
 use serde_json::Value;
 use std::fmt::Write;
+use std::mem;
+use tracing::debug;
 use tracing::error;
+use tracing::info;
 use tracing::warn;
 
 use crate::{
-    messages::{Attachment, ClientRequestBody, RequestBody},
+    config::{JailbreakPosition, SystemMode, TrimStrategy},
+    error::{ClewdrError, parse_prompt_too_long},
+    json_mode::json_instruction,
+    messages::{Attachment, ClientRequestBody, DEFAULT_MAX_TOKENS, RequestBody},
     state::AppState,
+    tools::{render_tool_block, tool_prompt},
     types::message::{ContentBlock, ImageSource, Message, MessageContent, Role},
-    utils::{TIME_ZONE, print_out_text},
+    utils::print_out_text,
 };
 
 /// Merged messages and images
@@ -27,12 +34,65 @@ pub struct Merged {
 
 impl AppState {
     /// Transform the request body from Claude API to Claude web
-    pub fn transform_anthropic(&self, value: ClientRequestBody) -> Option<RequestBody> {
-        let system = merge_system(value.system);
-        let merged = self.merge_messages(value.messages, system)?;
+    pub fn transform_anthropic(&self, mut value: ClientRequestBody) -> Option<RequestBody> {
+        self.apply_profile(&mut value);
+        if let Some(prompt) = value.raw_prompt.take() {
+            return self.transform_raw(value, prompt);
+        }
+        if let Some(tier) = value.service_tier.as_deref() {
+            debug!(
+                "Claude.ai has no service tiers, ignoring service_tier `{}`",
+                tier
+            );
+        }
+        let mode = self.config.system_mode;
+        // system messages are merged in order after the `system` field
+        let (system_msgs, msgs): (Vec<_>, Vec<_>) = self
+            .drop_ignored_system(value.messages)
+            .into_iter()
+            .partition(|m| m.role == Role::System && !m.jailbreak);
+        let mut system = [merge_system(value.system)]
+            .into_iter()
+            .chain(system_msgs.iter().map(message_text))
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .join(&self.config.system_separator);
+        if mode == SystemMode::Discard || self.is_ignored_system(&system) {
+            system.clear();
+        }
+        let mut system = self.wrap_system(system);
+        if !value.tools.is_empty() {
+            let tools = tool_prompt(&value.tools, value.tool_choice.as_ref());
+            system = format!("{}\n\n{}", tools, system);
+        }
+        if let Some(json) = value.response_format.as_ref().and_then(json_instruction) {
+            system = format!("{}\n\n{}", system, json);
+        }
+        let extended = value.has_beta("context-1m");
+        if value.has_beta("prompt-caching") {
+            // Claude.ai does not support prompt caching, ignore it
+            debug!("Prompt caching beta is not supported, ignoring");
+        }
+        // jailbreaks are kept out of trimming and placed afterwards
+        let (jailbreaks, msgs): (Vec<_>, Vec<_>) = msgs.into_iter().partition(|m| m.jailbreak);
+        let msgs = self.cap_history(msgs);
+        let msgs = self.trim_messages(msgs, &system, &value.model, extended, value.trim_limit);
+        let msgs = self.place_jailbreaks(msgs, jailbreaks);
+        let (system, native_system) = match mode {
+            SystemMode::NativeSystem => (
+                String::new(),
+                Some(system.trim().to_string()).filter(|s| !s.is_empty()),
+            ),
+            _ => (system, None),
+        };
+        let merged = self.merge_messages(msgs, system)?;
+        let (attachments, prompt) = self.split_paste(merged.paste, merged.prompt);
+        let timezone = self
+            .config
+            .timezone_for(value.timezone.as_deref(), &value.model);
         Some(RequestBody {
-            max_tokens_to_sample: value.max_tokens,
-            attachments: vec![Attachment::new(merged.paste)],
+            max_tokens_to_sample: value.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            attachments,
             files: vec![],
             model: if self.is_pro() {
                 Some(value.model)
@@ -44,14 +104,24 @@ impl AppState {
             } else {
                 "raw".to_string()
             },
-            prompt: merged.prompt,
-            timezone: TIME_ZONE.to_string(),
+            prompt,
+            system: native_system,
+            timezone,
+            temperature: value.temperature.filter(|_| self.config.pass_params),
+            top_p: value.top_p.filter(|_| self.config.pass_params),
+            top_k: value.top_k.filter(|_| self.config.pass_params),
             images: merged.images,
         })
     }
 
     /// Transform the request body from Claude web to OAI API
     pub fn transform_oai(&self, mut value: ClientRequestBody) -> Option<RequestBody> {
+        self.apply_profile(&mut value);
+        let msgs = mem::take(&mut value.messages);
+        let extended = value.has_beta("context-1m");
+        let msgs = self.drop_ignored_system(msgs);
+        let msgs = self.cap_history(msgs);
+        value.messages = self.trim_messages(msgs, "", &value.model, extended, value.trim_limit);
         let mut role = value.messages.first().map(|m| m.role)?;
         for msg in value.messages.iter_mut() {
             if msg.role != Role::System {
@@ -60,10 +130,25 @@ impl AppState {
                 msg.role = role;
             }
         }
-        let merged = self.merge_messages(value.messages, String::new())?;
+        let system = if value.tools.is_empty() {
+            String::new()
+        } else {
+            tool_prompt(&value.tools, value.tool_choice.as_ref())
+        };
+        let system = match value.response_format.as_ref().and_then(json_instruction) {
+            Some(json) if system.is_empty() => json,
+            Some(json) => format!("{}\n\n{}", system, json),
+            None => system,
+        };
+        let system = self.wrap_system(system);
+        let merged = self.merge_messages(value.messages, system)?;
+        let (attachments, prompt) = self.split_paste(merged.paste, merged.prompt);
+        let timezone = self
+            .config
+            .timezone_for(value.timezone.as_deref(), &value.model);
         Some(RequestBody {
-            max_tokens_to_sample: value.max_tokens,
-            attachments: vec![Attachment::new(merged.paste)],
+            max_tokens_to_sample: value.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            attachments,
             files: vec![],
             model: if self.is_pro() {
                 Some(value.model)
@@ -71,26 +156,238 @@ impl AppState {
                 None
             },
             rendering_mode: "raw".to_string(),
-            prompt: merged.prompt,
-            timezone: TIME_ZONE.to_string(),
+            prompt,
+            system: None,
+            timezone,
+            temperature: value.temperature.filter(|_| self.config.pass_params),
+            top_p: value.top_p.filter(|_| self.config.pass_params),
+            top_k: value.top_k.filter(|_| self.config.pass_params),
             images: merged.images,
         })
     }
 
+    /// Send a pre-assembled prompt as is, only adding padding
+    fn transform_raw(&self, value: ClientRequestBody, prompt: String) -> Option<RequestBody> {
+        let prompt = prompt.trim();
+        if prompt.is_empty() {
+            return None;
+        }
+        let mut paste = String::new();
+        if !self.config.pad_tokens.is_empty() {
+            paste += self.generate_padding(self.config.padtxt_len).as_str();
+        }
+        paste += prompt;
+        print_out_text(paste.as_str(), "paste.txt");
+        let (attachments, prompt) = self.split_paste(paste, self.config.custom_prompt.clone());
+        let timezone = self
+            .config
+            .timezone_for(value.timezone.as_deref(), &value.model);
+        Some(RequestBody {
+            max_tokens_to_sample: value.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            attachments,
+            files: vec![],
+            model: if self.is_pro() {
+                Some(value.model)
+            } else {
+                None
+            },
+            rendering_mode: if value.stream {
+                "messages".to_string()
+            } else {
+                "raw".to_string()
+            },
+            prompt,
+            system: None,
+            timezone,
+            temperature: value.temperature.filter(|_| self.config.pass_params),
+            top_p: value.top_p.filter(|_| self.config.pass_params),
+            top_k: value.top_k.filter(|_| self.config.pass_params),
+            images: vec![],
+        })
+    }
+
+    /// Split the pasted history into attachments, pointing the prompt at them in order
+    fn split_paste(&self, paste: String, prompt: String) -> (Vec<Attachment>, String) {
+        let attachments = Attachment::split(paste, self.config.attachment_max_bytes);
+        let Some(pointer) = Attachment::pointer(&attachments) else {
+            return (attachments, prompt);
+        };
+        debug!("Prompt split into {} attachments", attachments.len());
+        let prompt = [pointer.as_str(), prompt.trim()]
+            .into_iter()
+            .filter(|s| !s.is_empty())
+            .join("\n\n");
+        (attachments, prompt)
+    }
+
+    /// Wrap the system prompt with the configured prepend and append text
+    fn wrap_system(&self, system: String) -> String {
+        [
+            self.config.system_prepend.trim(),
+            system.trim(),
+            self.config.system_append.trim(),
+        ]
+        .into_iter()
+        .filter(|s| !s.is_empty())
+        .join("\n\n")
+    }
+
+    /// Fill parameters omitted by the client from the model profile
+    fn apply_profile(&self, value: &mut ClientRequestBody) {
+        let Some(profile) = self.config.model_profiles.get(&value.model) else {
+            return;
+        };
+        value.temperature = value.temperature.or(profile.temperature);
+        value.top_p = value.top_p.or(profile.top_p);
+        value.top_k = value.top_k.or(profile.top_k);
+        value.max_tokens = value.max_tokens.or(profile.max_tokens);
+    }
+
+    /// Check if the system text is one of the configured sentinels
+    fn is_ignored_system(&self, text: &str) -> bool {
+        let text = text.trim();
+        self.config
+            .ignored_system_prompts
+            .iter()
+            .any(|s| s.trim() == text)
+    }
+
+    /// Remove system messages that only contain a sentinel like `[Start a new chat]`
+    fn drop_ignored_system(&self, mut msgs: Vec<Message>) -> Vec<Message> {
+        msgs.retain(|m| m.role != Role::System || !self.is_ignored_system(&message_text(m)));
+        msgs
+    }
+
+    /// Keep system messages, the first user turn and the latest messages
+    /// up to `max_history_messages`, dropping the middle of the history
+    fn cap_history(&self, mut msgs: Vec<Message>) -> Vec<Message> {
+        let max = self.config.max_history_messages;
+        if max == 0 {
+            return msgs;
+        }
+        let turns = msgs.iter().filter(|m| m.role != Role::System).count();
+        if turns <= max {
+            return msgs;
+        }
+        let first_user = msgs.iter().position(|m| m.role == Role::User);
+        let skip = turns - max;
+        let mut turn = 0;
+        let mut keep = Vec::with_capacity(msgs.len());
+        for (i, m) in msgs.iter().enumerate() {
+            if m.role == Role::System {
+                keep.push(true);
+                continue;
+            }
+            keep.push(turn >= skip || Some(i) == first_user);
+            turn += 1;
+        }
+        info!(
+            "Dropped {} messages over max history length {}",
+            keep.iter().filter(|k| !**k).count(),
+            max
+        );
+        let mut keep = keep.into_iter();
+        msgs.retain(|_| keep.next().unwrap_or(true));
+        msgs
+    }
+
+    /// Put jailbreak messages at the configured position
+    fn place_jailbreaks(&self, mut msgs: Vec<Message>, jailbreaks: Vec<Message>) -> Vec<Message> {
+        if jailbreaks.is_empty() {
+            return msgs;
+        }
+        let at = match self.config.jailbreak_position {
+            JailbreakPosition::PostHistory => msgs.len(),
+            JailbreakPosition::PrePrompt => msgs
+                .iter()
+                .position(|m| m.role != Role::System)
+                .unwrap_or(msgs.len()),
+        };
+        msgs.splice(at..at, jailbreaks);
+        msgs
+    }
+
+    /// Drop messages until the prompt fits in the context window of the model
+    /// or the forced limit, the first system message and the last user message are always kept
+    fn trim_messages(
+        &self,
+        mut msgs: Vec<Message>,
+        system: &str,
+        model: &str,
+        extended: bool,
+        forced: Option<usize>,
+    ) -> Vec<Message> {
+        let limit = if forced.is_some() {
+            forced
+        } else if extended {
+            self.config.extended_context_limit(model)
+        } else {
+            self.config.context_limit(model)
+        };
+        let Some(limit) = limit else {
+            return msgs;
+        };
+        let tokenizer = self.config.tokenizer.tokenizer();
+        let counts = msgs
+            .iter()
+            .map(|m| tokenizer.count(&message_text(m)))
+            .collect::<Vec<_>>();
+        let mut total = tokenizer.count(system) + counts.iter().sum::<usize>();
+        if !self.config.pad_tokens.is_empty() {
+            total += self.config.padtxt_len;
+        }
+        if total <= limit {
+            return msgs;
+        }
+        let first_system = msgs
+            .first()
+            .is_some_and(|m| m.role == Role::System)
+            .then_some(0);
+        let last_user = msgs.iter().rposition(|m| m.role == Role::User);
+        let candidates = (0..msgs.len())
+            .filter(|i| Some(*i) != first_system && Some(*i) != last_user)
+            .collect::<Vec<_>>();
+        let order = match self.config.trim_strategy {
+            TrimStrategy::OldestFirst => candidates,
+            TrimStrategy::MiddleOut => middle_out(candidates),
+        };
+        let mut dropped = vec![false; msgs.len()];
+        let mut trimmed_msgs = 0;
+        let mut trimmed_tokens = 0;
+        for i in order {
+            if total <= limit {
+                break;
+            }
+            dropped[i] = true;
+            total -= counts[i];
+            trimmed_msgs += 1;
+            trimmed_tokens += counts[i];
+        }
+        info!(
+            "Trimmed {} messages, {} tokens to fit context window of {}",
+            trimmed_msgs, trimmed_tokens, limit
+        );
+        if total > limit {
+            warn!("Prompt still exceeds context window: {} > {}", total, limit);
+        }
+        let mut dropped = dropped.into_iter();
+        msgs.retain(|_| !dropped.next().unwrap_or_default());
+        msgs
+    }
+
     /// Merge messages into strings and extract images
     fn merge_messages(&self, msgs: Vec<Message>, system: String) -> Option<Merged> {
         if msgs.is_empty() {
             return None;
         }
-        let h = self.config.custom_h.clone().unwrap_or("Human".to_string());
-        let a = self
-            .config
-            .custom_a
-            .clone()
-            .unwrap_or("Assistant".to_string());
-
-        let user_real_roles = self.config.use_real_roles;
-        let line_breaks = if user_real_roles { "\n\n\x08" } else { "\n\n" };
+        let (h, a) = self.config.role_names();
+        // real roles mark the separator so Claude.ai does not parse it as a turn
+        let marker = if self.config.use_real_roles {
+            "\x08"
+        } else {
+            ""
+        };
+        let separator = format!("{}{}", self.config.turn_separator, marker);
         let system = system.trim().to_string();
         let size = size_of_val(&msgs);
         // preallocate string to avoid reallocations
@@ -106,19 +403,28 @@ impl AppState {
 
         let chunks = msgs
             .into_iter()
-            .map_while(|m| match m.content {
+            .map(|mut m| {
+                // system messages left in the history, such as jailbreaks, are sent as user text
+                if m.role == Role::System {
+                    m.role = Role::User;
+                }
+                m
+            })
+            .filter_map(|m| match m.content {
                 MessageContent::Blocks { content } => {
                     // collect all text blocks, join them with new line
                     let blocks = content
                         .into_iter()
-                        .map_while(|b| match b {
+                        .filter_map(|b| match b {
                             ContentBlock::Text { text } => Some(text.trim().to_string()),
                             ContentBlock::Image { source } => {
                                 // push image to the list
                                 imgs.push(source);
                                 None
                             }
-                            _ => None,
+                            // thinking of replayed history is not part of the conversation
+                            ContentBlock::Thinking { .. } => None,
+                            b => render_tool_block(&b),
                         })
                         .collect::<Vec<_>>()
                         .join("\n");
@@ -145,23 +451,28 @@ impl AppState {
             let txt = grp.into_iter().map(|m| m.1).collect::<Vec<_>>().join("\n");
             (role, txt)
         });
+        // the sentinel marks where the history of the new conversation starts
+        let head = if self.config.inject_new_chat {
+            [system.as_str(), self.config.new_chat_sentinel.trim()]
+                .into_iter()
+                .filter(|s| !s.is_empty())
+                .join("\n\n")
+        } else {
+            system
+        };
         // first message does not need prefix
-        if !system.is_empty() {
-            w += system.as_str();
+        if !head.is_empty() {
+            w += head.as_str();
         } else {
             let first = msgs.next()?;
             w += first.1.as_str();
         }
         for (role, text) in msgs {
             let prefix = match role {
-                Role::System => {
-                    warn!("System message should be merged into the first message");
-                    continue;
-                }
-                Role::User => format!("{}: ", h),
-                Role::Assistant => format!("{}: ", a),
+                Role::System | Role::User => h,
+                Role::Assistant => a,
             };
-            write!(w, "{}{}{}", line_breaks, prefix, text).unwrap();
+            write!(w, "{}{}: {}", separator, prefix, text).unwrap();
         }
         print_out_text(w.as_str(), "paste.txt");
 
@@ -209,6 +520,63 @@ impl AppState {
         result.push_str("\n\n");
         result
     }
+
+    /// Approximate token count of the prompt of a request
+    pub fn estimate_tokens(&self, p: &ClientRequestBody) -> usize {
+        let tokenizer = self.config.tokenizer.tokenizer();
+        tokenizer.count(&merge_system(p.system.clone()))
+            + p.messages
+                .iter()
+                .map(|m| tokenizer.count(&message_text(m)))
+                .sum::<usize>()
+    }
+
+    /// Token budget to retry with after Claude.ai rejected the prompt as too long
+    /// Scales the local estimate by the ratio reported upstream, with some headroom
+    pub fn retry_trim_limit(&self, p: &ClientRequestBody, msg: &str) -> usize {
+        let estimate = self.estimate_tokens(p);
+        match parse_prompt_too_long(msg) {
+            Some((tokens, limit)) if tokens > 0 => estimate * limit / tokens * 9 / 10,
+            _ => estimate * 8 / 10,
+        }
+    }
+
+    /// Error for a prompt that is still too long, with the approximate token count
+    pub fn prompt_too_long(&self, p: &ClientRequestBody, msg: &str) -> ClewdrError {
+        ClewdrError::PromptTooLong(format!("about {} tokens, {}", self.estimate_tokens(p), msg))
+    }
+}
+
+/// Text content of a message, used for token counting
+pub(crate) fn message_text(msg: &Message) -> String {
+    match &msg.content {
+        MessageContent::Text { content } => content.clone(),
+        MessageContent::Blocks { content } => content
+            .iter()
+            .filter_map(|b| match b {
+                ContentBlock::Text { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .join("\n"),
+    }
+}
+
+/// Reorder indices from the middle outwards
+fn middle_out(indices: Vec<usize>) -> Vec<usize> {
+    let (left, right) = indices.split_at(indices.len() / 2);
+    let mut left = left.iter().rev();
+    let mut right = right.iter();
+    let mut order = Vec::with_capacity(indices.len());
+    loop {
+        match (right.next(), left.next()) {
+            (None, None) => break,
+            (r, l) => {
+                order.extend(r);
+                order.extend(l);
+            }
+        }
+    }
+    order
 }
 
 /// Merge system message into a string
@@ -227,30 +595,317 @@ fn merge_system(sys: Value) -> String {
         .join("\n")
 }
 
+/// Merge Claude.ai events into the text of the response
 pub async fn merge_sse(
     stream: EventStream<impl Stream<Item = Result<Bytes, rquest::Error>>>,
 ) -> String {
+    blocks_text(&merge_sse_blocks(stream).await)
+}
+
+/// Concatenated text of the text blocks
+pub fn blocks_text(blocks: &[ContentBlock]) -> String {
+    blocks
+        .iter()
+        .filter_map(|b| match b {
+            ContentBlock::Text { text } => Some(text.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Content blocks being merged from Claude.ai events
+#[derive(Default)]
+struct BlockMerger {
+    blocks: Vec<ContentBlock>,
+    /// The last block is still receiving deltas
+    open: bool,
+    /// Partial JSON input of the open tool use block
+    input: String,
+}
+
+impl BlockMerger {
+    /// Append raw completion text, continuing the open text block
+    fn push_text(&mut self, text: &str) {
+        match self.blocks.last_mut() {
+            Some(ContentBlock::Text { text: t }) if self.open => t.push_str(text),
+            _ => {
+                self.close();
+                self.blocks.push(ContentBlock::Text {
+                    text: text.to_string(),
+                });
+                self.open = true;
+            }
+        }
+    }
+
+    fn start(&mut self, block: &Value) {
+        self.close();
+        let Ok(block) = serde_json::from_value::<ContentBlock>(block.clone()) else {
+            debug!("Unknown content block: {}", block);
+            return;
+        };
+        self.blocks.push(block);
+        self.open = true;
+    }
+
+    fn delta(&mut self, delta: &Value) {
+        if !self.open {
+            return;
+        }
+        match (self.blocks.last_mut(), delta["type"].as_str()) {
+            (Some(ContentBlock::Text { text }), Some("text_delta")) => {
+                text.push_str(delta["text"].as_str().unwrap_or_default())
+            }
+            (Some(ContentBlock::Thinking { thinking }), Some("thinking_delta")) => {
+                thinking.push_str(delta["thinking"].as_str().unwrap_or_default())
+            }
+            (Some(ContentBlock::ToolUse { .. }), Some("input_json_delta")) => self
+                .input
+                .push_str(delta["partial_json"].as_str().unwrap_or_default()),
+            _ => {}
+        }
+    }
+
+    /// Finish the open block, parsing the input of a tool use
+    fn close(&mut self) {
+        if !mem::take(&mut self.open) {
+            return;
+        }
+        let input = mem::take(&mut self.input);
+        if let Some(ContentBlock::ToolUse { input: i, .. }) = self.blocks.last_mut() {
+            if !input.is_empty() {
+                *i = serde_json::from_str(&input).unwrap_or_else(|_| {
+                    warn!("Invalid tool input: {}", input);
+                    Value::Object(Default::default())
+                });
+            }
+        }
+    }
+}
+
+/// Merge Claude.ai events into content blocks, keeping the boundaries of upstream blocks
+pub async fn merge_sse_blocks(
+    stream: EventStream<impl Stream<Item = Result<Bytes, rquest::Error>>>,
+) -> Vec<ContentBlock> {
     pin_mut!(stream);
-    let mut w = String::new();
+    let mut m = BlockMerger::default();
     while let Some(event) = stream.next().await {
-        match event {
-            Ok(event) => {
-                if event.event != "completion" {
-                    continue;
-                }
-                let data = event.data;
-                let Ok(json) = serde_json::from_str::<Value>(&data) else {
-                    error!("Failed to parse JSON: {}", data);
-                    continue;
-                };
-                let Some(completion) = json["completion"].as_str() else {
-                    error!("Failed to get completion from JSON: {}", json);
-                    continue;
-                };
-                w += completion;
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                error!("Stream Error: {}", e);
+                continue;
             }
-            Err(e) => error!("Stream Error: {}", e),
+        };
+        let Ok(json) = serde_json::from_str::<Value>(&event.data) else {
+            error!("Failed to parse JSON: {}", event.data);
+            continue;
+        };
+        if event.event == "completion" {
+            let Some(completion) = json["completion"].as_str() else {
+                error!("Failed to get completion from JSON: {}", json);
+                continue;
+            };
+            m.push_text(completion);
+            continue;
+        }
+        match json["type"].as_str() {
+            Some("content_block_start") => m.start(&json["content_block"]),
+            Some("content_block_delta") => m.delta(&json["delta"]),
+            Some("content_block_stop") => m.close(),
+            _ => {}
         }
     }
-    w
+    m.close();
+    m.blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use eventsource_stream::Eventsource;
+    use serde_json::json;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::config::Config;
+
+    fn state(config: Config) -> AppState {
+        let (req_tx, _) = mpsc::channel(1);
+        let (ret_tx, _) = mpsc::channel(1);
+        let (submit_tx, _) = mpsc::channel(1);
+        AppState::new(config, req_tx, ret_tx, submit_tx)
+    }
+
+    #[test]
+    fn system_prompt_is_wrapped() {
+        let mut config = Config::default();
+        config.system_prepend = "Before".to_string();
+        config.system_append = " After ".to_string();
+        let state = state(config);
+        assert_eq!(
+            state.wrap_system("Main".to_string()),
+            "Before\n\nMain\n\nAfter"
+        );
+        assert_eq!(state.wrap_system(String::new()), "Before\n\nAfter");
+    }
+
+    #[test]
+    fn ignored_system_prompts_are_dropped() {
+        let msgs = || {
+            vec![
+                Message::new_text(Role::System, " [Start a new chat] "),
+                Message::new_text(Role::System, "Be brief."),
+                Message::new_text(Role::User, "[Start a new chat]"),
+            ]
+        };
+        let kept = state(Config::default()).drop_ignored_system(msgs());
+        let texts = kept.iter().map(message_text).collect::<Vec<_>>();
+        assert_eq!(texts, ["Be brief.", "[Start a new chat]"]);
+
+        let mut config = Config::default();
+        config.ignored_system_prompts.clear();
+        assert_eq!(state(config).drop_ignored_system(msgs()).len(), 3);
+    }
+
+    #[test]
+    fn system_mode_routes_the_system_prompt() {
+        let transform = |mode| {
+            let mut config = Config::default();
+            config.system_mode = mode;
+            let request = ClientRequestBody {
+                messages: vec![Message::new_text(Role::User, "Hi")],
+                system: json!("Be brief."),
+                model: "claude-test".to_string(),
+                ..Default::default()
+            };
+            let mut body = state(config).transform_anthropic(request).unwrap();
+            let native = body.system.take();
+            (native, serde_json::to_string(&body).unwrap())
+        };
+        let (native, sent) = transform(SystemMode::MergeIntoPrompt);
+        assert!(native.is_none() && sent.contains("Be brief."));
+        let (native, sent) = transform(SystemMode::NativeSystem);
+        assert_eq!(native.as_deref(), Some("Be brief."));
+        assert!(!sent.contains("Be brief."));
+        let (native, sent) = transform(SystemMode::Discard);
+        assert!(native.is_none() && !sent.contains("Be brief."));
+    }
+
+    #[test]
+    fn system_messages_join_the_system_prompt() {
+        let mut config = Config::default();
+        config.system_mode = SystemMode::NativeSystem;
+        config.system_separator = "\n---\n".to_string();
+        let request = ClientRequestBody {
+            messages: vec![
+                Message::new_text(Role::System, "B"),
+                Message::new_text(Role::User, "Hi"),
+                Message::new_text(Role::System, "C"),
+            ],
+            system: json!("A"),
+            model: "claude-test".to_string(),
+            ..Default::default()
+        };
+        let body = state(config).transform_anthropic(request).unwrap();
+        assert_eq!(body.system.as_deref(), Some("A\n---\nB\n---\nC"));
+    }
+
+    #[tokio::test]
+    async fn merged_response_keeps_distinct_blocks() {
+        let delta = |index: usize, delta: Value| json!({ "type": "content_block_delta", "index": index, "delta": delta });
+        let events = [
+            json!({
+                "type": "content_block_start",
+                "index": 0,
+                "content_block": { "type": "text", "text": "" },
+            }),
+            delta(0, json!({ "type": "text_delta", "text": "Let me check." })),
+            json!({ "type": "content_block_stop", "index": 0 }),
+            json!({
+                "type": "content_block_start",
+                "index": 1,
+                "content_block": { "type": "tool_use", "id": "toolu_1", "name": "search", "input": {} },
+            }),
+            delta(
+                1,
+                json!({ "type": "input_json_delta", "partial_json": "{\"q\":" }),
+            ),
+            delta(
+                1,
+                json!({ "type": "input_json_delta", "partial_json": "\"rust\"}" }),
+            ),
+            json!({ "type": "content_block_stop", "index": 1 }),
+        ];
+        let sse = events
+            .iter()
+            .map(|e| format!("event: {}\ndata: {}\n\n", e["type"].as_str().unwrap(), e))
+            .collect::<String>();
+        let stream =
+            futures::stream::iter([Ok::<_, rquest::Error>(Bytes::from(sse))]).eventsource();
+        let blocks = merge_sse_blocks(stream).await;
+        assert_eq!(blocks.len(), 2);
+        assert!(matches!(&blocks[0], ContentBlock::Text { text } if text == "Let me check."));
+        assert!(matches!(
+            &blocks[1],
+            ContentBlock::ToolUse { name, input, .. } if name == "search" && input["q"] == "rust"
+        ));
+    }
+
+    #[test]
+    fn new_chat_sentinel_follows_the_system_prompt() {
+        let paste = |inject| {
+            let mut config = Config::default();
+            config.inject_new_chat = inject;
+            let msgs = vec![Message::new_text(Role::User, "Hi")];
+            state(config)
+                .merge_messages(msgs, "Sys".to_string())
+                .unwrap()
+                .paste
+        };
+        assert!(paste(true).starts_with("Sys\n\n[Start a new chat]"));
+        assert!(paste(true).ends_with("Human: Hi"));
+        assert!(!paste(false).contains("[Start a new chat]"));
+    }
+
+    #[test]
+    fn replayed_thinking_is_skipped_without_cutting_history() {
+        let answer = Message::new_blocks(
+            Role::Assistant,
+            vec![
+                ContentBlock::Thinking {
+                    thinking: "Hidden".to_string(),
+                },
+                ContentBlock::Text {
+                    text: "Hello".to_string(),
+                },
+            ],
+        );
+        let msgs = vec![
+            Message::new_text(Role::User, "Hi"),
+            answer,
+            Message::new_text(Role::User, "Again"),
+        ];
+        let merged = state(Config::default())
+            .merge_messages(msgs, String::new())
+            .unwrap();
+        assert!(!merged.paste.contains("Hidden"));
+        assert!(merged.paste.contains("Assistant: Hello"));
+        assert!(merged.paste.ends_with("Human: Again"));
+    }
+
+    #[test]
+    fn system_jailbreak_reaches_prompt() {
+        let state = state(Config::default());
+        let jailbreak = Message {
+            jailbreak: true,
+            ..Message::new_text(Role::System, "Stay in character.")
+        };
+        let history = vec![
+            Message::new_text(Role::User, "Hi"),
+            Message::new_text(Role::Assistant, "Hello"),
+        ];
+        let msgs = state.place_jailbreaks(history, vec![jailbreak]);
+        let merged = state.merge_messages(msgs, String::new()).unwrap();
+        assert!(merged.paste.ends_with("Human: Stay in character."));
+    }
 }
@@ -0,0 +1,51 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use axum_server::tls_rustls::RustlsConfig;
+use tracing::{info, warn};
+
+use crate::{config::Config, error::ClewdrError};
+
+/// Load the configured certificate, `None` when serving plain HTTP
+pub async fn load_tls(config: &Config) -> Result<Option<RustlsConfig>, ClewdrError> {
+    if !config.tls_enabled() {
+        return Ok(None);
+    }
+    let tls = RustlsConfig::from_pem_file(&config.tls_cert, &config.tls_key).await?;
+    Ok(Some(tls))
+}
+
+/// Latest modification time of the certificate and key
+fn modified(cert: &Path, key: &Path) -> Option<SystemTime> {
+    let cert = std::fs::metadata(cert).and_then(|m| m.modified()).ok()?;
+    let key = std::fs::metadata(key).and_then(|m| m.modified()).ok()?;
+    Some(cert.max(key))
+}
+
+/// Reload the certificate whenever its files change
+pub async fn watch_tls(tls: RustlsConfig, config: Config) {
+    if config.tls_reload_secs == 0 {
+        return;
+    }
+    let cert = PathBuf::from(&config.tls_cert);
+    let key = PathBuf::from(&config.tls_key);
+    let mut last = modified(&cert, &key);
+    let mut interval = tokio::time::interval(Duration::from_secs(config.tls_reload_secs));
+    loop {
+        interval.tick().await;
+        let current = modified(&cert, &key);
+        if current.is_none() || current == last {
+            continue;
+        }
+        // keep serving the old certificate if the new one is unreadable
+        match tls.reload_from_pem_file(&cert, &key).await {
+            Ok(()) => {
+                info!("Reloaded TLS certificate");
+                last = current;
+            }
+            Err(e) => warn!("Failed to reload TLS certificate: {}", e),
+        }
+    }
+}
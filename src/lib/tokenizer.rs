@@ -0,0 +1,65 @@
+use std::sync::LazyLock;
+
+use serde::{Deserialize, Serialize};
+use tiktoken_rs::{CoreBPE, o200k_base};
+
+/// Tokenizer used to estimate the length of a prompt
+pub trait Tokenizer: Send + Sync {
+    /// Count the tokens in the text
+    fn count(&self, text: &str) -> usize;
+}
+
+/// Tokenizer backed by tiktoken o200k_base
+pub struct TiktokenTokenizer {
+    bpe: CoreBPE,
+}
+
+impl TiktokenTokenizer {
+    pub fn new() -> Self {
+        Self {
+            bpe: o200k_base().expect("Failed to load o200k_base"),
+        }
+    }
+}
+
+impl Default for TiktokenTokenizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tokenizer for TiktokenTokenizer {
+    fn count(&self, text: &str) -> usize {
+        self.bpe.encode_with_special_tokens(text).len()
+    }
+}
+
+/// Cheap tokenizer assuming 4 bytes per token
+pub struct ApproxTokenizer;
+
+impl Tokenizer for ApproxTokenizer {
+    fn count(&self, text: &str) -> usize {
+        text.len().div_ceil(4)
+    }
+}
+
+static TIKTOKEN: LazyLock<TiktokenTokenizer> = LazyLock::new(TiktokenTokenizer::new);
+
+/// Tokenizer selectable in config
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenizerKind {
+    #[default]
+    Tiktoken,
+    Approx,
+}
+
+impl TokenizerKind {
+    /// Get the tokenizer instance
+    pub fn tokenizer(&self) -> &'static dyn Tokenizer {
+        match self {
+            TokenizerKind::Tiktoken => &*TIKTOKEN,
+            TokenizerKind::Approx => &ApproxTokenizer,
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde_json::{Value, json};
+
+use crate::types::message::{ContentBlock, Tool};
+
+/// Opening tag of a tool call in the completion
+pub const TOOL_USE_START: &str = "<tool_use>";
+/// Closing tag of a tool call in the completion
+pub const TOOL_USE_END: &str = "</tool_use>";
+
+static TOOL_BLOCK_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?s)<tool_use>(.*?)</tool_use>|<tool_result id="([^"]*)">(.*?)</tool_result>"#)
+        .unwrap()
+});
+
+/// Render tool definitions into a system section Claude.ai understands
+pub fn tool_prompt(tools: &[Tool], tool_choice: Option<&Value>) -> String {
+    let defs = serde_json::to_string_pretty(tools).unwrap_or_default();
+    let mut prompt = format!(
+        "In this environment you have access to a set of tools you can use to answer the user's question.\n\
+        To call a tool, reply with a block in exactly this format and stop your reply:\n\
+        {TOOL_USE_START}{{\"name\": \"TOOL_NAME\", \"input\": {{ARGUMENTS}}}}{TOOL_USE_END}\n\
+        Tool results will be provided in <tool_result id=\"TOOL_USE_ID\"></tool_result> blocks.\n\n\
+        Here are the tools available:\n<tools>\n{defs}\n</tools>"
+    );
+    if let Some(instruction) = tool_choice.and_then(tool_choice_instruction) {
+        prompt.push_str("\n\n");
+        prompt.push_str(&instruction);
+    }
+    prompt
+}
+
+/// Instruction for `tool_choice`, accepts both Anthropic and OpenAI formats
+fn tool_choice_instruction(choice: &Value) -> Option<String> {
+    let (kind, name) = match choice {
+        Value::String(s) => (s.as_str(), None),
+        Value::Object(o) => (
+            o.get("type").and_then(|t| t.as_str())?,
+            o.get("name")
+                .or(choice.pointer("/function/name"))
+                .and_then(|n| n.as_str()),
+        ),
+        _ => return None,
+    };
+    match (kind, name) {
+        ("any" | "required", _) => Some("You must call at least one of the tools.".to_string()),
+        ("none", _) => Some("Do not call any tools.".to_string()),
+        ("tool" | "function", Some(name)) => Some(format!("You must call the `{}` tool.", name)),
+        _ => None,
+    }
+}
+
+/// Render a tool call or result from history into the prompt
+pub fn render_tool_block(block: &ContentBlock) -> Option<String> {
+    match block {
+        ContentBlock::ToolUse { id, name, input } => Some(format!(
+            "{TOOL_USE_START}{}{TOOL_USE_END}",
+            json!({ "id": id, "name": name, "input": input })
+        )),
+        ContentBlock::ToolResult {
+            tool_use_id,
+            content,
+        } => Some(format!(
+            "<tool_result id=\"{}\">{}</tool_result>",
+            tool_use_id, content
+        )),
+        _ => None,
+    }
+}
+
+/// Split the completion into text, tool call and tool result blocks
+pub fn parse_tool_calls(text: &str) -> Vec<ContentBlock> {
+    let mut blocks = Vec::new();
+    let mut last = 0;
+    let push_text = |blocks: &mut Vec<ContentBlock>, s: &str| {
+        if !s.trim().is_empty() {
+            blocks.push(ContentBlock::text(s));
+        }
+    };
+    for caps in TOOL_BLOCK_RE.captures_iter(text) {
+        let whole = caps.get(0).unwrap();
+        push_text(&mut blocks, &text[last..whole.start()]);
+        last = whole.end();
+        if let Some(call) = caps.get(1) {
+            let Ok(call) = serde_json::from_str::<Value>(call.as_str().trim()) else {
+                push_text(&mut blocks, whole.as_str());
+                continue;
+            };
+            let Some(name) = call["name"].as_str() else {
+                push_text(&mut blocks, whole.as_str());
+                continue;
+            };
+            let id = call["id"]
+                .as_str()
+                .map(|id| id.to_string())
+                .unwrap_or_else(|| format!("toolu_{}", uuid::Uuid::new_v4().simple()));
+            blocks.push(ContentBlock::ToolUse {
+                id,
+                name: name.to_string(),
+                input: call.get("input").cloned().unwrap_or(json!({})),
+            });
+        } else {
+            blocks.push(ContentBlock::ToolResult {
+                tool_use_id: caps[2].to_string(),
+                content: caps[3].to_string(),
+            });
+        }
+    }
+    push_text(&mut blocks, &text[last..]);
+    blocks
+}
@@ -155,6 +155,9 @@ pub struct Message {
     /// Content of the message (either string or array of content blocks)
     #[serde(flatten)]
     pub content: MessageContent,
+    /// Jailbreak instruction, moved to `jailbreak_position` in the prompt
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub jailbreak: bool,
 }
 
 /// Role of a message sender
@@ -187,6 +190,9 @@ pub enum ContentBlock {
     /// Image content
     #[serde(rename = "image")]
     Image { source: ImageSource },
+    /// Thinking content
+    #[serde(rename = "thinking")]
+    Thinking { thinking: String },
     /// Tool use content
     #[serde(rename = "tool_use")]
     ToolUse {
@@ -209,13 +215,19 @@ pub struct ImageSource {
     #[serde(rename = "type")]
     pub type_: String,
     /// Media type of the image
+    #[serde(default)]
     pub media_type: String,
     /// Base64-encoded image data
+    #[serde(default)]
     pub data: String,
+    /// Path of a local image, relative to `image_file_root`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
 }
 
 /// Tool definition
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(from = "RawTool")]
 pub struct Tool {
     /// Name of the tool
     pub name: String,
@@ -226,8 +238,55 @@ pub struct Tool {
     pub input_schema: serde_json::Value,
 }
 
+/// Tool definition in Anthropic, OpenAI or legacy OpenAI function format
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawTool {
+    Anthropic {
+        name: String,
+        #[serde(default)]
+        description: Option<String>,
+        input_schema: serde_json::Value,
+    },
+    OpenAI {
+        function: RawFunction,
+    },
+    Function(RawFunction),
+}
+
+/// Function definition in OpenAI format
+#[derive(Deserialize)]
+struct RawFunction {
+    name: String,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    parameters: serde_json::Value,
+}
+
+impl From<RawTool> for Tool {
+    fn from(raw: RawTool) -> Self {
+        match raw {
+            RawTool::Anthropic {
+                name,
+                description,
+                input_schema,
+            } => Self {
+                name,
+                description,
+                input_schema,
+            },
+            RawTool::OpenAI { function } | RawTool::Function(function) => Self {
+                name: function.name,
+                description: function.description,
+                input_schema: function.parameters,
+            },
+        }
+    }
+}
+
 /// Tool choice configuration
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "type")]
 pub enum ToolChoice {
     /// Let model choose whether to use tools
@@ -271,6 +330,27 @@ pub struct CreateMessageResponse {
     pub usage: Usage,
 }
 
+/// Non-stream response sent to the client
+#[derive(Debug, Serialize)]
+pub struct MessageResponse {
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// Model that served the request, after aliases and fallbacks
+    pub model: String,
+    #[serde(flatten)]
+    pub message: Message,
+}
+
+impl MessageResponse {
+    pub fn new(model: String, message: Message) -> Self {
+        Self {
+            type_: "message".to_string(),
+            model,
+            message,
+        }
+    }
+}
+
 /// Reason for stopping message generation
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
@@ -281,6 +361,19 @@ pub enum StopReason {
     ToolUse,
 }
 
+impl StopReason {
+    /// Map a stop reason reported by Claude.ai or other upstreams to the API enum
+    pub fn normalize(reason: &str) -> Self {
+        match reason.trim().to_ascii_lowercase().as_str() {
+            "max_tokens" | "max_length" | "length" | "max_output_tokens" => StopReason::MaxTokens,
+            "stop_sequence" | "stop_sequences" => StopReason::StopSequence,
+            "tool_use" | "tool_calls" | "function_call" => StopReason::ToolUse,
+            // "end_turn", "stop", "message_limit", "refusal", ...
+            _ => StopReason::EndTurn,
+        }
+    }
+}
+
 /// Token usage statistics
 #[derive(Debug, Deserialize, Serialize, Default)]
 pub struct Usage {
@@ -288,6 +381,9 @@ pub struct Usage {
     pub input_tokens: u32,
     /// Output tokens used
     pub output_tokens: u32,
+    /// Service tier that served the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_tier: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Default)]
@@ -297,6 +393,9 @@ pub struct StreamUsage {
     pub input_tokens: u32,
     /// Output tokens used
     pub output_tokens: u32,
+    /// Service tier that served the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_tier: Option<String>,
 }
 
 impl Message {
@@ -307,6 +406,7 @@ impl Message {
             content: MessageContent::Text {
                 content: text.into(),
             },
+            jailbreak: false,
         }
     }
 
@@ -315,6 +415,7 @@ impl Message {
         Self {
             role,
             content: MessageContent::Blocks { content: blocks },
+            jailbreak: false,
         }
     }
 }
@@ -337,6 +438,7 @@ impl ContentBlock {
                 type_: type_.into(),
                 media_type: media_type.into(),
                 data: data.into(),
+                path: None,
             },
         }
     }
@@ -421,3 +523,38 @@ pub struct StreamError {
     pub type_: String,
     pub message: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stop_reasons_are_normalized() {
+        assert!(matches!(
+            StopReason::normalize("length"),
+            StopReason::MaxTokens
+        ));
+        assert!(matches!(
+            StopReason::normalize(" Stop_Sequence "),
+            StopReason::StopSequence
+        ));
+        assert!(matches!(
+            StopReason::normalize("tool_calls"),
+            StopReason::ToolUse
+        ));
+        assert!(matches!(
+            StopReason::normalize("message_limit"),
+            StopReason::EndTurn
+        ));
+    }
+
+    #[test]
+    fn response_reports_the_served_model() {
+        let message = Message::new_text(Role::Assistant, "Hi");
+        let res = serde_json::to_value(MessageResponse::new("claude-served".to_string(), message))
+            .unwrap();
+        assert_eq!(res["type"], "message");
+        assert_eq!(res["model"], "claude-served");
+        assert_eq!(res["role"], "assistant");
+    }
+}
@@ -1,7 +1,76 @@
-use std::path::PathBuf;
+use regex::Regex;
+use serde_json::Value;
+use std::{path::PathBuf, sync::OnceLock};
 use tracing::error;
 
-use crate::{config::CONFIG_NAME, error::ClewdrError};
+use crate::{
+    config::{CONFIG_NAME, Config},
+    error::ClewdrError,
+};
+
+/// Keys holding message content, masked when content redaction is enabled
+const CONTENT_KEYS: [&str; 8] = [
+    "text",
+    "content",
+    "prompt",
+    "system",
+    "completion",
+    "thinking",
+    "data",
+    "paste",
+];
+
+/// Masks sensitive data in dumped logs
+struct Redactor {
+    patterns: Vec<Regex>,
+    content: bool,
+}
+
+static REDACTOR: OnceLock<Redactor> = OnceLock::new();
+
+/// Build the log redactor from config
+/// Secrets of the proxy itself are always masked
+pub fn init_redaction(config: &Config) -> Result<(), ClewdrError> {
+    let mut patterns = config
+        .redact_patterns
+        .iter()
+        .map(|p| Regex::new(p))
+        .collect::<Result<Vec<_>, _>>()?;
+    for s in config.secrets() {
+        patterns.push(Regex::new(&regex::escape(s))?);
+    }
+    let _ = REDACTOR.set(Redactor {
+        patterns,
+        content: config.redact_content,
+    });
+    Ok(())
+}
+
+impl Redactor {
+    fn mask(&self, text: &str) -> String {
+        self.patterns.iter().fold(text.to_string(), |t, re| {
+            re.replace_all(&t, "[redacted]").into_owned()
+        })
+    }
+
+    /// Replace content strings, keeping the structure around them
+    fn mask_content(value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (k, v) in map.iter_mut() {
+                    match v {
+                        Value::String(s) if CONTENT_KEYS.contains(&k.as_str()) => {
+                            *s = format!("[redacted {} chars]", s.chars().count());
+                        }
+                        _ => Self::mask_content(v),
+                    }
+                }
+            }
+            Value::Array(arr) => arr.iter_mut().for_each(Self::mask_content),
+            _ => {}
+        }
+    }
+}
 
 /// Get directory of the config file
 pub fn config_dir() -> Result<PathBuf, ClewdrError> {
@@ -21,12 +90,35 @@ pub fn config_dir() -> Result<PathBuf, ClewdrError> {
 
 /// Helper function to print out json
 pub fn print_out_json(json: &impl serde::ser::Serialize, file_name: &str) {
-    let text = serde_json::to_string_pretty(json).unwrap_or_default();
-    print_out_text(&text, file_name);
+    let mut value = serde_json::to_value(json).unwrap_or_default();
+    let Some(r) = REDACTOR.get() else {
+        write_log(
+            &serde_json::to_string_pretty(&value).unwrap_or_default(),
+            file_name,
+        );
+        return;
+    };
+    if r.content {
+        Redactor::mask_content(&mut value);
+    }
+    let text = serde_json::to_string_pretty(&value).unwrap_or_default();
+    write_log(&r.mask(&text), file_name);
 }
 
 /// Helper function to print out text
 pub fn print_out_text(text: &str, file_name: &str) {
+    match REDACTOR.get() {
+        Some(r) if r.content => write_log(
+            &format!("[redacted {} chars]", text.chars().count()),
+            file_name,
+        ),
+        Some(r) => write_log(&r.mask(text), file_name),
+        None => write_log(text, file_name),
+    }
+}
+
+/// Write a log file to the log dir next to the config
+fn write_log(text: &str, file_name: &str) {
     let Ok(dir) = config_dir() else {
         error!("No config found in cwd or exec dir");
         return;
@@ -55,3 +147,6 @@ pub fn print_out_text(text: &str, file_name: &str) {
 
 /// Timezone for the API
 pub const TIME_ZONE: &str = "America/New_York";
+
+/// Timezone used when the configured or requested one is invalid
+pub const FALLBACK_TIME_ZONE: &str = "UTC";
@@ -1,12 +1,30 @@
 use clap::Parser;
 use clewdr::{
-    self, BANNER, config::Config, cookie::CookieManager, error::ClewdrError, state::AppState,
+    self, BANNER,
+    config::Config,
+    cookie::CookieManager,
+    error::ClewdrError,
+    events::run_event_log,
+    health::run_readiness,
+    models::run_model_refresh,
+    reload::run_reload_signal,
+    session::run_session_expiry,
+    shutdown::shutdown_signal,
+    state::AppState,
+    stats::run_stats,
+    sweeper::{run_delete_retry, run_sweeper},
+    tls::{load_tls, watch_tls},
     utils::config_dir,
 };
 use colored::Colorize;
 use const_format::formatc;
-use tokio::{spawn, sync::mpsc};
-use tracing::warn;
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    select, spawn,
+    sync::{mpsc, watch},
+    time::sleep,
+};
+use tracing::{info, warn};
 use tracing_subscriber::{
     Registry,
     fmt::{self, time::ChronoLocal},
@@ -48,7 +66,9 @@ async fn main() -> Result<(), ClewdrError> {
 
     println!("{}", *BANNER);
     // load config from file
-    let config = Config::load()?;
+    let mut config = Config::load()?;
+    clewdr::client::init_rproxy_client(&config)?;
+    clewdr::utils::init_redaction(&config)?;
 
     let updater = clewdr::update::Updater::new(config.clone())?;
     if let Err(e) = updater.check_for_updates().await {
@@ -62,7 +82,12 @@ async fn main() -> Result<(), ClewdrError> {
         env!("CARGO_PKG_AUTHORS")
     );
     println!("{}", TITLE.blue());
-    let addr = format!("http://{}/v1", config.address());
+    let scheme = if config.tls_enabled() {
+        "https"
+    } else {
+        "http"
+    };
+    let addr = format!("{}://{}/v1", scheme, config.address());
     println!("Listening on {}", addr.green());
     println!("{}", config);
 
@@ -71,14 +96,122 @@ async fn main() -> Result<(), ClewdrError> {
     let (ret_tx, ret_rx) = mpsc::channel(config.max_connections);
     let (submit_tx, submit_rx) = mpsc::channel(config.max_connections);
     let state = AppState::new(config.clone(), req_tx, ret_tx, submit_tx);
-    let cm = CookieManager::new(config, req_rx, ret_rx, submit_rx);
+    if config.warmup_cookies {
+        state.warmup(&mut config).await;
+    }
+    let cm = CookieManager::new(config, req_rx, ret_rx, submit_rx, state.live_config.clone());
     // build axum router
     // create a TCP listener
     let addr = state.config.address().to_string();
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    let router = clewdr::router::RouterBuilder::new(state).build();
+    let tls = load_tls(&state.config).await?;
+    let router = clewdr::router::RouterBuilder::new(state.clone()).build();
     // serve the application
     spawn(cm.run());
-    axum::serve(listener, router).await?;
+    spawn(run_sweeper(state.clone()));
+    spawn(run_delete_retry(state.clone()));
+    spawn(run_session_expiry(state.clone()));
+    spawn(run_readiness(state.clone()));
+    spawn(run_model_refresh(state.clone()));
+    spawn(run_reload_signal(state.clone()));
+    spawn(run_stats(state.clone()));
+    spawn(run_event_log(state.clone()));
+    // shared shutdown flag for every listener
+    let (stop_tx, stop_rx) = watch::channel(false);
+    spawn(async move {
+        shutdown_signal().await;
+        stop_tx.send_replace(true);
+    });
+    let app = router
+        .clone()
+        .into_make_service_with_connect_info::<SocketAddr>();
+    let tcp = {
+        let stop_rx = stop_rx.clone();
+        let config = (*state.config).clone();
+        async move {
+            match tls {
+                Some(tls) => {
+                    spawn(watch_tls(tls.clone(), config));
+                    let handle = axum_server::Handle::new();
+                    let stop_handle = handle.clone();
+                    spawn(async move {
+                        stopped(stop_rx).await;
+                        stop_handle.graceful_shutdown(None);
+                    });
+                    axum_server::from_tcp_rustls(listener.into_std()?, tls)
+                        .handle(handle)
+                        .serve(app)
+                        .await
+                }
+                None => {
+                    axum::serve(listener, app)
+                        .with_graceful_shutdown(stopped(stop_rx))
+                        .await
+                }
+            }
+        }
+    };
+    #[cfg(unix)]
+    let unix = {
+        let unix_listener = if state.config.unix_socket.is_empty() {
+            None
+        } else {
+            Some(bind_unix_socket(&state.config.unix_socket)?)
+        };
+        let stop_rx = stop_rx.clone();
+        async move {
+            match unix_listener {
+                Some(l) => {
+                    axum::serve(l, router)
+                        .with_graceful_shutdown(stopped(stop_rx))
+                        .await
+                }
+                None => Ok(()),
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let unix = {
+        if !state.config.unix_socket.is_empty() {
+            warn!("Unix sockets are not supported on this platform");
+        }
+        async { Ok::<(), std::io::Error>(()) }
+    };
+    let grace = Duration::from_secs(state.config.shutdown_grace_secs);
+    select! {
+        res = async { tokio::try_join!(tcp, unix) } => {
+            res?;
+        }
+        _ = async {
+            stopped(stop_rx).await;
+            sleep(grace).await;
+        } => {
+            warn!("Grace period elapsed, shutting down with requests in flight");
+        }
+    }
+    // clean up conversations left by unfinished requests
+    state.delete_pending_chats().await;
+    info!("Shutdown complete");
     Ok(())
 }
+
+/// Resolve once the shutdown signal is received
+async fn stopped(mut rx: watch::Receiver<bool>) {
+    let _ = rx.wait_for(|s| *s).await;
+}
+
+/// Bind a Unix domain socket, removing a stale socket file first
+#[cfg(unix)]
+fn bind_unix_socket(path: &str) -> Result<tokio::net::UnixListener, ClewdrError> {
+    use std::os::unix::fs::PermissionsExt;
+    let path = std::path::Path::new(path);
+    if path.exists() {
+        warn!("Removing stale socket file: {}", path.display());
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    // only the owner may connect
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    println!("Listening on {}", path.display().to_string().green());
+    Ok(listener)
+}